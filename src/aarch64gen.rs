@@ -0,0 +1,624 @@
+//! Generates AArch64 Linux code as GNU assembler source.
+//!
+//! The data stack is laid out as in `x86_64gen`, with `x19` pointing one
+//! past the top slot. Every slot lives in memory. Each proc saves `x29` and
+//! `x30` on the machine stack and restores them before it returns or tail
+//! calls, so a tail call is a plain branch.
+
+use std::fmt::Write;
+
+use crate::{
+    backend::Backend,
+    compiler::{Entry, Instruction, Label, Proc},
+    lexer::Span,
+    x86_64asm::StackError,
+};
+
+/// Points one past the topmost slot.
+const SP: &str = "x19";
+
+/// Linux AArch64 system call numbers.
+const SYS_WRITE: i64 = 64;
+const SYS_EXIT: i64 = 93;
+const SYS_KILL: i64 = 129;
+const SYS_GETPID: i64 = 172;
+const SIGFPE: i64 = 8;
+
+pub struct Aarch64<'src> {
+    string_literals: &'src [Box<str>],
+    stack_slots: usize,
+    /// The proc being generated, which is sized once it ends.
+    proc: Option<Label<'src>>,
+    text: String,
+}
+
+impl<'src> Aarch64<'src> {
+    pub fn new(string_literals: &'src [Box<str>], stack_slots: usize) -> Self {
+        Self {
+            string_literals,
+            stack_slots,
+            proc: None,
+            text: String::new(),
+        }
+    }
+
+    fn end_proc(&mut self) {
+        if let Some(label) = self.proc.take() {
+            self.line(format!(".size {label}, .-{label}"));
+        }
+    }
+
+    fn line(&mut self, line: impl AsRef<str>) {
+        writeln!(self.text, "    {}", line.as_ref()).unwrap();
+    }
+
+    fn label(&mut self, label: impl std::fmt::Display) {
+        writeln!(self.text, "{label}:").unwrap();
+    }
+
+    /// Moves `value` into `reg`, in up to four instructions.
+    fn mov_imm(&mut self, reg: &str, value: i64) {
+        if (-0x1_0000..0x1_0000).contains(&value) {
+            self.line(format!("mov {reg}, #{value}"));
+            return;
+        }
+
+        let mut first = true;
+        for shift in (0..64).step_by(16) {
+            let half = (value as u64 >> shift) & 0xffff;
+            if half == 0 {
+                continue;
+            }
+            let op = if first { "movz" } else { "movk" };
+            self.line(format!("{op} {reg}, #{half:#x}, lsl #{shift}"));
+            first = false;
+        }
+    }
+
+    /// Sets `dst` to `src` plus `n`.
+    fn add_imm(&mut self, dst: &str, src: &str, n: i64) {
+        let op = if n < 0 { "sub" } else { "add" };
+        if n.unsigned_abs() < 0x1000 {
+            self.line(format!("{op} {dst}, {src}, #{}", n.unsigned_abs()));
+        } else {
+            self.mov_imm("x9", n.unsigned_abs() as i64);
+            self.line(format!("{op} {dst}, {src}, x9"));
+        }
+    }
+
+    fn move_sp(&mut self, delta: i64) {
+        if delta != 0 {
+            self.add_imm(SP, SP, delta);
+        }
+    }
+
+    /// The operand for the memory `offset` bytes from `base`, which may
+    /// need `x9` set up first.
+    fn mem(&mut self, base: &str, offset: i64) -> String {
+        if (-256..256).contains(&offset) || (offset >= 0 && offset % 8 == 0 && offset < 0x8000) {
+            if offset == 0 {
+                format!("[{base}]")
+            } else {
+                format!("[{base}, #{offset}]")
+            }
+        } else {
+            self.mov_imm("x9", offset);
+            format!("[{base}, x9]")
+        }
+    }
+
+    fn load(&mut self, reg: &str, base: &str, offset: i64) {
+        let mem = self.mem(base, offset);
+        self.line(format!("ldr {reg}, {mem}"));
+    }
+
+    fn store(&mut self, reg: &str, base: &str, offset: i64) {
+        let mem = self.mem(base, offset);
+        self.line(format!("str {reg}, {mem}"));
+    }
+
+    fn adr(&mut self, reg: &str, symbol: impl std::fmt::Display) {
+        self.line(format!("adrp {reg}, {symbol}"));
+        self.line(format!("add {reg}, {reg}, :lo12:{symbol}"));
+    }
+
+    fn syscall(&mut self, number: i64) {
+        self.mov_imm("x8", number);
+        self.line("svc #0");
+    }
+
+    /// Restores the caller's frame before returning or tail calling.
+    fn leave(&mut self) {
+        self.line("ldp x29, x30, [sp], #16");
+    }
+
+    /// Writes the message for `error` to stderr and exits with 1.
+    fn gen_stack_error(&mut self, error: StackError) {
+        self.label(format_args!("data_stack_{error}"));
+        self.mov_imm("x0", 2);
+        self.adr("x1", format_args!("data_stack_{error}_message"));
+        self.mov_imm("x2", error.message().len() as i64);
+        self.syscall(SYS_WRITE);
+        self.mov_imm("x0", 1);
+        self.syscall(SYS_EXIT);
+    }
+
+    /// `sdiv` gives 0 rather than trapping, so dividing by zero raises
+    /// `SIGFPE` like `idiv` does.
+    fn gen_division_by_zero(&mut self) {
+        self.label("division_by_zero");
+        self.syscall(SYS_GETPID);
+        self.mov_imm("x1", SIGFPE);
+        self.syscall(SYS_KILL);
+        self.mov_imm("x0", 1);
+        self.syscall(SYS_EXIT);
+    }
+
+    fn gen_call(&mut self, callee: &str, indirect: bool, tail: bool) {
+        match (indirect, tail) {
+            (false, false) => self.line(format!("bl {callee}")),
+            (true, false) => self.line(format!("blr {callee}")),
+            (false, true) => {
+                self.leave();
+                self.line(format!("b {callee}"));
+            }
+            (true, true) => {
+                self.leave();
+                self.line(format!("br {callee}"));
+            }
+        }
+    }
+
+    /// Pops two slots and pushes what `ops` make of `x0` and `x1`.
+    fn gen_binary(&mut self, ops: &[&str]) {
+        self.line(format!("ldp x0, x1, [{SP}, #-16]"));
+        for op in ops {
+            self.line(op);
+        }
+        self.store("x0", SP, -16);
+        self.move_sp(-8);
+    }
+
+    /// Pushes a copy of the `size` slots that start `from` slots below the
+    /// top.
+    fn gen_copy_up(&mut self, from: usize, size: usize) {
+        for i in 0..size as i64 {
+            self.load("x0", SP, -8 * from as i64 + 8 * i);
+            self.store("x0", SP, 8 * i);
+        }
+        self.move_sp(8 * size as i64);
+    }
+
+    /// Swaps two multi-slot values, holding the top one on the machine
+    /// stack.
+    fn gen_swap(&mut self, size_a: usize, size_b: usize) {
+        let slot = |i: usize| -8 * (i as i64 + 1);
+        let (sa, sb) = (size_a as i64 * 8, size_b as i64 * 8);
+        let frame = sa + sa % 16;
+
+        self.add_imm("sp", "sp", -frame);
+        for i in 0..size_a {
+            self.load("x0", SP, slot(i));
+            self.store("x0", "sp", 8 * i as i64);
+        }
+        for i in 0..size_b {
+            self.load("x0", SP, slot(i) - sa);
+            self.store("x0", SP, slot(i));
+        }
+        for i in 0..size_a {
+            self.load("x0", "sp", 8 * i as i64);
+            self.store("x0", SP, slot(i) - sb);
+        }
+        self.add_imm("sp", "sp", frame);
+    }
+
+    /// Keeps the true or the false block of `size` slots below the
+    /// condition.
+    fn gen_branch(&mut self, size: usize) {
+        let cond = -8 * (2 * size as i64 + 1);
+        self.load("x0", SP, cond);
+        self.line("cmp x0, #0");
+        for i in 0..size as i64 {
+            let result = cond + 8 * i;
+            let if_true = result + 8;
+            let if_false = if_true + 8 * size as i64;
+            self.load("x1", SP, if_true);
+            self.load("x2", SP, if_false);
+            self.line("csel x1, x1, x2, ne");
+            self.store("x1", SP, result);
+        }
+        self.move_sp(-8 * (size as i64 + 1));
+    }
+}
+
+impl<'src> Backend<'src> for Aarch64<'src> {
+    type Output = String;
+
+    fn gen_start(&mut self, entry: Entry<'src>) {
+        self.line(".text");
+        self.line(".globl _start");
+        self.label("_start");
+        self.adr(SP, "data_stack");
+        self.line(format!("bl {}", entry.label()));
+        if entry.returns_exit_code() {
+            self.load("x0", SP, -8);
+        } else {
+            self.mov_imm("x0", 0);
+        }
+        self.syscall(SYS_EXIT);
+
+        for error in [StackError::Overflow, StackError::Underflow] {
+            self.gen_stack_error(error);
+        }
+        self.gen_division_by_zero();
+    }
+
+    fn gen_proc(&mut self, proc: &Proc<'src>) {
+        self.end_proc();
+        let label = proc.label();
+        self.line(format!(".globl {label}"));
+        self.line(format!(".type {label}, %function"));
+        self.label(label);
+        self.proc = Some(label);
+        self.line("stp x29, x30, [sp, #-16]!");
+        self.line("mov x29, sp");
+    }
+
+    fn gen_stack_check(&mut self, below: usize, above: usize) {
+        if above > 0 {
+            self.add_imm("x0", SP, 8 * above as i64);
+            self.adr("x1", "data_stack_end");
+            self.line("cmp x0, x1");
+            self.line(format!("b.hi data_stack_{}", StackError::Overflow));
+        }
+        if below > 0 {
+            self.add_imm("x0", SP, -8 * below as i64);
+            self.adr("x1", "data_stack");
+            self.line("cmp x0, x1");
+            self.line(format!("b.lo data_stack_{}", StackError::Underflow));
+        }
+    }
+
+    fn gen_instruction(&mut self, span: Span, instruction: Instruction<'src>, tail: bool) {
+        self.line(format!("// {span:?} -- {instruction}"));
+        match instruction {
+            Instruction::PushInt(i) => {
+                self.mov_imm("x0", i as i64);
+                self.store("x0", SP, 0);
+                self.move_sp(8);
+            }
+            Instruction::PushBool(b) => {
+                self.mov_imm("x0", if b { -1 } else { 0 });
+                self.store("x0", SP, 0);
+                self.move_sp(8);
+            }
+            Instruction::PushString(i) => {
+                self.adr("x0", format_args!("str_{i}"));
+                self.mov_imm("x1", self.string_literals[i].len() as i64);
+                self.line(format!("stp x0, x1, [{SP}]"));
+                self.move_sp(16);
+            }
+            Instruction::PushQuote(q) => {
+                self.adr("x0", q);
+                self.store("x0", SP, 0);
+                self.move_sp(8);
+            }
+
+            Instruction::Add => self.gen_binary(&["add x0, x0, x1"]),
+            Instruction::Sub => self.gen_binary(&["sub x0, x0, x1"]),
+            Instruction::Mul => self.gen_binary(&["mul x0, x0, x1"]),
+            Instruction::Div => self.gen_binary(&["cbz x1, division_by_zero", "sdiv x0, x0, x1"]),
+            Instruction::Eq => self.gen_binary(&["cmp x0, x1", "csetm x0, eq"]),
+            Instruction::Lt => self.gen_binary(&["cmp x0, x1", "csetm x0, lt"]),
+
+            Instruction::Exit => {
+                self.load("x0", SP, -8);
+                self.move_sp(-8);
+                self.syscall(SYS_EXIT);
+            }
+
+            Instruction::Puts => {
+                self.line(format!("ldp x1, x2, [{SP}, #-16]"));
+                self.move_sp(-16);
+                self.mov_imm("x0", 1);
+                self.syscall(SYS_WRITE);
+            }
+
+            Instruction::Dup { size } => self.gen_copy_up(size, size),
+            Instruction::Over { size_a, size_b } => self.gen_copy_up(size_a + size_b, size_b),
+            Instruction::Drop { size } => self.move_sp(-8 * size as i64),
+            Instruction::Swap { size_a, size_b } => self.gen_swap(size_a, size_b),
+            Instruction::Branch { size } => self.gen_branch(size),
+
+            Instruction::Apply => {
+                self.load("x16", SP, -8);
+                self.move_sp(-8);
+                self.gen_call("x16", true, tail);
+            }
+            Instruction::Call(label) => self.gen_call(&label.to_string(), false, tail),
+            Instruction::Ffi { .. } => unreachable!("only x86_64 code calls C functions"),
+            Instruction::Syscall { .. } => unreachable!("only x86_64 code makes system calls"),
+            Instruction::Argc
+            | Instruction::ArgvNth
+            | Instruction::Getenv
+            | Instruction::ReadLine
+            | Instruction::ReadAll => {
+                unreachable!(
+                    "only x86_64, C and LLVM code reads arguments, the environment and input"
+                )
+            }
+        }
+    }
+
+    fn gen_return(&mut self) {
+        self.line("// RETURN");
+        self.leave();
+        self.line("ret");
+    }
+
+    fn finish(mut self) -> String {
+        self.end_proc();
+        self.line(".section .rodata");
+        for (i, string_literal) in self.string_literals.iter().enumerate() {
+            self.label(format_args!("str_{i}"));
+            gen_bytes(&mut self.text, string_literal.as_bytes());
+        }
+        for error in [StackError::Overflow, StackError::Underflow] {
+            self.label(format_args!("data_stack_{error}_message"));
+            gen_bytes(&mut self.text, error.message().as_bytes());
+        }
+
+        self.line(".bss");
+        self.line(".balign 8");
+        self.label("data_stack");
+        self.line(format!(".zero {}", 8 * self.stack_slots));
+        self.label("data_stack_end");
+
+        self.text
+    }
+}
+
+fn gen_bytes(text: &mut String, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        let bytes = bytes.iter().map(|b| b.to_string()).collect::<Vec<_>>();
+        writeln!(text, "    .byte {}", bytes.join(",")).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process::Command};
+
+    use super::*;
+    use crate::{
+        analyzer::Analyzer,
+        backend,
+        compiler::{Compiler, DATA_STACK_SLOTS},
+        lexer::Lexer,
+        optimizer::{self, OptLevel},
+    };
+
+    fn generate(source: &str, level: OptLevel, stack_slots: usize) -> String {
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        let (entry, mut procs, strings) = Compiler::compile(defs).unwrap();
+        optimizer::optimize(&mut procs, level);
+        let (entry, procs, strings) =
+            optimizer::eliminate_dead_code(entry.unwrap(), procs, strings);
+        backend::generate(Aarch64::new(&strings, stack_slots), entry, &procs)
+    }
+
+    /// The lines of the proc whose label ends in `_{name}`, without the
+    /// frame setup, comments and size.
+    fn proc_body<'a>(asm: &'a str, name: &str) -> Vec<&'a str> {
+        asm.lines()
+            .skip_while(|line| !line.ends_with(&format!("_{name}:")))
+            .skip(3)
+            .take_while(|line| line.starts_with(' ') && !line.starts_with("    .size"))
+            .filter(|line| !line.trim_start().starts_with("//"))
+            .collect()
+    }
+
+    #[test]
+    fn program_matches_golden_output() {
+        let asm = generate(": main \"hi\" puts [ 1 2 + ] apply ;", OptLevel::O0, 16);
+        assert_eq!(
+            asm,
+            r#"    .text
+    .globl _start
+_start:
+    adrp x19, data_stack
+    add x19, x19, :lo12:data_stack
+    bl zila_0_main
+    ldr x0, [x19, #-8]
+    mov x8, #93
+    svc #0
+data_stack_overflow:
+    mov x0, #2
+    adrp x1, data_stack_overflow_message
+    add x1, x1, :lo12:data_stack_overflow_message
+    mov x2, #20
+    mov x8, #64
+    svc #0
+    mov x0, #1
+    mov x8, #93
+    svc #0
+data_stack_underflow:
+    mov x0, #2
+    adrp x1, data_stack_underflow_message
+    add x1, x1, :lo12:data_stack_underflow_message
+    mov x2, #21
+    mov x8, #64
+    svc #0
+    mov x0, #1
+    mov x8, #93
+    svc #0
+division_by_zero:
+    mov x8, #172
+    svc #0
+    mov x1, #8
+    mov x8, #129
+    svc #0
+    mov x0, #1
+    mov x8, #93
+    svc #0
+    .globl zila_0_main
+    .type zila_0_main, %function
+zila_0_main:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+    add x0, x19, #16
+    adrp x1, data_stack_end
+    add x1, x1, :lo12:data_stack_end
+    cmp x0, x1
+    b.hi data_stack_overflow
+    // Span { start: 7, end: 11 } -- push-string 0
+    adrp x0, str_0
+    add x0, x0, :lo12:str_0
+    mov x1, #2
+    stp x0, x1, [x19]
+    add x19, x19, #16
+    // Span { start: 12, end: 16 } -- puts
+    ldp x1, x2, [x19, #-16]
+    sub x19, x19, #16
+    mov x0, #1
+    mov x8, #64
+    svc #0
+    // Span { start: 17, end: 18 } -- push-quote @1
+    adrp x0, zila_1
+    add x0, x0, :lo12:zila_1
+    str x0, [x19]
+    add x19, x19, #8
+    // Span { start: 27, end: 32 } -- apply
+    ldr x16, [x19, #-8]
+    sub x19, x19, #8
+    ldp x29, x30, [sp], #16
+    br x16
+    .size zila_0_main, .-zila_0_main
+    .globl zila_1
+    .type zila_1, %function
+zila_1:
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+    add x0, x19, #16
+    adrp x1, data_stack_end
+    add x1, x1, :lo12:data_stack_end
+    cmp x0, x1
+    b.hi data_stack_overflow
+    // Span { start: 19, end: 20 } -- push-int 1
+    mov x0, #1
+    str x0, [x19]
+    add x19, x19, #8
+    // Span { start: 21, end: 22 } -- push-int 2
+    mov x0, #2
+    str x0, [x19]
+    add x19, x19, #8
+    // Span { start: 23, end: 24 } -- add
+    ldp x0, x1, [x19, #-16]
+    add x0, x0, x1
+    str x0, [x19, #-16]
+    sub x19, x19, #8
+    // RETURN
+    ldp x29, x30, [sp], #16
+    ret
+    .size zila_1, .-zila_1
+    .section .rodata
+str_0:
+    .byte 104,105
+data_stack_overflow_message:
+    .byte 100,97,116,97,32,115,116,97,99,107,32,111,118,101,114,102,108,111,119,10
+data_stack_underflow_message:
+    .byte 100,97,116,97,32,115,116,97,99,107,32,117,110,100,101,114,102,108,111,119,10
+    .bss
+    .balign 8
+data_stack:
+    .zero 128
+data_stack_end:
+"#
+        );
+    }
+
+    #[test]
+    fn large_immediates_go_through_registers() {
+        let asm = generate(
+            ": main 81985529216486895 0 70000 - + 1 swap ;",
+            OptLevel::O0,
+            DATA_STACK_SLOTS,
+        );
+        let main = proc_body(&asm, "main");
+        assert_eq!(
+            main[5..9],
+            [
+                "    movz x0, #0xcdef, lsl #0",
+                "    movk x0, #0x89ab, lsl #16",
+                "    movk x0, #0x4567, lsl #32",
+                "    movk x0, #0x123, lsl #48",
+            ]
+        );
+        assert!(main.contains(&"    movz x0, #0x1170, lsl #0"));
+        assert!(main.contains(&"    movk x0, #0x1, lsl #16"));
+
+        // The check before 520 pushes is too far for an immediate.
+        let source = format!(": main {}{};", "1 ".repeat(520), "+ ".repeat(519));
+        let asm = generate(&source, OptLevel::O0, DATA_STACK_SLOTS);
+        let main = proc_body(&asm, "main");
+        assert_eq!(main[..2], ["    mov x9, #4160", "    add x0, x19, x9"]);
+    }
+
+    #[test]
+    fn branches_select_and_swaps_use_the_machine_stack() {
+        let asm = generate(
+            ": main \"ab\" 1 swap puts true [ 4 ] [ 5 ] ? apply ;",
+            OptLevel::O0,
+            DATA_STACK_SLOTS,
+        );
+        let main = proc_body(&asm, "main");
+        assert!(main.contains(&"    sub sp, sp, #16"));
+        assert!(main.contains(&"    csel x1, x1, x2, ne"));
+    }
+
+    #[test]
+    fn tail_calls_restore_the_frame_and_branch() {
+        let asm = generate(
+            ": loop dup 0 = [ drop ] [ 1 - loop ] ? apply ; : main 5 loop ;",
+            OptLevel::O0,
+            DATA_STACK_SLOTS,
+        );
+        let main = proc_body(&asm, "main");
+        assert_eq!(
+            main[main.len() - 2..],
+            ["    ldp x29, x30, [sp], #16", "    b zila_0_loop"]
+        );
+        let looped = proc_body(&asm, "loop");
+        assert_eq!(
+            looped[looped.len() - 2..],
+            ["    ldp x29, x30, [sp], #16", "    br x16"]
+        );
+    }
+
+    #[test]
+    #[ignore = "needs llvm-mc; run with `cargo test -- --ignored`"]
+    fn llvm_mc_assembles_the_output() {
+        let programs = [
+            ": main \"hello\\n\" puts 3 4 * 5 - 2 / ;",
+            ": pick [ \"yes\" ] [ \"no\" ] ? apply ;
+             : main true pick puts false pick puts
+             1 2 < [ 10 ] [ 20 ] ? apply 3 3 = [ 4 ] [ 5 ] ? apply + ;",
+            ": s \"xy\" ;
+             : main 1 s 2 swap over drop dup puts puts + s 5 over puts drop puts \"end\" puts 7 exit ;",
+        ];
+
+        let dir = env::temp_dir().join(format!("zila-aarch64-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (i, source) in programs.into_iter().enumerate() {
+            let src = dir.join(format!("{i}.s"));
+            fs::write(&src, generate(source, OptLevel::O2, DATA_STACK_SLOTS)).unwrap();
+            let status = Command::new("llvm-mc")
+                .args(["-triple=aarch64-linux-gnu", "-filetype=obj", "-o"])
+                .arg(dir.join(format!("{i}.o")))
+                .arg(&src)
+                .status()
+                .unwrap();
+            assert!(status.success(), "{source}");
+        }
+    }
+}
@@ -1,12 +1,15 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt,
     io::{self, Write},
     iter::Peekable,
     path::Path,
 };
 
-use crate::lexer::{Span, Token, Word};
+use crate::{
+    diagnostic::{self, Diagnostic, Severity},
+    lexer::{Span, Token, Word},
+};
 
 #[derive(Debug, Clone)]
 pub enum CompileError<'src> {
@@ -24,75 +27,117 @@ pub enum CompileError<'src> {
         found: Option<Word<'src>>,
         reason: &'static str,
     },
-}
-
-fn get_line_col(source: &str, index: usize) -> (usize, usize) {
-    source
-        .chars()
-        .take(index)
-        .fold((1, 1), |(line, column), ch| match ch {
-            '\n' => (line + 1, 1),
-            _ => (line, column + 1),
-        })
+    Hole {
+        span: Span,
+        sig: Signature,
+    },
+    MissingMain,
+    InvalidMainSignature {
+        span: Span,
+        sig: Signature,
+    },
+    InconsistentRecursion {
+        name: &'src str,
+        span: Span,
+        sig: Signature,
+    },
+    RedefinedBuiltin {
+        name: &'src str,
+        span: Span,
+    },
+    Redefined {
+        name: &'src str,
+        span: Span,
+    },
+    UnknownSize {
+        word: &'src str,
+        span: Span,
+        sig: Signature,
+    },
 }
 
 pub fn report_error(
     err: CompileError,
     path: &Path,
     source: &str,
+    color: bool,
     out: &mut impl Write,
 ) -> io::Result<()> {
-    let ((start, end), reason, note) = match err {
+    let diagnostic = match err {
         CompileError::UndefinedWord { symbol, span } => {
-            (span.parts(), format!("undefined word `{symbol}`"), None)
+            Diagnostic::error(format!("undefined word `{symbol}`")).with_span(span.parts())
         }
-        CompileError::Expected { found, reason } => (
+        CompileError::Expected { found, reason } => Diagnostic::error(reason).with_span(
             found
                 .map(|word| word.span().parts())
-                .unwrap_or_else(|| get_line_col(source, source.len())),
-            reason.into(),
-            None,
+                .unwrap_or((source.len(), source.len())),
         ),
         CompileError::CannotExecSignature {
             word,
             word_span,
             stack,
             sig,
-        } => (
-            word_span.parts(),
-            format!("cannot execute word `{word}`"),
-            Some(format!(
+        } => Diagnostic::error(format!("cannot execute word `{word}`"))
+            .with_span(word_span.parts())
+            .with_note(format!(
                 "stack state:\n    {}\n\nsignature of `{word}`:\n    {}",
-                stack
-                    .iter()
-                    .map(|ty| ty.to_string())
-                    .collect::<Vec<_>>()
-                    .join(" "),
+                display_stack(&stack),
                 sig
             )),
-        ),
+        CompileError::Hole { span, sig } => Diagnostic::new(Severity::Hole, "found hole")
+            .with_span(span.parts())
+            .with_note(format!(
+                "stack state:\n    {}\n\neffect so far:\n    {sig}",
+                display_stack(&sig.outputs)
+            )),
+        CompileError::MissingMain => Diagnostic::error("no `main` word defined")
+            .with_note("every program needs an entry point, e.g. `: main ... ;`"),
+        CompileError::InvalidMainSignature { span, sig } => {
+            Diagnostic::error("`main` has an invalid signature")
+                .with_span(span.parts())
+                .with_note(format!(
+                    "signature of `main`:\n    {}\n\n`main` must have the signature `( -- )` or `( -- int )`",
+                    sig.canonicalize()
+                ))
+        }
+        CompileError::RedefinedBuiltin { name, span } => {
+            Diagnostic::error(format!("cannot redefine builtin word `{name}`"))
+                .with_span(span.parts())
+        }
+        CompileError::Redefined { name, span } => {
+            Diagnostic::error(format!("word `{name}` is already defined"))
+                .with_span(span.parts())
+        }
+        CompileError::UnknownSize { word, span, sig } => {
+            Diagnostic::error(format!("cannot compile `{word}` on values of unknown type"))
+                .with_span(span.parts())
+                .with_note(format!(
+                    "signature of `{word}` here:\n    {}\n\nwords that work on values of any type can be checked but not compiled yet",
+                    sig.canonicalize()
+                ))
+        }
+        CompileError::InconsistentRecursion { name, span, sig } => {
+            Diagnostic::error(format!("recursive word `{name}` has no consistent signature"))
+                .with_span(span.parts())
+                .with_note(format!(
+                    "effect of the body:\n    {sig}\n\nevery recursive call to `{name}` must have the same effect as the whole definition"
+                ))
+        }
     };
 
-    let (ln, col) = get_line_col(source, start);
-    let source_ln = source.lines().nth(ln - 1).unwrap_or_default();
-
-    writeln!(out, "ERROR: {reason}")?;
-    writeln!(out, " --> {}:{ln}:{col}", path.display())?;
-    writeln!(out, "  {source_ln}")?;
-    writeln!(out, "  {}{}", " ".repeat(col - 1), "^".repeat(end - start))?;
-
-    if let Some(note) = note {
-        writeln!(out)?;
-        for note_ln in note.lines() {
-            if note_ln.is_empty() {
-                writeln!(out)?;
-            } else {
-                writeln!(out, "NOTE: {note_ln}")?;
-            }
-        }
+    diagnostic::emit(&diagnostic, path, source, color, out)
+}
+
+fn display_stack(stack: &[Type]) -> String {
+    if stack.is_empty() {
+        return "(empty)".into();
     }
 
-    Ok(())
+    stack
+        .iter()
+        .map(|ty| ty.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
 #[derive(Debug, Clone)]
@@ -111,13 +156,22 @@ impl fmt::Display for Type {
             Type::Int => write!(f, "int"),
             Type::Bool => write!(f, "bool"),
             Type::String => write!(f, "string"),
-            Type::Var(v) => write!(f, "'{v}"),
-            Type::MultiVar(v) => write!(f, "..{v}"),
+            Type::Var(v) => write!(f, "'{}", var_name(*v)),
+            Type::MultiVar(v) => write!(f, "..{}", var_name(*v)),
             Type::Quotation(s) => write!(f, "{s}"),
         }
     }
 }
 
+/// Names type variables `a` through `z`, then `a1`, `b1`, and so on.
+fn var_name(v: usize) -> String {
+    let letter = (b'a' + (v % 26) as u8) as char;
+    match v / 26 {
+        0 => letter.to_string(),
+        n => format!("{letter}{n}"),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Signature {
     inputs: Vec<Type>,
@@ -147,6 +201,87 @@ impl Signature {
     pub fn parts(self) -> (Vec<Type>, Vec<Type>) {
         (self.inputs, self.outputs)
     }
+
+    /// Builds a signature from bottom-first input and output rows. A row
+    /// variable at the bottom of both rows that nothing else mentions is left
+    /// implicit, so `dup` reads `( 'a -- 'a 'a )` rather than
+    /// `( ..a 'a -- ..a 'a 'a )`.
+    fn from_rows(mut inputs: Vec<Type>, mut outputs: Vec<Type>) -> Self {
+        if let (Some(Type::MultiVar(m)), Some(Type::MultiVar(n))) =
+            (inputs.first(), outputs.first())
+            && m == n
+        {
+            let row = *m;
+            if !inputs[1..]
+                .iter()
+                .chain(&outputs[1..])
+                .any(|ty| ty.mentions_multivar(row))
+            {
+                inputs.remove(0);
+                outputs.remove(0);
+            }
+        }
+
+        inputs.reverse();
+        Self::new(inputs, outputs)
+    }
+
+    /// Rebuilds the signature with every type variable replaced by `f`,
+    /// visiting variables in the order they are displayed.
+    fn map_vars(&self, f: &mut impl FnMut(&Type) -> Type) -> Signature {
+        fn map(ty: &Type, f: &mut impl FnMut(&Type) -> Type) -> Type {
+            match ty {
+                Type::Int | Type::Bool | Type::String => ty.clone(),
+                Type::Var(_) | Type::MultiVar(_) => f(ty),
+                Type::Quotation(sig) => Type::Quotation(sig.map_vars(f)),
+            }
+        }
+
+        let mut inputs = self
+            .inputs
+            .iter()
+            .rev()
+            .map(|ty| map(ty, f))
+            .collect::<Vec<_>>();
+        inputs.reverse();
+        let outputs = self.outputs.iter().map(|ty| map(ty, f)).collect();
+
+        Signature::new(inputs, outputs)
+    }
+
+    /// Renumbers type variables from zero in the order they are displayed,
+    /// so that inferred signatures read `( 'a 'b -- 'b 'a )` rather than
+    /// exposing the analyzer's internal counters.
+    pub fn canonicalize(&self) -> Signature {
+        let mut vars = HashMap::new();
+        let mut multivars = HashMap::new();
+
+        self.map_vars(&mut |ty| match *ty {
+            Type::Var(v) => {
+                let next = vars.len();
+                Type::Var(*vars.entry(v).or_insert(next))
+            }
+            Type::MultiVar(v) => {
+                let next = multivars.len();
+                Type::MultiVar(*multivars.entry(v).or_insert(next))
+            }
+            _ => unreachable!(),
+        })
+    }
+}
+
+impl Type {
+    fn mentions_multivar(&self, row: usize) -> bool {
+        match self {
+            Type::MultiVar(m) => *m == row,
+            Type::Quotation(sig) => sig
+                .inputs
+                .iter()
+                .chain(&sig.outputs)
+                .any(|ty| ty.mentions_multivar(row)),
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -157,6 +292,9 @@ pub enum Def<'src> {
         ty: Signature,
         body: Vec<Item<'src>>,
     },
+    /// A C function, called with its int arguments in the order they are
+    /// written and returning an int or nothing.
+    Ffi { name: &'src str, ty: Signature },
 }
 
 #[derive(Debug, Clone)]
@@ -190,6 +328,10 @@ struct Context {
     multivar_gen: usize,
 }
 
+/// Two types, or two stack rows, that cannot be made equal.
+#[derive(Debug)]
+struct Mismatch;
+
 impl Context {
     fn new() -> Self {
         Self {
@@ -227,100 +369,198 @@ impl Context {
         self.multivar_gen += 1;
         v
     }
-}
 
-struct State<'src> {
-    signature: Signature,
-    items: Vec<Item<'src>>,
-}
+    /// Copies `sig` with fresh type variables, so that every use of a word
+    /// is checked independently.
+    fn instantiate(&mut self, sig: &Signature) -> Signature {
+        let mut vars = HashMap::new();
+        let mut multivars = HashMap::new();
 
-impl<'src> State<'src> {
-    pub fn new() -> Self {
-        Self {
-            signature: Signature::new(vec![], vec![]),
-            items: Vec::new(),
+        sig.map_vars(&mut |ty| match *ty {
+            Type::Var(v) => Type::Var(*vars.entry(v).or_insert_with(|| self.gen_var())),
+            Type::MultiVar(v) => {
+                Type::MultiVar(*multivars.entry(v).or_insert_with(|| self.gen_multivar()))
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    /// Follows the bindings of `ty` until reaching a type constructor or an
+    /// unbound variable.
+    fn shallow(&self, ty: &Type) -> Type {
+        let mut ty = ty.clone();
+        while let Type::Var(v) = ty
+            && let Some(bound) = self.get_var(v)
+        {
+            ty = bound.clone();
         }
+        ty
     }
 
-    fn push_output(&mut self, ty: Type) {
-        self.signature.outputs.push(ty)
+    /// Splices the binding of the row variable at the bottom of the
+    /// bottom-first `row`, until the bottom is unbound or concrete.
+    fn expand_row(&self, row: &[Type]) -> Vec<Type> {
+        if let Some((Type::MultiVar(m), rest)) = row.split_first()
+            && let Some(bound) = self.get_multivar(*m)
+        {
+            let mut expanded = self.expand_row(bound);
+            expanded.extend_from_slice(rest);
+            expanded
+        } else {
+            row.to_vec()
+        }
     }
 
-    fn push_input(&mut self, ty: Type) {
-        self.signature.inputs.push(ty)
+    fn resolve_type(&self, ty: &Type) -> Type {
+        match self.shallow(ty) {
+            Type::Quotation(sig) => Type::Quotation(self.resolve_signature(&sig)),
+            ty => ty,
+        }
     }
 
-    fn clone_outputs(&self) -> Vec<Type> {
-        self.signature.outputs.clone()
+    fn resolve_row(&self, row: &[Type]) -> Vec<Type> {
+        self.expand_row(row)
+            .iter()
+            .map(|ty| self.resolve_type(ty))
+            .collect()
     }
 
-    fn resolve_type(&self, t: Type, stack: &mut Vec<Type>, context: &Context) {
-        match t {
-            Type::Int => stack.push(Type::Int),
-            Type::Bool => stack.push(Type::Bool),
-            Type::String => stack.push(Type::String),
-            Type::Var(v) => {
-                if let Some(var) = context.get_var(v).cloned() {
-                    let mut resolved = Vec::new();
-                    self.resolve_type(var, &mut resolved, context);
-                    stack.push(resolved.into_iter().next().unwrap());
-                } else {
-                    stack.push(t);
-                }
+    fn resolve_signature(&self, sig: &Signature) -> Signature {
+        let mut inputs = sig.inputs.iter().rev().cloned().collect::<Vec<_>>();
+        inputs = self.resolve_row(&inputs);
+        inputs.reverse();
+
+        Signature::new(inputs, self.resolve_row(&sig.outputs))
+    }
+
+    /// Checks whether the variable `var` appears anywhere inside `ty`.
+    fn occurs(&self, var: &Type, ty: &Type) -> bool {
+        match (var, self.shallow(ty)) {
+            (Type::Var(v), Type::Var(w)) => *v == w,
+            (_, Type::MultiVar(m)) => match self.get_multivar(m) {
+                Some(bound) => bound.iter().any(|ty| self.occurs(var, ty)),
+                None => matches!(var, Type::MultiVar(n) if *n == m),
+            },
+            (_, Type::Quotation(sig)) => sig
+                .inputs
+                .iter()
+                .chain(&sig.outputs)
+                .any(|ty| self.occurs(var, ty)),
+            _ => false,
+        }
+    }
+
+    /// Returns the bottom-first input and output rows of `sig`, giving it a
+    /// fresh row variable if it leaves the rest of the stack implicit.
+    fn rows(&mut self, sig: &Signature) -> (Vec<Type>, Vec<Type>) {
+        let mut inputs = sig.inputs.iter().rev().cloned().collect::<Vec<_>>();
+        let mut outputs = sig.outputs.clone();
+
+        if !matches!(inputs.first(), Some(Type::MultiVar(_)))
+            && !matches!(outputs.first(), Some(Type::MultiVar(_)))
+        {
+            let row = Type::MultiVar(self.gen_multivar());
+            inputs.insert(0, row.clone());
+            outputs.insert(0, row);
+        }
+
+        (inputs, outputs)
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> Result<(), Mismatch> {
+        match (self.shallow(a), self.shallow(b)) {
+            (Type::Int, Type::Int) | (Type::Bool, Type::Bool) | (Type::String, Type::String) => {
+                Ok(())
             }
-            Type::MultiVar(v) => {
-                if let Some(var) = context.get_multivar(v) {
-                    let mut resolved = Vec::new();
-                    for v in var {
-                        self.resolve_type(v.clone(), &mut resolved, context);
-                    }
-                    stack.extend(resolved);
-                } else {
-                    stack.push(t);
+            (Type::Var(v), Type::Var(w)) if v == w => Ok(()),
+            (Type::Var(v), ty) | (ty, Type::Var(v)) => {
+                if self.occurs(&Type::Var(v), &ty) {
+                    return Err(Mismatch);
                 }
+                self.set_var(v, ty);
+                Ok(())
             }
-            Type::Quotation(signature) => {
-                stack.push(Type::Quotation(self.resolve_signature(signature, context)))
+            (Type::Quotation(a), Type::Quotation(b)) => {
+                let (a_inputs, a_outputs) = self.rows(&a);
+                let (b_inputs, b_outputs) = self.rows(&b);
+                self.unify_rows(a_inputs, b_inputs)?;
+                self.unify_rows(a_outputs, b_outputs)
             }
+            _ => Err(Mismatch),
         }
     }
 
-    fn resolve_signature(&self, signature: Signature, context: &Context) -> Signature {
-        let Signature { inputs, outputs } = signature;
+    /// Unifies two bottom-first stack rows from the top down. Once one side
+    /// is down to a lone row variable, that variable is bound to whatever is
+    /// left of the other side.
+    fn unify_rows(&mut self, mut a: Vec<Type>, mut b: Vec<Type>) -> Result<(), Mismatch> {
+        loop {
+            a = self.expand_row(&a);
+            b = self.expand_row(&b);
+
+            match (&a[..], &b[..]) {
+                ([], []) => return Ok(()),
+                ([Type::MultiVar(m)], [Type::MultiVar(n)]) if m == n => return Ok(()),
+                ([Type::MultiVar(m)], row) | (row, [Type::MultiVar(m)]) => {
+                    let var = Type::MultiVar(*m);
+                    if row.iter().any(|ty| self.occurs(&var, ty)) {
+                        return Err(Mismatch);
+                    }
+                    self.set_multivar(*m, row.into());
+                    return Ok(());
+                }
+                ([.., _], [.., _]) => {
+                    let (x, y) = (a.pop().unwrap(), b.pop().unwrap());
+                    self.unify(&x, &y)?;
+                }
+                _ => return Err(Mismatch),
+            }
+        }
+    }
+}
 
-        let mut new_inputs = Vec::new();
-        inputs
-            .into_iter()
-            .for_each(|t_inner| self.resolve_type(t_inner, &mut new_inputs, context));
+struct State<'src> {
+    row: usize,
+    stack: Vec<Type>,
+    items: Vec<Item<'src>>,
+}
 
-        let mut new_outputs = Vec::new();
-        outputs
-            .into_iter()
-            .for_each(|t_inner| self.resolve_type(t_inner, &mut new_outputs, context));
+impl<'src> State<'src> {
+    /// Starts checking a body against an unknown stack, represented by a
+    /// fresh row variable.
+    fn new(context: &mut Context) -> Self {
+        let row = context.gen_multivar();
+        Self {
+            row,
+            stack: vec![Type::MultiVar(row)],
+            items: Vec::new(),
+        }
+    }
 
-        Signature::new(new_inputs, new_outputs)
+    fn push(&mut self, ty: Type) {
+        self.stack.push(ty)
     }
 
+    /// The effect of the items checked so far.
     fn resolve(&self, context: &Context) -> Signature {
-        self.resolve_signature(self.signature.clone(), context)
+        Signature::from_rows(
+            context.resolve_row(&[Type::MultiVar(self.row)]),
+            context.resolve_row(&self.stack),
+        )
     }
 
-    fn resolve_item(&self, item: &Item<'src>, context: &Context) -> Item<'src> {
+    fn resolve_item(item: &Item<'src>, context: &Context) -> Item<'src> {
         Item::new(
             match &item.kind {
-                ItemKind::Quotation(signature, items) => {
-                    let mut new_items = Vec::new();
-                    for item in items {
-                        new_items.push(self.resolve_item(&item, context))
-                    }
-                    ItemKind::Quotation(
-                        self.resolve_signature(signature.clone(), context),
-                        new_items.into_boxed_slice(),
-                    )
-                }
+                ItemKind::Quotation(signature, items) => ItemKind::Quotation(
+                    context.resolve_signature(signature),
+                    items
+                        .iter()
+                        .map(|item| Self::resolve_item(item, context))
+                        .collect(),
+                ),
                 ItemKind::Word(signature, word) => {
-                    let sig = self.resolve_signature(signature.clone(), context);
-                    ItemKind::Word(sig, word)
+                    ItemKind::Word(context.resolve_signature(signature), word)
                 }
                 _ => item.kind.clone(),
             },
@@ -328,184 +568,77 @@ impl<'src> State<'src> {
         )
     }
 
+    /// Resolves the signature and every item once the whole body has been
+    /// checked. Quotation bodies are only resolved here, so that they see
+    /// bindings made by the words that later consume them.
     fn resolve_all(self, context: &Context) -> (Signature, Vec<Item<'src>>) {
         let signature = self.resolve(context);
-        let mut new_items = Vec::new();
-
-        for item in self.items.iter() {
-            new_items.push(self.resolve_item(item, context))
-        }
+        let items = self
+            .items
+            .iter()
+            .map(|item| Self::resolve_item(item, context))
+            .collect();
 
-        (signature, new_items)
+        (signature, items)
     }
 
-    fn instantiate(
-        &mut self,
-        stack: &mut [Type],
-        local_vars: &mut HashMap<usize, usize>,
-        local_multivars: &mut HashMap<usize, usize>,
-        context: &mut Context,
-    ) {
-        for t in stack.iter_mut() {
-            match t {
-                Type::Int | Type::Bool | Type::String => (),
-                Type::Var(n) => {
-                    if let Some(var) = local_vars.get(n) {
-                        *t = Type::Var(*var);
-                    } else {
-                        let var = context.gen_var();
-                        local_vars.insert(*n, var);
-                        *t = Type::Var(var);
-                    }
-                }
-                Type::MultiVar(n) => {
-                    if let Some(var) = local_vars.get(n) {
-                        *t = Type::MultiVar(*var);
-                    } else {
-                        let var = context.gen_multivar();
-                        local_vars.insert(*n, var);
-                        *t = Type::MultiVar(var);
-                    }
-                }
-                Type::Quotation(q_sig) => {
-                    self.instantiate(&mut q_sig.inputs, local_vars, local_multivars, context);
-                    self.instantiate(&mut q_sig.outputs, local_vars, local_multivars, context);
-                }
-            }
-        }
+    /// Applies the effect `sig` to the top of the stack.
+    fn apply(&mut self, sig: &Signature, context: &mut Context) -> Result<(), Mismatch> {
+        let (inputs, outputs) = context.rows(sig);
+        let stack = std::mem::take(&mut self.stack);
+        context.unify_rows(stack, inputs)?;
+        self.stack = outputs;
+        Ok(())
     }
+}
 
-    fn unify(
-        &mut self,
-        word: Word<'src>,
-        sig: &Signature,
-        stack_shot: &[Type],
-        a: &Type,
-        b: &Type,
-        context: &mut Context,
-    ) -> Result<(), CompileError<'src>> {
-        match (a, b) {
-            (Type::Bool, Type::Bool) => Ok(()),
-            (Type::Int, Type::Int) => Ok(()),
-            (Type::String, Type::String) => Ok(()),
-            (Type::Var(v), t) => {
-                if let Some(v_t) = context.get_var(*v) {
-                    self.unify(word, sig, stack_shot, &v_t.clone(), t, context)?;
-                } else {
-                    if let Type::Var(t_var) = t {
-                        if t_var == v {
-                            return Ok(());
-                        }
-                    }
-                    context.set_var(*v, t.clone());
-                }
-                Ok(())
-            }
-            (t, Type::Var(v)) => {
-                if let Some(v_t) = context.get_var(*v) {
-                    self.unify(word, sig, stack_shot, &v_t.clone(), t, context)?;
-                } else {
-                    context.set_var(*v, t.clone());
-                }
-                Ok(())
-            }
-            (Type::Quotation(a_sig), Type::Quotation(b_sig)) => {
-                self.unify_signature(word, sig, a_sig, b_sig, stack_shot, context)
-            }
-            _ => Err(CompileError::CannotExecSignature {
-                word: word.word(),
-                word_span: word.span(),
-                stack: stack_shot.to_vec(),
-                sig: sig.clone(),
-            }),
-        }
-    }
+/// Checks that the program defines a `main` word with the effect
+/// `( -- )`, or `( -- int )` to return an exit code.
+pub fn check_main<'src>(defs: &[Def<'src>]) -> Result<(), CompileError<'src>> {
+    let Some((name_span, ty)) = defs.iter().find_map(|def| match def {
+        Def::WordDef {
+            name: "main",
+            name_span,
+            ty,
+            ..
+        } => Some((*name_span, ty)),
+        _ => None,
+    }) else {
+        return Err(CompileError::MissingMain);
+    };
 
-    fn unify_signature(
-        &mut self,
-        word: Word<'src>,
-        sig: &Signature,
-        a: &Signature,
-        b: &Signature,
-        stack_shot: &[Type],
-        context: &mut Context,
-    ) -> Result<(), CompileError<'src>> {
-        self.unify_stack(word, sig, &a.inputs, &b.inputs, stack_shot, context)?;
-        self.unify_stack(word, sig, &a.outputs, &b.outputs, stack_shot, context)
+    match (&ty.inputs[..], &ty.outputs[..]) {
+        ([], []) | ([], [Type::Int]) => Ok(()),
+        _ => Err(CompileError::InvalidMainSignature {
+            span: name_span,
+            sig: ty.clone(),
+        }),
     }
+}
 
-    fn unify_stack(
-        &mut self,
-        word: Word<'src>,
-        sig: &Signature,
-        a: &[Type],
-        b: &[Type],
-        stack_shot: &[Type],
-        context: &mut Context,
-    ) -> Result<(), CompileError<'src>> {
-        match (a.split_last(), b.split_last()) {
-            (_, Some((Type::MultiVar(b_mv), b_rest))) => {
-                let len = b_rest.len();
-                if a.len() < len {
-                    return Err(CompileError::CannotExecSignature {
-                        word: word.word(),
-                        word_span: word.span(),
-                        stack: stack_shot.to_vec(),
-                        sig: sig.clone(),
-                    });
-                }
-
-                for (a_t, b_t) in a[..len].iter().zip(b_rest) {
-                    self.unify(word, sig, stack_shot, a_t, b_t, context)?;
-                }
-
-                let tail = &a[len..];
-                context.set_multivar(*b_mv, tail.to_vec().into_boxed_slice());
-
-                Ok(())
-            }
-            (Some((Type::MultiVar(a_mv), a_rest)), _) => {
-                let len = a_rest.len();
-                if b.len() < len {
-                    return Err(CompileError::CannotExecSignature {
-                        word: word.word(),
-                        word_span: word.span(),
-                        stack: stack_shot.to_vec(),
-                        sig: sig.clone(),
-                    });
-                }
-
-                for (a_t, b_t) in a_rest.iter().zip(&b[..len]) {
-                    self.unify(word, sig, stack_shot, a_t, b_t, context)?;
-                }
-
-                let tail = &b[len..];
-                context.set_multivar(*a_mv, tail.to_vec().into_boxed_slice());
-
-                Ok(())
-            }
-            _ => {
-                if a.len() != b.len() {
-                    return Err(CompileError::CannotExecSignature {
-                        word: word.word(),
-                        word_span: word.span(),
-                        stack: stack_shot.to_vec(),
-                        sig: sig.clone(),
-                    });
-                }
+/// How many ints a C function can take, one per argument register.
+pub const MAX_FFI_ARGS: usize = 6;
 
-                for (a_t, b_t) in a.iter().zip(b.iter()) {
-                    self.unify(word, sig, stack_shot, a_t, b_t, context)?;
-                }
+/// The builtins that make a raw system call, indexed by how many arguments
+/// they pass after the call number.
+pub const SYSCALLS: [&str; 7] = [
+    "syscall0", "syscall1", "syscall2", "syscall3", "syscall4", "syscall5", "syscall6",
+];
 
-                Ok(())
-            }
-        }
-    }
-}
+/// How many times a recursive definition may refine its own effect before
+/// it is rejected.
+const MAX_RECURSION_ROUNDS: usize = 8;
 
 pub struct Analyzer<'src, W: Iterator<Item = Word<'src>>> {
     word_bindings: HashMap<&'src str, Signature>,
+    /// Words the compiler lowers to instructions by name, which user
+    /// definitions therefore cannot replace.
+    builtins: HashSet<&'src str>,
+    /// The word being defined, while its body is checked.
+    current: Option<&'src str>,
+    /// The effects assumed for each recursive call in the current body,
+    /// checked against the effect of the whole body once it is known.
+    recursive_calls: Vec<Signature>,
     words: Peekable<W>,
 }
 
@@ -513,6 +646,9 @@ impl<'src, W: Iterator<Item = Word<'src>>> Analyzer<'src, W> {
     pub fn new(words: W) -> Self {
         Self {
             word_bindings: HashMap::new(),
+            builtins: HashSet::new(),
+            current: None,
+            recursive_calls: Vec::new(),
             words: words.peekable(),
         }
     }
@@ -529,12 +665,33 @@ impl<'src, W: Iterator<Item = Word<'src>>> Analyzer<'src, W> {
             .insert("*", S::new(vec![Int, Int], vec![Int]));
         self.word_bindings
             .insert("/", S::new(vec![Int, Int], vec![Int]));
+        self.word_bindings
+            .insert("=", S::new(vec![Int, Int], vec![Bool]));
+        self.word_bindings
+            .insert("<", S::new(vec![Int, Int], vec![Bool]));
 
         self.word_bindings.insert("exit", S::new(vec![Int], vec![]));
 
         self.word_bindings
             .insert("puts", S::new(vec![String], vec![]));
 
+        self.word_bindings.insert("argc", S::new(vec![], vec![Int]));
+        self.word_bindings
+            .insert("argv-nth", S::new(vec![Int], vec![String]));
+        self.word_bindings
+            .insert("getenv", S::new(vec![String], vec![String, Bool]));
+
+        self.word_bindings
+            .insert("read-line", S::new(vec![], vec![String, Bool]));
+        self.word_bindings
+            .insert("read-all", S::new(vec![], vec![String]));
+
+        // `nr a1 .. an syscalln` returns what the kernel leaves in `rax`.
+        for (args, name) in SYSCALLS.into_iter().enumerate() {
+            self.word_bindings
+                .insert(name, S::new(vec![Int; args + 1], vec![Int]));
+        }
+
         self.word_bindings
             .insert("true", S::new(vec![], vec![Bool]));
         self.word_bindings
@@ -563,6 +720,8 @@ impl<'src, W: Iterator<Item = Word<'src>>> Analyzer<'src, W> {
         );
         self.word_bindings
             .insert("?", S::new(vec![Var(0), Var(0), Bool], vec![Var(0)]));
+
+        self.builtins = self.word_bindings.keys().copied().collect();
     }
 
     pub fn analyze(words: W) -> Result<Vec<Def<'src>>, CompileError<'src>> {
@@ -571,8 +730,12 @@ impl<'src, W: Iterator<Item = Word<'src>>> Analyzer<'src, W> {
 
         let mut defs = Vec::new();
 
-        while analyzer.words.peek().is_some() {
-            defs.push(analyzer.check_def()?);
+        while let Some(word) = analyzer.words.peek() {
+            let def = match word.token() {
+                Token::Symbol("FFI:") => analyzer.check_ffi()?,
+                _ => analyzer.check_def()?,
+            };
+            defs.push(def);
         }
 
         Ok(defs)
@@ -616,8 +779,22 @@ impl<'src, W: Iterator<Item = Word<'src>>> Analyzer<'src, W> {
             unreachable!();
         };
 
-        let mut state = State::new();
+        if self.builtins.contains(name) {
+            return Err(CompileError::RedefinedBuiltin {
+                name,
+                span: name_span,
+            });
+        }
+        if self.word_bindings.contains_key(name) {
+            return Err(CompileError::Redefined {
+                name,
+                span: name_span,
+            });
+        }
+
         let mut context = Context::new();
+        let mut state = State::new(&mut context);
+        self.current = Some(name);
 
         while self
             .words
@@ -631,6 +808,41 @@ impl<'src, W: Iterator<Item = Word<'src>>> Analyzer<'src, W> {
             |t| matches!(t, Token::Symbol(";")),
             "expected `;` to end definition",
         )?;
+        self.current = None;
+        let recursive_calls = std::mem::take(&mut self.recursive_calls);
+
+        // Each recursive call must be an instance of the body's effect. Tying
+        // the knot can make that effect more specific, so repeat until it
+        // settles.
+        let mut effect = state.resolve(&context);
+        let mut rounds = 0;
+        while !recursive_calls.is_empty() {
+            let inconsistent = CompileError::InconsistentRecursion {
+                name,
+                span: name_span,
+                sig: effect.canonicalize(),
+            };
+            if rounds == MAX_RECURSION_ROUNDS {
+                return Err(inconsistent);
+            }
+            rounds += 1;
+
+            for call in &recursive_calls {
+                let instance = context.instantiate(&effect);
+                if context
+                    .unify(&Type::Quotation(instance), &Type::Quotation(call.clone()))
+                    .is_err()
+                {
+                    return Err(inconsistent);
+                }
+            }
+
+            let settled = state.resolve(&context);
+            if settled.canonicalize().to_string() == effect.canonicalize().to_string() {
+                break;
+            }
+            effect = settled;
+        }
 
         let (ty, body) = state.resolve_all(&context);
         self.word_bindings.insert(name, ty.clone());
@@ -642,6 +854,75 @@ impl<'src, W: Iterator<Item = Word<'src>>> Analyzer<'src, W> {
         })
     }
 
+    /// Checks `FFI: name ( int int -- int )`, which declares the C function
+    /// `name` so that it can be called like a word.
+    fn check_ffi(&mut self) -> Result<Def<'src>, CompileError<'src>> {
+        self.words.next();
+
+        let name = self.expect(
+            |t| matches!(t, Token::Symbol(s) if is_c_name(s)),
+            "expected the name of a C function",
+        )?;
+
+        let name_span = name.span();
+        let Token::Symbol(name) = name.token() else {
+            unreachable!();
+        };
+
+        if self.builtins.contains(name) {
+            return Err(CompileError::RedefinedBuiltin {
+                name,
+                span: name_span,
+            });
+        }
+        if self.word_bindings.contains_key(name) {
+            return Err(CompileError::Redefined {
+                name,
+                span: name_span,
+            });
+        }
+
+        self.expect(
+            |t| matches!(t, Token::Symbol("(")),
+            "expected `(` to start the signature of a C function",
+        )?;
+
+        let mut inputs = Vec::new();
+        loop {
+            let word = self.expect(
+                |t| matches!(t, Token::Symbol("int" | "--")),
+                "expected `int` or `--`",
+            )?;
+            if matches!(word.token(), Token::Symbol("--")) {
+                break;
+            }
+            if inputs.len() == MAX_FFI_ARGS {
+                return Err(CompileError::Expected {
+                    found: Some(word),
+                    reason: "C functions take at most 6 ints",
+                });
+            }
+            inputs.push(Type::Int);
+        }
+
+        let mut outputs = Vec::new();
+        let word = self.expect(
+            |t| matches!(t, Token::Symbol("int" | ")")),
+            "expected `int` or `)`",
+        )?;
+        if matches!(word.token(), Token::Symbol("int")) {
+            outputs.push(Type::Int);
+            self.expect(
+                |t| matches!(t, Token::Symbol(")")),
+                "C functions return at most one int",
+            )?;
+        }
+
+        let ty = Signature::from_rows(inputs, outputs);
+        self.word_bindings.insert(name, ty.clone());
+        Ok(Def::Ffi { name, ty })
+    }
+
     fn check_word(
         &mut self,
         state: &mut State<'src>,
@@ -654,15 +935,22 @@ impl<'src, W: Iterator<Item = Word<'src>>> Analyzer<'src, W> {
         let item = Item::new(
             match word.token() {
                 Token::Integer(i) => {
-                    state.push_output(Type::Int);
+                    state.push(Type::Int);
                     ItemKind::Integer(i)
                 }
                 Token::String(s) => {
-                    state.push_output(Type::String);
+                    state.push(Type::String);
                     ItemKind::String(s)
                 }
+                Token::Symbol("_" | "??") => {
+                    let sig = state.resolve(context).canonicalize();
+                    return Err(CompileError::Hole {
+                        span: word.span(),
+                        sig,
+                    });
+                }
                 Token::Symbol("[") => {
-                    let mut quotation_state = State::new();
+                    let mut quotation_state = State::new(context);
 
                     while self
                         .words
@@ -674,9 +962,23 @@ impl<'src, W: Iterator<Item = Word<'src>>> Analyzer<'src, W> {
 
                     self.words.next();
 
-                    let (sig, items) = quotation_state.resolve_all(context);
-                    state.push_output(Type::Quotation(sig.clone()));
-                    ItemKind::Quotation(sig, items.into_boxed_slice())
+                    let sig = quotation_state.resolve(context);
+                    state.push(Type::Quotation(sig.clone()));
+                    ItemKind::Quotation(sig, quotation_state.items.into_boxed_slice())
+                }
+                Token::Symbol(sym) if self.current == Some(sym) => {
+                    // The effect of a recursive call is unknown until the
+                    // whole body is checked, so assume the most general one.
+                    let instance = Signature::new(
+                        vec![Type::MultiVar(context.gen_multivar())],
+                        vec![Type::MultiVar(context.gen_multivar())],
+                    );
+                    state
+                        .apply(&instance, context)
+                        .expect("a bare row effect applies to any stack");
+                    self.recursive_calls.push(instance.clone());
+
+                    ItemKind::Word(instance, sym)
                 }
                 Token::Symbol(sym) => {
                     let Some(signature) = self.word_bindings.get(sym) else {
@@ -686,10 +988,19 @@ impl<'src, W: Iterator<Item = Word<'src>>> Analyzer<'src, W> {
                         });
                     };
 
-                    let mut signature = signature.clone();
-                    self.try_signature(word, state, &mut signature, context, true)?;
+                    let instance = context.instantiate(signature);
+                    let before = state.resolve(context);
 
-                    ItemKind::Word(signature, sym)
+                    if state.apply(&instance, context).is_err() {
+                        return Err(CompileError::CannotExecSignature {
+                            word: sym,
+                            word_span: word.span(),
+                            stack: before.canonicalize().outputs,
+                            sig: signature.canonicalize(),
+                        });
+                    }
+
+                    ItemKind::Word(instance, sym)
                 }
             },
             word.span(),
@@ -699,60 +1010,260 @@ impl<'src, W: Iterator<Item = Word<'src>>> Analyzer<'src, W> {
 
         Ok(())
     }
+}
 
-    fn try_signature(
-        &mut self,
-        word: Word<'src>,
-        state: &mut State<'src>,
-        sig: &mut Signature,
-        context: &mut Context,
-        instantiate: bool,
-    ) -> Result<(), CompileError<'src>> {
-        let stack = state.clone_outputs();
-
-        if instantiate {
-            let mut local_vars = HashMap::new();
-            let mut local_multivars = HashMap::new();
-
-            state.instantiate(
-                &mut sig.inputs,
-                &mut local_vars,
-                &mut local_multivars,
-                context,
-            );
-            state.instantiate(
-                &mut sig.outputs,
-                &mut local_vars,
-                &mut local_multivars,
-                context,
-            );
-        }
-
-        for input in &sig.inputs {
-            if let Type::MultiVar(mv) = input {
-                let Some(tys) = context.get_multivar(*mv) else {
-                    todo!("undefined multivar")
-                };
-                self.try_signature(
-                    word,
-                    state,
-                    &mut Signature::new(tys.to_vec(), Vec::new()),
-                    context,
-                    false,
-                )?;
-            } else if let Some(ty) = state.signature.outputs.pop() {
-                state.unify(word, &sig, &stack, input, &ty, context)?;
-            } else {
-                state.push_input(input.clone());
-            }
-        }
+/// Whether `name` is a C identifier, and so can name a C function.
+fn is_c_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
 
-        let mut new_outputs = Vec::new();
-        sig.outputs
-            .iter()
-            .for_each(|t_inner| state.resolve_type(t_inner.clone(), &mut new_outputs, context));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
 
-        state.signature.outputs.extend(new_outputs);
-        Ok(())
+    fn signatures(source: &str) -> Vec<String> {
+        Analyzer::analyze(Lexer::new(source))
+            .unwrap()
+            .into_iter()
+            .map(|def| match def {
+                Def::WordDef { name, ty, .. } | Def::Ffi { name, ty, .. } => {
+                    format!("{name} {}", ty.canonicalize())
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn signatures_use_canonical_variable_names() {
+        assert_eq!(
+            signatures(": a 1 drop ; : b swap over ; : c [ dup ] ;"),
+            [
+                "a ( -- )",
+                "b ( 'a 'b -- 'b 'a 'b )",
+                "c ( -- ( 'a -- 'a 'a ) )",
+            ]
+        );
+    }
+
+    #[test]
+    fn main_must_take_nothing_and_return_at_most_an_int() {
+        let check = |source| check_main(&Analyzer::analyze(Lexer::new(source)).unwrap());
+
+        assert!(check(": main ;").is_ok());
+        assert!(check(": main 3 ;").is_ok());
+        assert!(matches!(check(": foo ;"), Err(CompileError::MissingMain)));
+        assert!(matches!(
+            check(": main true ;"),
+            Err(CompileError::InvalidMainSignature { .. })
+        ));
+        assert!(matches!(
+            check(": main drop ;"),
+            Err(CompileError::InvalidMainSignature { .. })
+        ));
+    }
+
+    #[test]
+    fn words_can_call_themselves() {
+        assert_eq!(
+            signatures(
+                ": countdown dup 0 = [ drop ] [ 1 - countdown ] ? apply ;
+                 : sum dup 0 = [ drop 0 ] [ dup 1 - sum + ] ? apply ;"
+            ),
+            ["countdown ( int -- )", "sum ( int -- int )"]
+        );
+
+        assert!(matches!(
+            Analyzer::analyze(Lexer::new(
+                ": bad dup 0 = [ drop ] [ drop \"s\" bad ] ? apply ;"
+            )),
+            Err(CompileError::InconsistentRecursion { name: "bad", .. })
+        ));
+    }
+
+    #[test]
+    fn higher_order_words_are_row_polymorphic() {
+        assert_eq!(
+            signatures(
+                ": ap apply ;
+                 : ap2 [ apply ] apply ;
+                 : nest [ [ 1 ] apply ] apply ;
+                 : use 3 [ dup + ] ap ;"
+            ),
+            [
+                "ap ( ..a ( ..a -- ..b ) -- ..b )",
+                "ap2 ( ..a ( ..a -- ..b ) -- ..b )",
+                "nest ( -- int )",
+                "use ( -- int )",
+            ]
+        );
+    }
+
+    #[test]
+    fn quotation_bodies_see_later_bindings() {
+        let defs = Analyzer::analyze(Lexer::new(": f [ dup ] \"s\" swap apply ;")).unwrap();
+        let Def::WordDef { body, .. } = &defs[0] else {
+            panic!("expected a word");
+        };
+        let ItemKind::Quotation(_, items) = &body[0].kind else {
+            panic!("expected quotation");
+        };
+        let ItemKind::Word(sig, "dup") = &items[0].kind else {
+            panic!("expected `dup`");
+        };
+        assert_eq!(sig.to_string(), "( string -- string string )");
+    }
+
+    #[test]
+    fn builtins_cannot_be_redefined() {
+        assert!(matches!(
+            Analyzer::analyze(Lexer::new(": dup dup ; : main ;")),
+            Err(CompileError::RedefinedBuiltin { name: "dup", .. })
+        ));
+        assert!(matches!(
+            Analyzer::analyze(Lexer::new(": + 1 ;")),
+            Err(CompileError::RedefinedBuiltin { name: "+", .. })
+        ));
+    }
+
+    #[test]
+    fn words_cannot_be_redefined() {
+        assert!(matches!(
+            Analyzer::analyze(Lexer::new(": main ; : main 1 2 ;")),
+            Err(CompileError::Redefined { name: "main", .. })
+        ));
+        assert!(matches!(
+            Analyzer::analyze(Lexer::new(": foo 1 ; : foo ; : main foo ;")),
+            Err(CompileError::Redefined { name: "foo", .. })
+        ));
+        assert!(matches!(
+            Analyzer::analyze(Lexer::new("FFI: abs ( int -- int ) : abs ; : main ;")),
+            Err(CompileError::Redefined { name: "abs", .. })
+        ));
+        assert!(matches!(
+            Analyzer::analyze(Lexer::new(": abs ; FFI: abs ( int -- int ) : main ;")),
+            Err(CompileError::Redefined { name: "abs", .. })
+        ));
+    }
+
+    #[test]
+    fn arguments_and_the_environment_are_strings() {
+        assert_eq!(
+            signatures(": first 1 argv-nth ; : home \"HOME\" getenv ; : n argc ;"),
+            [
+                "first ( -- string )",
+                "home ( -- string bool )",
+                "n ( -- int )"
+            ]
+        );
+    }
+
+    #[test]
+    fn input_is_read_as_strings() {
+        assert_eq!(
+            signatures(": line read-line ; : rest read-all ;"),
+            ["line ( -- string bool )", "rest ( -- string )"]
+        );
+    }
+
+    #[test]
+    fn syscalls_take_a_number_and_their_arguments() {
+        assert_eq!(
+            signatures(": pid 39 syscall0 ; : write syscall3 ;"),
+            ["pid ( -- int )", "write ( int int int int -- int )"]
+        );
+        assert!(matches!(
+            Analyzer::analyze(Lexer::new(": syscall1 ;")),
+            Err(CompileError::RedefinedBuiltin {
+                name: "syscall1",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn c_functions_are_typed_by_their_declaration() {
+        assert_eq!(
+            signatures(
+                "FFI: malloc ( int -- int )
+                 FFI: free ( int -- )
+                 FFI: rand ( -- int )
+                 : f 16 malloc free rand ;"
+            ),
+            [
+                "malloc ( int -- int )",
+                "free ( int -- )",
+                "rand ( -- int )",
+                "f ( -- int )",
+            ]
+        );
+
+        assert!(matches!(
+            Analyzer::analyze(Lexer::new("FFI: free ( int -- ) : f \"s\" free ;")),
+            Err(CompileError::CannotExecSignature { word: "free", .. })
+        ));
+    }
+
+    #[test]
+    fn c_function_declarations_are_checked() {
+        let reason = |source| match Analyzer::analyze(Lexer::new(source)) {
+            Err(CompileError::Expected { reason, .. }) => reason,
+            _ => panic!("expected an error in `{source}`"),
+        };
+
+        assert_eq!(
+            reason("FFI: 2dup ( -- )"),
+            "expected the name of a C function"
+        );
+        assert_eq!(
+            reason("FFI: f int -- )"),
+            "expected `(` to start the signature of a C function"
+        );
+        assert_eq!(reason("FFI: f ( string -- )"), "expected `int` or `--`");
+        assert_eq!(reason("FFI: f ( -- bool )"), "expected `int` or `)`");
+        assert_eq!(
+            reason("FFI: f ( -- int int )"),
+            "C functions return at most one int"
+        );
+        assert_eq!(
+            reason("FFI: f ( int int int int int int int -- )"),
+            "C functions take at most 6 ints"
+        );
+        assert!(matches!(
+            Analyzer::analyze(Lexer::new("FFI: exit ( int -- )")),
+            Err(CompileError::RedefinedBuiltin { name: "exit", .. })
+        ));
+    }
+
+    #[test]
+    fn holes_report_the_effect_so_far() {
+        let hole = |source| match Analyzer::analyze(Lexer::new(source)) {
+            Err(CompileError::Hole { span, sig }) => (span.parts().0, sig.to_string()),
+            _ => panic!("expected a hole in `{source}`"),
+        };
+
+        assert_eq!(hole(": f 1 \"s\" _ ;"), (10, "( -- int string )".into()));
+        assert_eq!(hole(": f swap ?? ;"), (9, "( 'a 'b -- 'b 'a )".into()));
+        assert_eq!(hole(": f 1 [ dup _ ] ;"), (12, "( 'a -- 'a 'a )".into()));
+        assert_eq!(hole(": f 1 [ _ ] ;"), (8, "( -- )".into()));
+
+        let source = ": f [ _ ] ;";
+        let err = Analyzer::analyze(Lexer::new(source)).unwrap_err();
+        let mut out = Vec::new();
+        report_error(err, Path::new("f.zila"), source, false, &mut out).unwrap();
+        assert!(
+            String::from_utf8(out)
+                .unwrap()
+                .contains("NOTE: stack state:\nNOTE:     (empty)\n")
+        );
+    }
+
+    #[test]
+    fn self_application_fails_occurs_check() {
+        assert!(matches!(
+            Analyzer::analyze(Lexer::new(": f dup apply ;")),
+            Err(CompileError::CannotExecSignature { .. })
+        ));
     }
 }
@@ -0,0 +1,74 @@
+//! The part of native code generation that doesn't depend on the target:
+//! walking the procs, deciding where the data stack is checked and which
+//! calls are tail calls. Each target implements `Backend` for the rest.
+
+use crate::{
+    compiler::{self, Entry, Instruction, Proc},
+    lexer::Span,
+};
+
+pub trait Backend<'src> {
+    /// What the backend generates, e.g. the assembly of the whole program.
+    type Output;
+
+    /// Generates the entry point, which calls `entry` and exits, and any
+    /// code and data shared by the procs.
+    fn gen_start(&mut self, entry: Entry<'src>);
+
+    /// Starts a new proc. Its instructions follow.
+    fn gen_proc(&mut self, proc: &Proc<'src>);
+
+    /// Checks that the data stack has `below` slots under the top and room
+    /// for `above` more. Either may be zero.
+    fn gen_stack_check(&mut self, below: usize, above: usize);
+
+    /// Generates `instruction`. A `tail` call is the last instruction of
+    /// its proc and returns to the caller's caller.
+    fn gen_instruction(&mut self, span: Span, instruction: Instruction<'src>, tail: bool);
+
+    /// Returns from a proc that doesn't end in a tail call.
+    fn gen_return(&mut self);
+
+    fn finish(self) -> Self::Output;
+}
+
+/// Generates the program with `backend`, starting at `entry`.
+pub fn generate<'src, B: Backend<'src>>(
+    mut backend: B,
+    entry: Entry<'src>,
+    procs: &[Proc<'src>],
+) -> B::Output {
+    backend.gen_start(entry);
+    for proc in procs {
+        backend.gen_proc(proc);
+
+        let instructions = proc.code();
+        gen_stack_check(&mut backend, instructions);
+        for (i, &(span, instruction)) in instructions.iter().enumerate() {
+            let tail = i + 1 == instructions.len();
+            backend.gen_instruction(span, instruction, tail);
+
+            // The callee may have left the stack anywhere, so the rest is
+            // checked from there.
+            if !tail && matches!(instruction, Instruction::Call(_) | Instruction::Apply) {
+                gen_stack_check(&mut backend, &instructions[i + 1..]);
+            }
+        }
+
+        if !matches!(
+            instructions.last(),
+            Some((_, Instruction::Call(_) | Instruction::Apply))
+        ) {
+            backend.gen_return();
+        }
+    }
+    backend.finish()
+}
+
+/// Checks the bounds of the instructions up to the next call.
+fn gen_stack_check<'src>(backend: &mut impl Backend<'src>, instructions: &[(Span, Instruction)]) {
+    let (below, above) = compiler::segment_bounds(instructions);
+    if below > 0 || above > 0 {
+        backend.gen_stack_check(below, above);
+    }
+}
@@ -0,0 +1,513 @@
+//! Translates the IR to portable C99, for hosts `x86_64gen` can't target.
+//!
+//! The data stack is an array of `uint64_t` slots indexed by `sp`, laid out
+//! exactly as in the native backend: a string is its pointer below its
+//! length, a bool is all ones or all zeros and a quotation is a function
+//! pointer. C compilers don't have to eliminate tail calls, so every proc
+//! returns the proc to tail call, if any, and `run` calls it in a loop.
+
+use std::io::{self, Write};
+
+use crate::{
+    compiler::{self, Entry, Instruction, Label, Proc},
+    lexer::Span,
+};
+
+const PRELUDE: &str = "\
+#include <signal.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+struct next;
+typedef struct next (*proc)(void);
+struct next {
+    proc fn;
+};
+
+static uint64_t stack[STACK_SLOTS];
+static size_t sp;
+
+static int arg_count;
+static char **arg_values;
+
+static void run(proc fn) {
+    while (fn) {
+        fn = fn().fn;
+    }
+}
+
+static inline void stack_error(const char *message) {
+    fflush(stdout);
+    fputs(message, stderr);
+    exit(1);
+}
+
+/* Traps like `idiv` does rather than leaving the result undefined. */
+static inline uint64_t divide(uint64_t a, uint64_t b) {
+    if (b == 0 || ((int64_t)a == INT64_MIN && (int64_t)b == -1)) {
+        raise(SIGFPE);
+    }
+    return (uint64_t)((int64_t)a / (int64_t)b);
+}
+
+/* The value of the variable named by the `len` bytes at `name`, or NULL if
+   it isn't set. Names with `=` or a null byte in them never are. */
+static inline const char *env_value(const char *name, size_t len) {
+    char *copy;
+    const char *value;
+    if (memchr(name, '=', len) || memchr(name, 0, len) || !(copy = malloc(len + 1))) {
+        return NULL;
+    }
+    memcpy(copy, name, len);
+    copy[len] = 0;
+    value = getenv(copy);
+    free(copy);
+    return value;
+}
+
+/* Reads stdin up to the next newline, or to its end if `line` is 0, into a
+   buffer that is never freed, and stores its address and length in
+   `string`. Returns 0 if the input had already ended. */
+static inline int read_input(int line, uint64_t *string) {
+    size_t len = 0, size = 64;
+    char *s = malloc(size);
+    int c;
+    fflush(stdout);
+    while ((c = getchar()) != EOF && !(line && c == '\\n')) {
+        if (len == size) {
+            s = realloc(s, size *= 2);
+        }
+        if (!s) {
+            fputs(\"out of memory\\n\", stderr);
+            exit(1);
+        }
+        s[len++] = (char)c;
+    }
+    string[0] = (uintptr_t)s;
+    string[1] = len;
+    return c != EOF || len > 0;
+}
+";
+
+/// A C identifier for `label`. Mangled names only use ASCII letters,
+/// digits and underscores, as C99 doesn't promise more.
+fn c_name(label: Label) -> String {
+    label.to_string()
+}
+
+/// A C string literal with the bytes of `s`. Anything but letters, digits
+/// and spaces is written in octal, which sidesteps trigraphs and escapes.
+fn c_string(s: &str) -> String {
+    let mut literal = String::from("\"");
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b == b' ' {
+            literal.push(b as char);
+        } else {
+            literal.push_str(&format!("\\{b:03o}"));
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Writes the program as a C99 translation unit whose data stack holds
+/// `stack_slots` slots.
+pub fn generate(
+    entry: Entry,
+    procs: &[Proc],
+    string_literals: &[Box<str>],
+    stack_slots: usize,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    writeln!(out, "#define STACK_SLOTS {stack_slots}")?;
+    write!(out, "{PRELUDE}")?;
+
+    writeln!(out)?;
+    for (i, string_literal) in string_literals.iter().enumerate() {
+        writeln!(
+            out,
+            "static const char str_{i}[] = {};",
+            c_string(string_literal)
+        )?;
+    }
+
+    writeln!(out)?;
+    for proc in procs {
+        writeln!(out, "static struct next {}(void);", c_name(proc.label()))?;
+    }
+
+    for proc in procs {
+        writeln!(out)?;
+        gen_proc(proc, string_literals, out)?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "int main(int argc, char **argv) {{")?;
+    writeln!(out, "    arg_count = argc;")?;
+    writeln!(out, "    arg_values = argv;")?;
+    writeln!(out, "    run({});", c_name(entry.label()))?;
+    if entry.returns_exit_code() {
+        writeln!(out, "    return (int)stack[sp - 1];")?;
+    } else {
+        writeln!(out, "    return 0;")?;
+    }
+    writeln!(out, "}}")
+}
+
+fn gen_proc(proc: &Proc, string_literals: &[Box<str>], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "static struct next {}(void) {{", c_name(proc.label()))?;
+
+    let instructions = proc.code();
+    gen_stack_check(instructions, out)?;
+    for (i, &(span, instruction)) in instructions.iter().enumerate() {
+        writeln!(out, "    /* {span:?} -- {instruction} */")?;
+        let tail = i + 1 == instructions.len();
+        gen_instruction(instruction, tail, string_literals, out)?;
+
+        if !tail && matches!(instruction, Instruction::Call(_) | Instruction::Apply) {
+            gen_stack_check(&instructions[i + 1..], out)?;
+        }
+    }
+
+    if !matches!(
+        instructions.last(),
+        Some((_, Instruction::Call(_) | Instruction::Apply))
+    ) {
+        writeln!(out, "    return (struct next){{0}};")?;
+    }
+    writeln!(out, "}}")
+}
+
+/// Checks that the stack can take what `instructions` do to it up to the
+/// next call.
+fn gen_stack_check(instructions: &[(Span, Instruction)], out: &mut impl Write) -> io::Result<()> {
+    let (below, above) = compiler::segment_bounds(instructions);
+    if below > 0 {
+        writeln!(
+            out,
+            "    if (sp < {below}) stack_error(\"data stack underflow\\n\");"
+        )?;
+    }
+    if above > 0 {
+        writeln!(
+            out,
+            "    if (STACK_SLOTS - sp < {above}) stack_error(\"data stack overflow\\n\");"
+        )?;
+    }
+    Ok(())
+}
+
+fn gen_instruction(
+    instruction: Instruction,
+    tail: bool,
+    string_literals: &[Box<str>],
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let call = |callee: &str| {
+        if tail {
+            format!("    return (struct next){{{callee}}};")
+        } else {
+            format!("    run({callee});")
+        }
+    };
+
+    match instruction {
+        // `-9223372036854775808` negates a literal that doesn't fit.
+        Instruction::PushInt(isize::MIN) => writeln!(out, "    stack[sp++] = (uint64_t)INT64_MIN;"),
+        Instruction::PushInt(i) => writeln!(out, "    stack[sp++] = (uint64_t)INT64_C({i});"),
+        Instruction::PushBool(b) => writeln!(
+            out,
+            "    stack[sp++] = {};",
+            if b { "UINT64_MAX" } else { "0" }
+        ),
+        Instruction::PushString(i) => {
+            writeln!(out, "    stack[sp++] = (uint64_t)(uintptr_t)str_{i};")?;
+            writeln!(out, "    stack[sp++] = {};", string_literals[i].len())
+        }
+        Instruction::PushQuote(q) => {
+            writeln!(out, "    stack[sp++] = (uint64_t)(uintptr_t){};", c_name(q))
+        }
+
+        Instruction::Add => writeln!(out, "    sp--; stack[sp - 1] += stack[sp];"),
+        Instruction::Sub => writeln!(out, "    sp--; stack[sp - 1] -= stack[sp];"),
+        Instruction::Mul => writeln!(out, "    sp--; stack[sp - 1] *= stack[sp];"),
+        Instruction::Div => writeln!(
+            out,
+            "    sp--; stack[sp - 1] = divide(stack[sp - 1], stack[sp]);"
+        ),
+
+        Instruction::Eq => writeln!(
+            out,
+            "    sp--; stack[sp - 1] = stack[sp - 1] == stack[sp] ? UINT64_MAX : 0;"
+        ),
+        Instruction::Lt => writeln!(
+            out,
+            "    sp--; stack[sp - 1] = (int64_t)stack[sp - 1] < (int64_t)stack[sp] ? UINT64_MAX : 0;"
+        ),
+
+        Instruction::Exit => writeln!(out, "    exit((int)stack[--sp]);"),
+
+        Instruction::Puts => {
+            writeln!(out, "    sp -= 2;")?;
+            writeln!(
+                out,
+                "    fwrite((const char *)(uintptr_t)stack[sp], 1, (size_t)stack[sp + 1], stdout);"
+            )
+        }
+
+        Instruction::Argc => writeln!(out, "    stack[sp++] = (uint64_t)arg_count;"),
+        Instruction::ArgvNth => {
+            writeln!(out, "    {{")?;
+            writeln!(
+                out,
+                "        const char *arg = stack[sp - 1] < (uint64_t)arg_count ? arg_values[stack[sp - 1]] : \"\";"
+            )?;
+            writeln!(out, "        stack[sp - 1] = (uintptr_t)arg;")?;
+            writeln!(out, "        stack[sp++] = strlen(arg);")?;
+            writeln!(out, "    }}")
+        }
+        Instruction::Getenv => {
+            writeln!(out, "    {{")?;
+            writeln!(
+                out,
+                "        const char *value = env_value((const char *)(uintptr_t)stack[sp - 2], (size_t)stack[sp - 1]);"
+            )?;
+            writeln!(
+                out,
+                "        stack[sp - 2] = (uintptr_t)(value ? value : \"\");"
+            )?;
+            writeln!(out, "        stack[sp - 1] = value ? strlen(value) : 0;")?;
+            writeln!(out, "        stack[sp++] = value ? UINT64_MAX : 0;")?;
+            writeln!(out, "    }}")
+        }
+
+        Instruction::ReadLine => writeln!(
+            out,
+            "    stack[sp + 2] = read_input(1, &stack[sp]) ? UINT64_MAX : 0; sp += 3;"
+        ),
+        Instruction::ReadAll => writeln!(out, "    read_input(0, &stack[sp]); sp += 2;"),
+
+        Instruction::Dup { size } => writeln!(
+            out,
+            "    memcpy(&stack[sp], &stack[sp - {size}], {size} * sizeof *stack); sp += {size};"
+        ),
+        Instruction::Swap { size_a, size_b } => {
+            writeln!(out, "    {{")?;
+            writeln!(out, "        uint64_t top[{size_a}];")?;
+            writeln!(
+                out,
+                "        memcpy(top, &stack[sp - {size_a}], sizeof top);"
+            )?;
+            writeln!(
+                out,
+                "        memmove(&stack[sp - {size_b}], &stack[sp - {}], {size_b} * sizeof *stack);",
+                size_a + size_b
+            )?;
+            writeln!(
+                out,
+                "        memcpy(&stack[sp - {}], top, sizeof top);",
+                size_a + size_b
+            )?;
+            writeln!(out, "    }}")
+        }
+        Instruction::Drop { size } => writeln!(out, "    sp -= {size};"),
+        Instruction::Over { size_a, size_b } => writeln!(
+            out,
+            "    memcpy(&stack[sp], &stack[sp - {}], {size_b} * sizeof *stack); sp += {size_b};",
+            size_a + size_b
+        ),
+
+        Instruction::Branch { size } => {
+            writeln!(out, "    sp -= {};", 2 * size + 1)?;
+            writeln!(
+                out,
+                "    memmove(&stack[sp], &stack[sp + (stack[sp] ? 1 : {})], {size} * sizeof *stack);",
+                size + 1
+            )?;
+            writeln!(out, "    sp += {size};")
+        }
+
+        Instruction::Apply => {
+            writeln!(out, "    sp--;")?;
+            writeln!(out, "{}", call("(proc)(uintptr_t)stack[sp]"))
+        }
+        Instruction::Call(label) => writeln!(out, "{}", call(&c_name(label))),
+        Instruction::Ffi { .. } => unreachable!("only x86_64 code calls C functions"),
+        Instruction::Syscall { .. } => unreachable!("only x86_64 code makes system calls"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        os::unix::fs::PermissionsExt,
+        path::{Path, PathBuf},
+        process::{Command, Stdio},
+    };
+
+    use super::*;
+    use crate::{
+        analyzer::Analyzer,
+        backend,
+        compiler::{Compiler, DATA_STACK_SLOTS},
+        interp::Interpreter,
+        lexer::Lexer,
+        optimizer::{self, OptLevel},
+        x86_64enc, x86_64gen,
+    };
+
+    fn generate_c(source: &str, level: OptLevel, stack_slots: usize) -> String {
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        let (entry, mut procs, strings) = Compiler::compile(defs).unwrap();
+        optimizer::optimize(&mut procs, level);
+        let (entry, procs, strings) =
+            optimizer::eliminate_dead_code(entry.unwrap(), procs, strings);
+        let mut out = Vec::new();
+        generate(entry, &procs, &strings, stack_slots, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Compiles `c` with the system `cc` in a fresh temporary directory.
+    fn build_c(c: &str, name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("zila-c-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (src, exe) = (dir.join("out.c"), dir.join("out"));
+        fs::write(&src, c).unwrap();
+
+        let cc = Command::new("cc")
+            .args(["-std=c99", "-pedantic", "-Wall", "-Werror", "-O2", "-o"])
+            .arg(&exe)
+            .arg(&src)
+            .status()
+            .unwrap();
+        assert!(cc.success());
+
+        exe
+    }
+
+    fn run(exe: &Path) -> (String, String, i32) {
+        let output = Command::new(exe).output().unwrap();
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+            output.status.code().unwrap(),
+        )
+    }
+
+    #[test]
+    fn strings_are_written_in_octal() {
+        assert_eq!(c_string("a b\n\"?"), "\"a b\\012\\042\\077\"");
+        assert_eq!(
+            c_name(Label::new(3, Some("is-even?"))),
+            "zila_3_is_2d_even_3f_"
+        );
+    }
+
+    #[test]
+    fn tail_calls_return_the_callee() {
+        let c = generate_c(
+            ": loop dup 0 = [ drop ] [ 1 - loop ] ? apply ; : main 5 loop ;",
+            OptLevel::O0,
+            DATA_STACK_SLOTS,
+        );
+        assert!(c.contains("    return (struct next){zila_0_loop};\n}"));
+        assert!(c.contains("    sp--;\n    return (struct next){(proc)(uintptr_t)stack[sp]};\n}"));
+    }
+
+    #[test]
+    fn c_matches_the_interpreter_and_native_code() {
+        let programs = [
+            ": main \"hello\\n\" puts 3 4 * 5 - 2 / ;",
+            ": main \"a\" 1 swap puts \"b\" over drop puts 2 swap - ;",
+            ": pick [ \"yes\" ] [ \"no\" ] ? apply ;
+             : main true pick puts false pick puts
+             1 2 < [ 10 ] [ 20 ] ? apply 3 3 = [ 4 ] [ 5 ] ? apply + ;",
+            ": count dup 0 = [ drop ] [ 1 - count ] ? apply ;
+             : main 1000000 count \"done\" puts ;",
+            ": s \"xy\" ;
+             : main 1 s 2 swap over drop dup puts puts + s 5 over puts drop puts \"end\" puts 7 exit ;",
+            ": main 0 7 - 2 / 7 0 2 - / * ;",
+        ];
+
+        for (i, source) in programs.into_iter().enumerate() {
+            let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+            let (entry, procs, strings) = Compiler::compile(defs).unwrap();
+            let mut out = Vec::new();
+            let code = Interpreter::run(
+                entry.unwrap(),
+                &procs,
+                &strings,
+                &[],
+                DATA_STACK_SLOTS,
+                io::empty(),
+                &mut out,
+            )
+            .unwrap();
+            let expected = (
+                String::from_utf8(out).unwrap(),
+                String::new(),
+                code as u8 as i32,
+            );
+
+            for level in [OptLevel::O0, OptLevel::O2] {
+                let c = generate_c(source, level, DATA_STACK_SLOTS);
+                let exe = build_c(&c, &format!("{i}-{level:?}"));
+                assert_eq!(run(&exe), expected, "{source} at {level:?}");
+            }
+
+            let backend = x86_64gen::X86_64::new(&strings, 0, DATA_STACK_SLOTS);
+            let assembly = backend::generate(backend, entry.unwrap(), &procs);
+            let native = env::temp_dir().join(format!("zila-c-native-{i}-{}", std::process::id()));
+            let mut file = fs::File::create(&native).unwrap();
+            x86_64enc::encode(&assembly)
+                .unwrap()
+                .write(&mut file)
+                .unwrap();
+            file.set_permissions(fs::Permissions::from_mode(0o755))
+                .unwrap();
+            drop(file);
+            assert_eq!(run(&native), expected, "{source} natively");
+        }
+    }
+
+    #[test]
+    fn arguments_and_environment_are_read() {
+        let source = ": main 1 argv-nth puts 5 argv-nth puts \"ZILA_VAR\" getenv drop puts
+                      \"ZILA_VAR=\" getenv swap drop [ 1 ] [ 2 ] ? apply argc + ;";
+        let exe = build_c(&generate_c(source, OptLevel::O1, DATA_STACK_SLOTS), "args");
+        let output = Command::new(exe)
+            .args(["a", "b"])
+            .env("ZILA_VAR", "x=y")
+            .output()
+            .unwrap();
+        assert_eq!(output.stdout, b"ax=y");
+        assert_eq!(output.status.code(), Some(5));
+    }
+
+    #[test]
+    fn input_is_read_by_the_line_or_whole() {
+        let source = ": main read-line drop read-line drop read-all puts puts puts
+                      read-line swap drop [ 1 ] [ 2 ] ? apply ;";
+        let exe = build_c(&generate_c(source, OptLevel::O1, DATA_STACK_SLOTS), "input");
+        let mut child = Command::new(exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"a\n\nb\nc").unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(output.stdout, b"b\nca");
+        assert_eq!(output.status.code(), Some(2));
+    }
+
+    #[test]
+    fn stack_overflow_is_reported() {
+        let source = ": grow dup 0 = [ ] [ dup 1 - grow + ] ? apply ; : main 100 grow ;";
+        let exe = build_c(&generate_c(source, OptLevel::O1, 50), "overflow");
+        let (_, stderr, code) = run(&exe);
+        assert_eq!((stderr.as_str(), code), ("data stack overflow\n", 1));
+    }
+}
@@ -3,33 +3,156 @@ use std::{
     path::{Path, PathBuf},
 };
 
-enum CommandError {
-    FlagExpectsArgument { flag: Box<str>, arguments: Box<str> },
-    NoFileGiven,
-    UnknownFlag,
+use crate::{compiler::DATA_STACK_SLOTS, diagnostic::ColorChoice, optimizer::OptLevel};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Build,
+    Check,
+    Run { interp: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emit {
+    Exe,
+    Ir,
+    Asm,
+    C,
+    Ll,
+    Wat,
+}
+
+impl Emit {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "exe" => Some(Self::Exe),
+            "ir" => Some(Self::Ir),
+            "asm" => Some(Self::Asm),
+            "c" => Some(Self::C),
+            "ll" => Some(Self::Ll),
+            "wat" => Some(Self::Wat),
+            _ => None,
+        }
+    }
+
+    /// The target to use when `--target` isn't given.
+    fn default_target(self) -> Target {
+        match self {
+            Self::C => Target::C,
+            Self::Ll => Target::Llvm,
+            Self::Wat => Target::Wasm,
+            Self::Exe | Self::Ir | Self::Asm => Target::X86_64,
+        }
+    }
+
+    /// Whether `target` can write this kind of output.
+    fn is_written_by(self, target: Target) -> bool {
+        match self {
+            Self::Exe => target != Target::Wasm,
+            Self::Ir => true,
+            Self::Asm => matches!(target, Target::X86_64 | Target::Aarch64Linux),
+            Self::C => target == Target::C,
+            Self::Ll => target == Target::Llvm,
+            Self::Wat => target == Target::Wasm,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    X86_64,
+    Aarch64Linux,
+    C,
+    Llvm,
+    Wasm,
+}
+
+impl Target {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "x86_64" => Some(Self::X86_64),
+            "aarch64-linux" => Some(Self::Aarch64Linux),
+            "c" => Some(Self::C),
+            "llvm" => Some(Self::Llvm),
+            "wasm" => Some(Self::Wasm),
+            _ => None,
+        }
+    }
+}
+
+/// What zila was asked to do.
+#[derive(Debug)]
+pub enum Command {
+    Compile(CommandResult),
+    /// Demangles the given symbols, or the text on stdin if there are none.
+    Demangle(Vec<String>),
 }
 
 #[derive(Debug)]
 pub struct CommandResult {
+    pub mode: Mode,
+    pub emit: Emit,
+    pub target: Target,
+    pub opt_level: OptLevel,
     pub file: PathBuf,
     pub output_file: PathBuf,
+    pub color: ColorChoice,
+    pub verify_ir: bool,
+    pub link_libc: bool,
+    /// The `-l` and `-L` flags to link with.
+    pub link_args: Vec<String>,
+    pub stack_slots: usize,
     pub command_line_args: Vec<String>,
     pub program_name: PathBuf,
 }
 
 pub fn usage(program: &Path) {
     eprintln!(
-        "usage: {} [OPTIONS] <file.zila>
+        "usage: {0} [COMMAND] [OPTIONS] <file.zila | file.ir>
+       {0} demangle [symbol...]
+  COMMANDS:
+    build               Compiles the file to an executable (default)
+    check               Type-checks the file and prints the signature of every word
+    run                 Builds and runs the file, passing arguments after `--`
+    demangle            Prints the words that symbols were compiled from, or copies
+                        stdin to stdout with every symbol demangled
   OPTIONS:
-    -o <file>       Sets the name of the output assembly, object file, and executable",
+    -o <file>           Sets the name of the output executable, IR or assembly
+    -O<level>           Optimizes the IR: `0` (default) none, `1` folds constants and
+                        removes no-op shuffles, `2` also inlines
+    --color=<when>      Colors diagnostics: `auto` (default), `always` or `never`
+    --emit=<kind>       Writes `exe` (default) to `<file>`, or stops after writing `ir`,
+                        `asm`, `c`, LLVM `ll` or WebAssembly `wat` source to
+                        `<file>.<kind>` (`<file>.s` for AArch64 assembly)
+    --target=<target>   Generates `x86_64` (default) machine code, `aarch64-linux`
+                        assembly built with `aarch64-linux-gnu-as` and `-ld`, C99 built
+                        with `cc`, `llvm` IR built with `clang` or `wasm`, which is
+                        only written as `wat`
+    --stack-size=<n>    Gives the data stack room for `n` 8-byte slots (default 1024)
+    --link-libc         Links the x86_64 executable with `cc` against libc, so that
+                        C functions declared with `FFI:` can be called
+    -l<lib>, -L<dir>    With `--link-libc`, also links with `lib`, searching `dir`
+    --print-signatures  Same as `check`
+    --interp            With `run`, executes the IR with the reference interpreter
+    --verify-ir         Checks the stack effect of every proc before and after optimizing
+                        (always on in debug builds of zila)",
         program.display()
     );
 }
 
 pub struct CommandParser {
     args: Args,
+    mode: Option<Mode>,
+    emit: Option<Emit>,
+    target: Option<Target>,
+    opt_level: Option<OptLevel>,
     file: Option<PathBuf>,
     output_file: Option<PathBuf>,
+    color: Option<ColorChoice>,
+    verify_ir: bool,
+    link_libc: bool,
+    link_args: Vec<String>,
+    stack_slots: Option<usize>,
     program_name: PathBuf,
 }
 
@@ -40,25 +163,87 @@ impl CommandParser {
 
         Self {
             args,
+            mode: None,
+            emit: None,
+            target: None,
+            opt_level: None,
             file: None,
             output_file: None,
+            color: None,
+            verify_ir: false,
+            link_libc: false,
+            link_args: Vec::new(),
+            stack_slots: None,
             program_name,
         }
     }
 
     fn make_default(self, file: PathBuf) -> CommandResult {
         CommandResult {
+            mode: self.mode.unwrap_or(Mode::Build),
+            emit: self.emit.unwrap_or(Emit::Exe),
+            target: self.target.unwrap_or(Target::X86_64),
+            opt_level: self.opt_level.unwrap_or(OptLevel::O0),
             file,
             output_file: self.output_file.unwrap_or("output".into()),
+            color: self.color.unwrap_or(ColorChoice::Auto),
+            verify_ir: self.verify_ir,
+            link_libc: self.link_libc,
+            link_args: self.link_args,
+            stack_slots: self.stack_slots.unwrap_or(DATA_STACK_SLOTS),
             command_line_args: self.args.collect(),
             program_name: self.program_name,
         }
     }
 
-    pub fn parse_commands(mut self) -> Result<CommandResult, ()> {
+    fn set_mode(&mut self, mode: Mode) -> Result<(), ()> {
+        if self.mode.is_some_and(|m| m != mode) {
+            eprintln!("ERROR: multiple commands specified");
+            usage(&self.program_name);
+            return Err(());
+        }
+
+        self.mode = Some(mode);
+        Ok(())
+    }
+
+    pub fn parse_commands(mut self) -> Result<Command, ()> {
+        let mut first = true;
         while let Some(key) = self.args.next() {
+            if std::mem::take(&mut first) {
+                match key.as_str() {
+                    "demangle" => return Ok(Command::Demangle(self.args.collect())),
+                    "build" => {
+                        self.set_mode(Mode::Build)?;
+                        continue;
+                    }
+                    "check" => {
+                        self.set_mode(Mode::Check)?;
+                        continue;
+                    }
+                    "run" => {
+                        self.set_mode(Mode::Run { interp: false })?;
+                        continue;
+                    }
+                    _ => (),
+                }
+            }
+
             if let Some(flag) = key.strip_prefix('-') {
                 match flag {
+                    "-print-signatures" => self.set_mode(Mode::Check)?,
+                    "-verify-ir" => self.verify_ir = true,
+                    "-link-libc" => self.link_libc = true,
+                    _ if flag.len() > 1 && flag.starts_with(['l', 'L']) => self.link_args.push(key),
+                    "-interp" => {
+                        if !matches!(self.mode, Some(Mode::Run { .. })) {
+                            eprintln!("ERROR: `--interp` is only valid with `run`");
+                            usage(&self.program_name);
+                            return Err(());
+                        }
+
+                        self.mode = Some(Mode::Run { interp: true });
+                    }
                     "o" => {
                         let Some(output_file) = self.args.next() else {
                             eprintln!("ERROR: `-o` flag expects argument <file>");
@@ -74,6 +259,63 @@ impl CommandParser {
 
                         self.output_file = Some(output_file.into());
                     }
+                    _ if flag.starts_with("-color=") => {
+                        let when = &flag["-color=".len()..];
+                        let Some(color) = ColorChoice::parse(when) else {
+                            eprintln!(
+                                "ERROR: `--color` expects `auto`, `always` or `never`, found `{when}`"
+                            );
+                            usage(&self.program_name);
+                            return Err(());
+                        };
+
+                        self.color = Some(color);
+                    }
+                    _ if flag.starts_with('O') => {
+                        let Some(level) = OptLevel::parse(&flag[1..]) else {
+                            eprintln!("ERROR: unknown optimization level `{key}`");
+                            usage(&self.program_name);
+                            return Err(());
+                        };
+
+                        self.opt_level = Some(level);
+                    }
+                    _ if flag.starts_with("-emit=") => {
+                        let kind = &flag["-emit=".len()..];
+                        let Some(emit) = Emit::parse(kind) else {
+                            eprintln!(
+                                "ERROR: `--emit` expects `exe`, `ir`, `asm`, `c`, `ll` or `wat`, found `{kind}`"
+                            );
+                            usage(&self.program_name);
+                            return Err(());
+                        };
+
+                        self.emit = Some(emit);
+                    }
+                    _ if flag.starts_with("-target=") => {
+                        let name = &flag["-target=".len()..];
+                        let Some(target) = Target::parse(name) else {
+                            eprintln!(
+                                "ERROR: `--target` expects `x86_64`, `aarch64-linux`, `c`, `llvm` or `wasm`, found `{name}`"
+                            );
+                            usage(&self.program_name);
+                            return Err(());
+                        };
+
+                        self.target = Some(target);
+                    }
+                    _ if flag.starts_with("-stack-size=") => {
+                        let size = &flag["-stack-size=".len()..];
+                        let Some(slots) = size.parse().ok().filter(|&slots| slots > 0) else {
+                            eprintln!(
+                                "ERROR: `--stack-size` expects a positive number of slots, found `{size}`"
+                            );
+                            usage(&self.program_name);
+                            return Err(());
+                        };
+
+                        self.stack_slots = Some(slots);
+                    }
                     "-" => break,
                     _ => {
                         eprintln!("ERROR: unknown flag `{key}`");
@@ -92,13 +334,40 @@ impl CommandParser {
             }
         }
 
+        if self.target == Some(Target::Wasm) {
+            if self.mode == Some(Mode::Run { interp: false }) {
+                eprintln!("ERROR: `run` cannot execute `--target=wasm` output");
+                usage(&self.program_name);
+                return Err(());
+            }
+            self.emit.get_or_insert(Emit::Wat);
+        }
+        if let Some(emit) = self.emit
+            && !emit.is_written_by(*self.target.get_or_insert(emit.default_target()))
+        {
+            eprintln!("ERROR: `--emit` and `--target` disagree on the output");
+            usage(&self.program_name);
+            return Err(());
+        }
+
+        if self.link_libc && self.target.unwrap_or(Target::X86_64) != Target::X86_64 {
+            eprintln!("ERROR: `--link-libc` only applies to `--target=x86_64`");
+            usage(&self.program_name);
+            return Err(());
+        }
+        if !self.link_args.is_empty() && !self.link_libc {
+            eprintln!("ERROR: `-l` and `-L` need `--link-libc`");
+            usage(&self.program_name);
+            return Err(());
+        }
+
         if let Some(ref file) = self.file {
             let file = file.clone();
-            Ok(self.make_default(file))
+            Ok(Command::Compile(self.make_default(file)))
         } else {
             eprintln!("ERROR: no file given");
             usage(&self.program_name);
-            return Err(());
+            Err(())
         }
     }
 }
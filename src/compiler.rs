@@ -1,10 +1,14 @@
 use std::collections::HashMap;
 
 use crate::{
-    analyzer::{Def, Item, ItemKind, Type},
+    analyzer::{CompileError, Def, Item, ItemKind, SYSCALLS, Signature, Type},
     lexer::Span,
 };
 
+/// Number of 8-byte slots in the data stack unless `--stack-size` says
+/// otherwise.
+pub const DATA_STACK_SLOTS: usize = 1024;
+
 impl Type {
     fn size(&self) -> Option<usize> {
         match self {
@@ -29,28 +33,128 @@ pub enum Instruction<'src> {
     Mul,
     Div,
 
+    Eq,
+    Lt,
+
     Exit,
 
     Puts,
 
-    Dup { size: usize },
-    Swap { size_a: usize, size_b: usize },
-    Drop { size: usize },
-    Over { size_a: usize, size_b: usize },
+    /// Pushes how many command-line arguments there are, counting the
+    /// program's name.
+    Argc,
+    /// Replaces an index with that command-line argument, or the empty
+    /// string past the last one.
+    ArgvNth,
+    /// Replaces a name with the value of that environment variable and
+    /// whether it is set.
+    Getenv,
+    /// Pushes the next line of standard input without its newline, and
+    /// whether there was one.
+    ReadLine,
+    /// Pushes the rest of standard input.
+    ReadAll,
+
+    /// Makes system call number `args` slots below the top, with the slots
+    /// above it as arguments, and leaves its result.
+    Syscall {
+        args: usize,
+    },
+
+    Dup {
+        size: usize,
+    },
+    Swap {
+        size_a: usize,
+        size_b: usize,
+    },
+    Drop {
+        size: usize,
+    },
+    Over {
+        size_a: usize,
+        size_b: usize,
+    },
     Apply,
-    Branch { size: usize },
+    Branch {
+        size: usize,
+    },
 
     Call(Label<'src>),
+    /// Calls the C function `name` with `inputs` ints off the stack,
+    /// leaving the `outputs` ints it returns, at most one.
+    Ffi {
+        name: &'src str,
+        inputs: usize,
+        outputs: usize,
+    },
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Instruction<'_> {
+    /// How many slots the instruction takes off the top of the stack and
+    /// how many it leaves in their place. `Call` and `Apply` only count the
+    /// quotation they pop; the callee's effect is its own.
+    pub fn slots(&self) -> (usize, usize) {
+        match *self {
+            Instruction::PushInt(_) | Instruction::PushBool(_) | Instruction::PushQuote(_) => {
+                (0, 1)
+            }
+            Instruction::PushString(_) => (0, 2),
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::Eq
+            | Instruction::Lt => (2, 1),
+            Instruction::Exit => (1, 0),
+            Instruction::Puts => (2, 0),
+            Instruction::Argc => (0, 1),
+            Instruction::ArgvNth => (1, 2),
+            Instruction::Getenv => (2, 3),
+            Instruction::ReadLine => (0, 3),
+            Instruction::ReadAll => (0, 2),
+            Instruction::Syscall { args } => (args + 1, 1),
+            Instruction::Dup { size } => (size, 2 * size),
+            Instruction::Swap { size_a, size_b } => (size_a + size_b, size_a + size_b),
+            Instruction::Drop { size } => (size, 0),
+            Instruction::Over { size_a, size_b } => (size_a + size_b, size_a + 2 * size_b),
+            Instruction::Apply => (1, 0),
+            Instruction::Branch { size } => (2 * size + 1, size),
+            Instruction::Call(_) => (0, 0),
+            Instruction::Ffi {
+                inputs, outputs, ..
+            } => (inputs, outputs),
+        }
+    }
+}
+
+/// How far below and above the top of the stack the instructions up to and
+/// including the first `Call` or `Apply` reach, in slots. Backends check
+/// these bounds once per segment rather than on every push and pop.
+pub fn segment_bounds(instructions: &[(Span, Instruction)]) -> (usize, usize) {
+    let (mut depth, mut below, mut above) = (0isize, 0isize, 0isize);
+    for (_, instruction) in instructions {
+        let (taken, left) = instruction.slots();
+        depth -= taken as isize;
+        below = below.min(depth);
+        depth += left as isize;
+        above = above.max(depth);
+
+        if matches!(instruction, Instruction::Call(_) | Instruction::Apply) {
+            break;
+        }
+    }
+    (-below as usize, above as usize)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Label<'src> {
     id: usize,
     name: Option<&'src str>,
 }
 
 impl<'src> Label<'src> {
-    fn new(id: usize, name: Option<&'src str>) -> Self {
+    pub fn new(id: usize, name: Option<&'src str>) -> Self {
         Self { id, name }
     }
 
@@ -63,27 +167,95 @@ impl<'src> Label<'src> {
     }
 }
 
+/// The word `_start` calls into, and whether its result is the exit code.
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'src> {
+    label: Label<'src>,
+    returns_exit_code: bool,
+}
+
+impl<'src> Entry<'src> {
+    pub fn new(label: Label<'src>, returns_exit_code: bool) -> Self {
+        Self {
+            label,
+            returns_exit_code,
+        }
+    }
+
+    pub fn label(&self) -> Label<'src> {
+        self.label
+    }
+
+    pub fn returns_exit_code(&self) -> bool {
+        self.returns_exit_code
+    }
+}
+
+/// The number of slots a proc reads from and leaves on the data stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Effect {
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+impl Effect {
+    /// The slot effect of a signature, or `None` if a type on either side
+    /// has no fixed size, or the rows below them differ.
+    fn of(sig: Signature) -> Option<Self> {
+        let (mut inputs, mut outputs) = sig.parts();
+        if let (Some(Type::MultiVar(m)), Some(Type::MultiVar(n))) = (inputs.last(), outputs.first())
+        {
+            if m != n {
+                return None;
+            }
+            inputs.pop();
+            outputs.remove(0);
+        }
+
+        let slots = |types: &[Type]| types.iter().map(Type::size).sum::<Option<usize>>();
+        Some(Self {
+            inputs: slots(&inputs)?,
+            outputs: slots(&outputs)?,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Proc<'src> {
     label: Label<'src>,
+    effect: Option<Effect>,
     code: Vec<(Span, Instruction<'src>)>,
 }
 
 impl<'src> Proc<'src> {
-    pub fn new(label: Label<'src>) -> Self {
+    pub fn new(label: Label<'src>, effect: Option<Effect>) -> Self {
         Self {
             label,
+            effect,
             code: Vec::new(),
         }
     }
 
-    pub fn label(&self) -> Label {
+    pub fn label(&self) -> Label<'src> {
         self.label
     }
 
+    /// The effect the analyzer inferred, if it is known in slots.
+    pub fn effect(&self) -> Option<Effect> {
+        self.effect
+    }
+
     pub fn code(&self) -> &[(Span, Instruction<'src>)] {
         &self.code
     }
+
+    pub fn code_mut(&mut self) -> &mut Vec<(Span, Instruction<'src>)> {
+        &mut self.code
+    }
+
+    pub fn push(&mut self, span: Span, instruction: Instruction<'src>) {
+        self.code.push((span, instruction))
+    }
 }
 
 fn escape(s: &str) -> Box<str> {
@@ -108,10 +280,15 @@ fn escape(s: &str) -> Box<str> {
     escaped.into_boxed_str()
 }
 
+/// The entry point, procs and string literals of a compiled program.
+type Compiled<'src> = (Option<Entry<'src>>, Vec<Proc<'src>>, Vec<Box<str>>);
+
 pub struct Compiler<'src> {
     procs: Vec<Proc<'src>>,
     string_literals: Vec<Box<str>>,
     defs: HashMap<&'src str, Label<'src>>,
+    /// The C functions declared with `FFI:`, with their slot effects.
+    ffis: HashMap<&'src str, Effect>,
 }
 
 impl<'src> Compiler<'src> {
@@ -120,66 +297,77 @@ impl<'src> Compiler<'src> {
             procs: Vec::new(),
             string_literals: Vec::new(),
             defs: HashMap::new(),
+            ffis: HashMap::new(),
         }
     }
 
-    pub fn compile(defs: Vec<Def<'src>>) -> (Option<Label<'src>>, Vec<Proc<'src>>, Vec<Box<str>>) {
+    pub fn compile(defs: Vec<Def<'src>>) -> Result<Compiled<'src>, CompileError<'src>> {
         let mut compiler = Self::new();
+        let mut entry = None;
 
         for def in &defs {
             match def {
-                Def::WordDef { name, .. } => {
-                    let label = compiler.new_proc(Some(name));
+                Def::WordDef { name, ty, .. } => {
+                    let label = compiler.new_proc(Some(name), Effect::of(ty.clone()));
                     compiler.defs.insert(name, label);
+
+                    if *name == "main" {
+                        entry = Some(Entry::new(label, !ty.clone().parts().1.is_empty()));
+                    }
+                }
+                Def::Ffi { name, ty, .. } => {
+                    let effect = Effect::of(ty.clone()).expect("C functions only take ints");
+                    compiler.ffis.insert(name, effect);
                 }
             }
         }
 
         for def in defs {
-            compiler.compile_def(def);
+            compiler.compile_def(def)?;
         }
 
-        (
-            compiler.defs.get("main").copied(),
-            compiler.procs,
-            compiler.string_literals,
-        )
+        Ok((entry, compiler.procs, compiler.string_literals))
     }
 
-    fn compile_def(&mut self, def: Def<'src>) {
+    fn compile_def(&mut self, def: Def<'src>) -> Result<(), CompileError<'src>> {
         match def {
             Def::WordDef { name, body, .. } => {
                 let label = self.defs[name];
-                let mut body = body.into_iter();
-                while let Some(item) = body.next() {
-                    self.compile_item_to_block(item, label);
+                for item in body {
+                    self.compile_item_to_block(item, label)?;
                 }
             }
+            Def::Ffi { .. } => (),
         }
+        Ok(())
     }
 
     fn add_instruction(&mut self, label: Label<'src>, instruction: Instruction<'src>, span: Span) {
-        self.procs[label.id].code.push((span, instruction))
+        self.procs[label.id].push(span, instruction)
     }
 
-    fn new_proc(&mut self, name: Option<&'src str>) -> Label<'src> {
+    fn new_proc(&mut self, name: Option<&'src str>, effect: Option<Effect>) -> Label<'src> {
         let id = self.procs.len();
         let label = Label::new(id, name);
 
-        let proc = Proc::new(label);
+        let proc = Proc::new(label, effect);
         self.procs.push(proc);
 
         label
     }
 
-    fn compile_item_to_block(&mut self, item: Item<'src>, label: Label<'src>) {
+    fn compile_item_to_block(
+        &mut self,
+        item: Item<'src>,
+        label: Label<'src>,
+    ) -> Result<(), CompileError<'src>> {
         let (kind, span) = item.parts();
         match kind {
-            ItemKind::Quotation(_, items) => {
-                let quotation_proc = self.new_proc(None);
+            ItemKind::Quotation(sig, items) => {
+                let quotation_proc = self.new_proc(None, Effect::of(sig));
 
                 for quotation_word in items {
-                    self.compile_item_to_block(quotation_word, quotation_proc);
+                    self.compile_item_to_block(quotation_word, quotation_proc)?;
                 }
 
                 self.add_instruction(label, Instruction::PushQuote(quotation_proc), span);
@@ -204,64 +392,79 @@ impl<'src> Compiler<'src> {
             ItemKind::Word(_, "*") => self.add_instruction(label, Instruction::Mul, span),
             ItemKind::Word(_, "/") => self.add_instruction(label, Instruction::Div, span),
 
+            ItemKind::Word(_, "=") => self.add_instruction(label, Instruction::Eq, span),
+            ItemKind::Word(_, "<") => self.add_instruction(label, Instruction::Lt, span),
+
             ItemKind::Word(_, "exit") => self.add_instruction(label, Instruction::Exit, span),
 
             ItemKind::Word(_, "puts") => self.add_instruction(label, Instruction::Puts, span),
 
+            ItemKind::Word(_, "argc") => self.add_instruction(label, Instruction::Argc, span),
+            ItemKind::Word(_, "argv-nth") => {
+                self.add_instruction(label, Instruction::ArgvNth, span)
+            }
+            ItemKind::Word(_, "getenv") => self.add_instruction(label, Instruction::Getenv, span),
+
+            ItemKind::Word(_, "read-line") => {
+                self.add_instruction(label, Instruction::ReadLine, span)
+            }
+            ItemKind::Word(_, "read-all") => {
+                self.add_instruction(label, Instruction::ReadAll, span)
+            }
+
+            ItemKind::Word(_, s) if SYSCALLS.contains(&s) => {
+                let args = SYSCALLS.iter().position(|&name| name == s).unwrap();
+                self.add_instruction(label, Instruction::Syscall { args }, span);
+            }
+
             ItemKind::Word(sig, "dup") => {
-                let (inputs, _) = sig.parts();
-                self.add_instruction(
-                    label,
-                    Instruction::Dup {
-                        size: inputs[0].size().unwrap(),
-                    },
-                    span,
-                );
+                let sizes = input_sizes("dup", 1, sig, span)?;
+                self.add_instruction(label, Instruction::Dup { size: sizes[0] }, span);
             }
             ItemKind::Word(sig, "drop") => {
-                let (inputs, _) = sig.parts();
-                self.add_instruction(
-                    label,
-                    Instruction::Drop {
-                        size: inputs[0].size().unwrap(),
-                    },
-                    span,
-                );
+                let sizes = input_sizes("drop", 1, sig, span)?;
+                self.add_instruction(label, Instruction::Drop { size: sizes[0] }, span);
             }
             ItemKind::Word(sig, "swap") => {
-                let (inputs, _) = sig.parts();
+                let sizes = input_sizes("swap", 2, sig, span)?;
                 self.add_instruction(
                     label,
                     Instruction::Swap {
-                        size_a: inputs[0].size().unwrap(),
-                        size_b: inputs[1].size().unwrap(),
+                        size_a: sizes[0],
+                        size_b: sizes[1],
                     },
                     span,
                 );
             }
             ItemKind::Word(sig, "over") => {
-                let (inputs, _) = sig.parts();
+                let sizes = input_sizes("over", 2, sig, span)?;
                 self.add_instruction(
                     label,
                     Instruction::Over {
-                        size_a: inputs[0].size().unwrap(),
-                        size_b: inputs[1].size().unwrap(),
+                        size_a: sizes[0],
+                        size_b: sizes[1],
                     },
                     span,
                 );
             }
             ItemKind::Word(_, "apply") => self.add_instruction(label, Instruction::Apply, span),
             ItemKind::Word(sig, "?") => {
-                let (inputs, _) = sig.parts();
+                let sizes = input_sizes("?", 1, sig, span)?;
+                self.add_instruction(label, Instruction::Branch { size: sizes[0] }, span);
+            }
+
+            ItemKind::Word(_, s) if self.ffis.contains_key(s) => {
+                let Effect { inputs, outputs } = self.ffis[s];
                 self.add_instruction(
                     label,
-                    Instruction::Branch {
-                        size: inputs[0].size().unwrap(),
+                    Instruction::Ffi {
+                        name: s,
+                        inputs,
+                        outputs,
                     },
                     span,
                 );
             }
-
             ItemKind::Word(_, s) => {
                 let Some(&proc) = self.defs.get(s) else {
                     todo!("error: undefined word `{s}`");
@@ -270,5 +473,47 @@ impl<'src> Compiler<'src> {
                 self.add_instruction(label, Instruction::Call(proc), span);
             }
         }
+        Ok(())
+    }
+}
+
+/// The number of slots taken by each of the first `count` inputs of the
+/// builtin `word`. A word used on values of unknown type has no size to
+/// compile with.
+fn input_sizes<'src>(
+    word: &'src str,
+    count: usize,
+    sig: Signature,
+    span: Span,
+) -> Result<Vec<usize>, CompileError<'src>> {
+    let (inputs, _) = sig.clone().parts();
+    inputs[..count]
+        .iter()
+        .map(Type::size)
+        .collect::<Option<_>>()
+        .ok_or(CompileError::UnknownSize { word, span, sig })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analyzer::Analyzer, lexer::Lexer};
+
+    fn compile(source: &str) -> Result<Vec<Proc<'_>>, CompileError<'_>> {
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        Compiler::compile(defs).map(|(_, procs, _)| procs)
+    }
+
+    #[test]
+    fn words_on_values_of_unknown_type_are_reported() {
+        assert!(compile(": main 1 dup drop drop \"s\" [ 1 ] swap drop drop ;").is_ok());
+        assert!(matches!(
+            compile(": mydup dup ; : main ;"),
+            Err(CompileError::UnknownSize { word: "dup", .. })
+        ));
+        assert!(matches!(
+            compile(": main [ dup ] drop ;"),
+            Err(CompileError::UnknownSize { word: "dup", .. })
+        ));
     }
 }
@@ -0,0 +1,301 @@
+use std::{
+    env,
+    io::{self, IsTerminal, Write},
+    path::Path,
+};
+
+const TAB_WIDTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "auto" => Some(Self::Auto),
+            "always" => Some(Self::Always),
+            "never" => Some(Self::Never),
+            _ => None,
+        }
+    }
+
+    /// Decides whether to emit ANSI escapes to `stream`. `auto` honours
+    /// `NO_COLOR` and only colors terminals.
+    pub fn enabled_for(self, stream: &impl IsTerminal) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => {
+                env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && stream.is_terminal()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Note,
+    Hole,
+}
+
+impl Severity {
+    fn label(self) -> &'static str {
+        match self {
+            Severity::Error => "ERROR",
+            Severity::Note => "NOTE",
+            Severity::Hole => "HOLE",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Note => "\x1b[1;36m",
+            Severity::Hole => "\x1b[1;35m",
+        }
+    }
+}
+
+const GUTTER: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    severity: Severity,
+    message: String,
+    span: Option<(usize, usize)>,
+    notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            message: message.into(),
+            span: None,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::new(Severity::Error, message)
+    }
+
+    pub fn with_span(mut self, (start, end): (usize, usize)) -> Self {
+        self.span = Some((start, end));
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+}
+
+/// Returns the 1-based line and display column of the byte `index`, with
+/// tabs expanded to the next multiple of [`TAB_WIDTH`] and every `char`
+/// counted as one column.
+pub fn line_col(source: &str, index: usize) -> (usize, usize) {
+    let index = floor_char_boundary(source, index.min(source.len()));
+    let line_start = source[..index].rfind('\n').map_or(0, |i| i + 1);
+    let line = source[..line_start].matches('\n').count() + 1;
+    (line, display_width(&source[line_start..index]) + 1)
+}
+
+/// Returns the byte index of the 1-based `line` and display column `col`,
+/// the inverse of [`line_col`].
+pub fn offset_of(source: &str, line: usize, col: usize) -> Option<usize> {
+    let line_start = if line == 1 {
+        0
+    } else {
+        source.match_indices('\n').nth(line.checked_sub(2)?)?.0 + 1
+    };
+    let source_ln = source[line_start..].split('\n').next().unwrap_or_default();
+
+    let mut width = 0;
+    for (i, c) in source_ln.char_indices() {
+        if width + 1 >= col {
+            return (width + 1 == col).then_some(line_start + i);
+        }
+        width = display_width(&source_ln[..i + c.len_utf8()]);
+    }
+
+    (width + 1 == col).then_some(line_start + source_ln.len())
+}
+
+fn floor_char_boundary(s: &str, mut index: usize) -> usize {
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn display_width(s: &str) -> usize {
+    s.chars().fold(0, |col, c| match c {
+        '\t' => (col / TAB_WIDTH + 1) * TAB_WIDTH,
+        _ => col + 1,
+    })
+}
+
+fn expand_tabs(s: &str) -> String {
+    let mut expanded = String::new();
+    let mut col = 0;
+    for c in s.chars() {
+        if c == '\t' {
+            let next = (col / TAB_WIDTH + 1) * TAB_WIDTH;
+            expanded.extend(std::iter::repeat_n(' ', next - col));
+            col = next;
+        } else {
+            expanded.push(c);
+            col += 1;
+        }
+    }
+    expanded
+}
+
+struct Painter {
+    color: bool,
+}
+
+impl Painter {
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color {
+            format!("{style}{text}{RESET}")
+        } else {
+            text.into()
+        }
+    }
+}
+
+pub fn emit(
+    diagnostic: &Diagnostic,
+    path: &Path,
+    source: &str,
+    color: bool,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let painter = Painter { color };
+    let severity = diagnostic.severity;
+
+    writeln!(
+        out,
+        "{} {}",
+        painter.paint(severity.color(), &format!("{}:", severity.label())),
+        painter.paint(BOLD, &diagnostic.message)
+    )?;
+
+    if let Some((start, end)) = diagnostic.span {
+        let (ln, col) = line_col(source, start);
+        let line_start = source[..floor_char_boundary(source, start.min(source.len()))]
+            .rfind('\n')
+            .map_or(0, |i| i + 1);
+        let source_ln = source[line_start..].lines().next().unwrap_or_default();
+
+        // Spans that cross a newline are underlined up to the end of the line.
+        let end = end.clamp(start, line_start + source_ln.len());
+        let (_, end_col) = line_col(source, end);
+        let width = end_col.saturating_sub(col).max(1);
+
+        let gutter = " ".repeat(ln.to_string().len());
+        let bar = painter.paint(GUTTER, "|");
+
+        writeln!(
+            out,
+            "{gutter}{} {}:{ln}:{col}",
+            painter.paint(GUTTER, "-->"),
+            path.display()
+        )?;
+        writeln!(out, "{gutter} {bar}")?;
+        writeln!(
+            out,
+            "{} {bar} {}",
+            painter.paint(GUTTER, &ln.to_string()),
+            expand_tabs(source_ln)
+        )?;
+        writeln!(
+            out,
+            "{gutter} {bar} {}{}",
+            " ".repeat(col - 1),
+            painter.paint(severity.color(), &"^".repeat(width))
+        )?;
+    } else {
+        writeln!(out, " {} {}", painter.paint(GUTTER, "-->"), path.display())?;
+    }
+
+    for note in &diagnostic.notes {
+        writeln!(out)?;
+        for note_ln in note.lines() {
+            if note_ln.is_empty() {
+                writeln!(out)?;
+            } else {
+                writeln!(
+                    out,
+                    "{} {note_ln}",
+                    painter.paint(Severity::Note.color(), "NOTE:")
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(diagnostic: &Diagnostic, source: &str, color: bool) -> String {
+        let mut out = Vec::new();
+        emit(diagnostic, Path::new("test.zila"), source, color, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn columns_expand_tabs_and_count_chars() {
+        let source = "a\n\tfoo\n\u{e9}\u{e9} bar";
+        assert_eq!(line_col(source, 0), (1, 1));
+        assert_eq!(line_col(source, 3), (2, 5));
+        assert_eq!(line_col(source, source.find("bar").unwrap()), (3, 4));
+    }
+
+    #[test]
+    fn offset_of_inverts_line_col() {
+        let source = "a\n\tfoo\n\u{e9}\u{e9} bar";
+        for (i, _) in source.char_indices() {
+            let (line, col) = line_col(source, i);
+            assert_eq!(offset_of(source, line, col), Some(i));
+        }
+        assert_eq!(offset_of(source, 2, 2), None);
+        assert_eq!(offset_of(source, 4, 1), None);
+    }
+
+    #[test]
+    fn caret_aligns_under_tabbed_word() {
+        let source = ":\tmain\tfoo ;";
+        let start = source.find("foo").unwrap();
+        let diagnostic = Diagnostic::error("undefined word `foo`").with_span((start, start + 3));
+
+        assert_eq!(
+            render(&diagnostic, source, false),
+            "ERROR: undefined word `foo`
+ --> test.zila:1:13
+  |
+1 | :   main    foo ;
+  |             ^^^
+"
+        );
+    }
+
+    #[test]
+    fn color_wraps_severity() {
+        let diagnostic = Diagnostic::error("oops");
+        let rendered = render(&diagnostic, "", true);
+        assert!(rendered.starts_with("\x1b[1;31mERROR:\x1b[0m"));
+    }
+}
@@ -0,0 +1,284 @@
+//! Builds the DWARF sections that let debuggers map the machine code of an
+//! executable back to its Zila source: a compile unit covering `.text`, its
+//! line table, and the call frame information needed to unwind the stack.
+//!
+//! Only the parts of DWARF 4 that debuggers need for that are written.
+
+use std::path::Path;
+
+const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+const DW_CHILDREN_NO: u8 = 0;
+const DW_AT_NAME: u8 = 0x03;
+const DW_AT_STMT_LIST: u8 = 0x10;
+const DW_AT_LOW_PC: u8 = 0x11;
+const DW_AT_HIGH_PC: u8 = 0x12;
+const DW_AT_COMP_DIR: u8 = 0x1b;
+const DW_AT_PRODUCER: u8 = 0x25;
+const DW_FORM_ADDR: u8 = 0x01;
+const DW_FORM_DATA8: u8 = 0x07;
+const DW_FORM_STRING: u8 = 0x08;
+const DW_FORM_SEC_OFFSET: u8 = 0x17;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_COLUMN: u8 = 5;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+/// The number of operands of each standard opcode, from `DW_LNS_copy` on.
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_UNDEFINED: u8 = 0x07;
+const DW_CFA_DEF_CFA: u8 = 0x0c;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+const DW_CFA_OFFSET: u8 = 0x80;
+
+/// The DWARF numbers of `rsp` and of the return address.
+const RSP: u8 = 7;
+const RIP: u8 = 16;
+
+/// The code from `offset` in `.text` on was compiled from `line` and `col`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Line {
+    pub offset: u64,
+    pub line: u64,
+    pub col: u64,
+}
+
+/// A function in `.text`, from `start` up to `end`. It is entered with the
+/// return address on top of the machine stack, except for the entry point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub start: u64,
+    pub end: u64,
+    pub is_entry: bool,
+    /// Where the function pushes or pops: the offset of the next
+    /// instruction, and how far the return address then is above `rsp`.
+    pub frame_sizes: Vec<(u64, u64)>,
+}
+
+/// Builds the `.debug_*` sections for the code in `.text`, which is loaded
+/// at `text_addr`, compiled from `file`.
+pub fn sections(
+    file: &Path,
+    text_addr: u64,
+    text_size: u64,
+    lines: &[Line],
+    functions: &[Function],
+) -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        (".debug_abbrev", debug_abbrev()),
+        (".debug_info", debug_info(file, text_addr, text_size)),
+        (".debug_line", debug_line(file, text_addr, text_size, lines)),
+        (".debug_frame", debug_frame(text_addr, functions)),
+    ]
+}
+
+fn debug_abbrev() -> Vec<u8> {
+    let mut abbrev = vec![1, DW_TAG_COMPILE_UNIT, DW_CHILDREN_NO];
+    for (attribute, form) in [
+        (DW_AT_NAME, DW_FORM_STRING),
+        (DW_AT_COMP_DIR, DW_FORM_STRING),
+        (DW_AT_PRODUCER, DW_FORM_STRING),
+        (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+        (DW_AT_LOW_PC, DW_FORM_ADDR),
+        (DW_AT_HIGH_PC, DW_FORM_DATA8),
+    ] {
+        abbrev.extend([attribute, form]);
+    }
+    abbrev.extend([0, 0, 0]);
+    abbrev
+}
+
+fn debug_info(file: &Path, text_addr: u64, text_size: u64) -> Vec<u8> {
+    let mut unit = Vec::new();
+    unit.extend(4u16.to_le_bytes());
+    unit.extend(0u32.to_le_bytes()); // The abbreviations start `.debug_abbrev`.
+    unit.push(8);
+
+    unit.push(1);
+    put_str(&mut unit, &file.display().to_string());
+    let dir = file.parent().unwrap_or(Path::new("."));
+    put_str(&mut unit, &dir.display().to_string());
+    put_str(&mut unit, concat!("zila ", env!("CARGO_PKG_VERSION")));
+    unit.extend(0u32.to_le_bytes()); // The line table starts `.debug_line`.
+    unit.extend(text_addr.to_le_bytes());
+    unit.extend(text_size.to_le_bytes());
+
+    with_length(unit)
+}
+
+fn debug_line(file: &Path, text_addr: u64, text_size: u64, lines: &[Line]) -> Vec<u8> {
+    let mut header = vec![
+        1,          // minimum_instruction_length
+        1,          // maximum_operations_per_instruction
+        1,          // default_is_stmt
+        -5i8 as u8, // line_base
+        14,         // line_range
+        STANDARD_OPCODE_LENGTHS.len() as u8 + 1,
+    ];
+    header.extend(STANDARD_OPCODE_LENGTHS);
+    header.push(0); // No include directories.
+    put_str(&mut header, &file.display().to_string());
+    header.extend([0, 0, 0, 0]); // Directory, modification time, length.
+
+    // Every row spells out its changes with standard opcodes rather than
+    // packing them into special ones.
+    let mut program = vec![0, 9, DW_LNE_SET_ADDRESS];
+    program.extend(text_addr.to_le_bytes());
+    let (mut offset, mut line, mut col) = (0, 1, 0);
+    for row in lines {
+        program.push(DW_LNS_ADVANCE_PC);
+        put_uleb(&mut program, row.offset - offset);
+        program.push(DW_LNS_ADVANCE_LINE);
+        put_sleb(&mut program, row.line as i64 - line as i64);
+        if row.col != col {
+            program.push(DW_LNS_SET_COLUMN);
+            put_uleb(&mut program, row.col);
+        }
+        program.push(DW_LNS_COPY);
+        (offset, line, col) = (row.offset, row.line, row.col);
+    }
+    program.push(DW_LNS_ADVANCE_PC);
+    put_uleb(&mut program, text_size - offset);
+    program.extend([0, 1, DW_LNE_END_SEQUENCE]);
+
+    let mut unit = Vec::new();
+    unit.extend(4u16.to_le_bytes());
+    unit.extend((header.len() as u32).to_le_bytes());
+    unit.extend(header);
+    unit.extend(program);
+    with_length(unit)
+}
+
+fn debug_frame(text_addr: u64, functions: &[Function]) -> Vec<u8> {
+    // On entry the return address is on top of the stack, so the caller's
+    // frame, the CFA, starts right above it.
+    let mut cie = Vec::new();
+    cie.extend(u32::MAX.to_le_bytes());
+    cie.push(1); // version
+    cie.push(0); // No augmentation.
+    put_uleb(&mut cie, 1); // code_alignment_factor
+    put_sleb(&mut cie, -8); // data_alignment_factor
+    cie.push(RIP);
+    cie.extend([DW_CFA_DEF_CFA, RSP, 8]);
+    cie.extend([DW_CFA_OFFSET | RIP, 1]);
+    let mut frame = with_length(padded(cie));
+
+    for function in functions {
+        let mut fde = Vec::new();
+        fde.extend(0u32.to_le_bytes()); // The CIE starts the section.
+        fde.extend((text_addr + function.start).to_le_bytes());
+        fde.extend((function.end - function.start).to_le_bytes());
+        if function.is_entry {
+            // There is nothing to return to, which ends a backtrace.
+            fde.extend([DW_CFA_UNDEFINED, RIP]);
+        }
+        let mut offset = function.start;
+        for &(at, size) in &function.frame_sizes {
+            fde.push(DW_CFA_ADVANCE_LOC4);
+            fde.extend(((at - offset) as u32).to_le_bytes());
+            fde.push(DW_CFA_DEF_CFA_OFFSET);
+            put_uleb(&mut fde, size);
+            offset = at;
+        }
+        frame.extend(with_length(padded(fde)));
+    }
+
+    frame
+}
+
+/// Pads call frame instructions with `DW_CFA_nop`s so that the entry,
+/// with its length, is a multiple of the address size.
+fn padded(mut entry: Vec<u8>) -> Vec<u8> {
+    entry.resize((entry.len() + 4).next_multiple_of(8) - 4, 0);
+    entry
+}
+
+/// Prefixes a unit or entry with its 32-bit length.
+fn with_length(unit: Vec<u8>) -> Vec<u8> {
+    let mut bytes = (unit.len() as u32).to_le_bytes().to_vec();
+    bytes.extend(unit);
+    bytes
+}
+
+fn put_str(bytes: &mut Vec<u8>, s: &str) {
+    bytes.extend(s.as_bytes());
+    bytes.push(0);
+}
+
+fn put_uleb(bytes: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = n as u8 & 0x7f;
+        n >>= 7;
+        if n == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn put_sleb(bytes: &mut Vec<u8>, mut n: i64) {
+    loop {
+        let byte = n as u8 & 0x7f;
+        n >>= 7;
+        if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leb128_matches_the_dwarf_examples() {
+        let uleb = |n| {
+            let mut bytes = Vec::new();
+            put_uleb(&mut bytes, n);
+            bytes
+        };
+        let sleb = |n| {
+            let mut bytes = Vec::new();
+            put_sleb(&mut bytes, n);
+            bytes
+        };
+
+        assert_eq!(uleb(2), [2]);
+        assert_eq!(uleb(127), [127]);
+        assert_eq!(uleb(128), [0x80, 1]);
+        assert_eq!(uleb(12857), [0xb9, 0x64]);
+        assert_eq!(sleb(2), [2]);
+        assert_eq!(sleb(-2), [0x7e]);
+        assert_eq!(sleb(127), [0xff, 0]);
+        assert_eq!(sleb(-128), [0x80, 0x7f]);
+    }
+
+    #[test]
+    fn frame_entries_are_aligned() {
+        let functions = [Function {
+            start: 0,
+            end: 16,
+            is_entry: false,
+            frame_sizes: vec![(4, 16), (9, 8)],
+        }];
+        let frame = debug_frame(0x40_1000, &functions);
+
+        let cie_len = u32::from_le_bytes(frame[..4].try_into().unwrap()) as usize;
+        assert_eq!((cie_len + 4) % 8, 0);
+        let fde = &frame[cie_len + 4..];
+        let fde_len = u32::from_le_bytes(fde[..4].try_into().unwrap()) as usize;
+        assert_eq!(fde.len(), fde_len + 4);
+        assert_eq!(fde.len() % 8, 0);
+        assert_eq!(
+            u64::from_le_bytes(fde[8..16].try_into().unwrap()),
+            0x40_1000
+        );
+        assert_eq!(fde[24..36], [4, 4, 0, 0, 0, 0x0e, 16, 4, 5, 0, 0, 0]);
+    }
+}
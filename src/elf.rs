@@ -0,0 +1,700 @@
+//! Writes static ELF64 executables for x86_64 Linux, and relocatable
+//! objects for linking with libc.
+//!
+//! An executable has one loadable segment per section: `.text` is readable and
+//! executable, `.rodata` read-only and `.bss` writable and zero-filled. A
+//! symbol table and any DWARF sections are included for debuggers and
+//! `objdump`.
+
+use std::io::{self, Write};
+
+/// Where the first byte of the file would be mapped.
+const BASE_ADDR: u64 = 0x40_0000;
+const PAGE_SIZE: u64 = 0x1000;
+
+const EHDR_SIZE: u64 = 64;
+const PHDR_SIZE: u64 = 56;
+const SHDR_SIZE: u64 = 64;
+const SYM_SIZE: u64 = 24;
+const RELA_SIZE: u64 = 24;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+const SHN_UNDEF: u16 = 0;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Section {
+    Text,
+    Rodata,
+    Bss,
+}
+
+impl Section {
+    /// The index of the section's header.
+    fn index(self) -> u16 {
+        match self {
+            Section::Text => 1,
+            Section::Rodata => 2,
+            Section::Bss => 3,
+        }
+    }
+}
+
+/// The addresses the sections are loaded at. `.text` and `.rodata` are
+/// stored at their address minus `BASE_ADDR` in the file.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    text: u64,
+    rodata: u64,
+    bss: u64,
+}
+
+impl Layout {
+    pub fn new(text_size: usize, rodata_size: usize) -> Self {
+        let text = BASE_ADDR + PAGE_SIZE;
+        let rodata = align(text + text_size as u64, PAGE_SIZE);
+        let bss = align(rodata + rodata_size as u64, PAGE_SIZE);
+        Self { text, rodata, bss }
+    }
+
+    pub fn addr(&self, section: Section) -> u64 {
+        match section {
+            Section::Text => self.text,
+            Section::Rodata => self.rodata,
+            Section::Bss => self.bss,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Section,
+    /// The offset from the start of the section.
+    pub offset: u64,
+    pub size: u64,
+    pub global: bool,
+}
+
+#[derive(Debug)]
+pub struct Executable {
+    pub layout: Layout,
+    pub entry: u64,
+    pub text: Vec<u8>,
+    pub rodata: Vec<u8>,
+    pub bss_size: usize,
+    pub symbols: Vec<Symbol>,
+    /// Sections that aren't loaded, such as `.debug_line`, by name.
+    pub debug_sections: Vec<(&'static str, Vec<u8>)>,
+}
+
+impl Executable {
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&self.to_bytes())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let text_offset = self.layout.text - BASE_ADDR;
+        let rodata_offset = self.layout.rodata - BASE_ADDR;
+        let rodata_end = rodata_offset + self.rodata.len() as u64;
+
+        let (symtab, strtab, first_global) = symbol_table(
+            &self.symbols,
+            |section| self.layout.addr(section),
+            false,
+            &[],
+        );
+
+        let mut shstrtab = vec![0];
+        let mut name = |name: &str| {
+            let offset = shstrtab.len() as u32;
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+            offset
+        };
+        let names = [
+            name(".text"),
+            name(".rodata"),
+            name(".bss"),
+            name(".symtab"),
+            name(".strtab"),
+            name(".shstrtab"),
+        ];
+        let debug_names = self
+            .debug_sections
+            .iter()
+            .map(|(section, _)| name(section))
+            .collect::<Vec<_>>();
+
+        let symtab_offset = align(rodata_end, 8);
+        let strtab_offset = symtab_offset + symtab.len() as u64;
+        let shstrtab_offset = strtab_offset + strtab.len() as u64;
+        let mut debug_offsets = Vec::new();
+        let mut end = shstrtab_offset + shstrtab.len() as u64;
+        for (_, bytes) in &self.debug_sections {
+            debug_offsets.push(end);
+            end += bytes.len() as u64;
+        }
+        let shdrs_offset = align(end, 8);
+
+        let segments = [
+            (
+                PF_R | PF_X,
+                text_offset,
+                self.layout.text,
+                self.text.len(),
+                self.text.len(),
+            ),
+            (
+                PF_R,
+                rodata_offset,
+                self.layout.rodata,
+                self.rodata.len(),
+                self.rodata.len(),
+            ),
+            (
+                PF_R | PF_W,
+                self.layout.bss - BASE_ADDR,
+                self.layout.bss,
+                0,
+                self.bss_size,
+            ),
+        ];
+        let segments = segments
+            .into_iter()
+            .filter(|&(.., mem_size)| mem_size > 0)
+            .collect::<Vec<_>>();
+
+        let mut elf = Vec::new();
+        put_header(
+            &mut elf,
+            ET_EXEC,
+            self.entry,
+            segments.len() as u16,
+            shdrs_offset,
+            (names.len() + debug_names.len()) as u16 + 1,
+            names.len() as u16,
+        );
+
+        for (flags, offset, addr, file_size, mem_size) in segments {
+            elf.put_u32(PT_LOAD);
+            elf.put_u32(flags);
+            elf.put_u64(offset);
+            elf.put_u64(addr);
+            elf.put_u64(addr);
+            elf.put_u64(file_size as u64);
+            elf.put_u64(mem_size as u64);
+            elf.put_u64(PAGE_SIZE);
+        }
+
+        elf.resize(text_offset as usize, 0);
+        elf.extend_from_slice(&self.text);
+        elf.resize(rodata_offset as usize, 0);
+        elf.extend_from_slice(&self.rodata);
+        elf.resize(symtab_offset as usize, 0);
+        elf.extend_from_slice(&symtab);
+        elf.extend_from_slice(&strtab);
+        elf.extend_from_slice(&shstrtab);
+        for (_, bytes) in &self.debug_sections {
+            elf.extend_from_slice(bytes);
+        }
+        elf.resize(shdrs_offset as usize, 0);
+
+        let headers = [
+            SectionHeader::default(),
+            SectionHeader {
+                name: names[0],
+                kind: SHT_PROGBITS,
+                flags: SHF_ALLOC | SHF_EXECINSTR,
+                addr: self.layout.text,
+                offset: text_offset,
+                size: self.text.len() as u64,
+                align: 16,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[1],
+                kind: SHT_PROGBITS,
+                flags: SHF_ALLOC,
+                addr: self.layout.rodata,
+                offset: rodata_offset,
+                size: self.rodata.len() as u64,
+                align: 1,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[2],
+                kind: SHT_NOBITS,
+                flags: SHF_ALLOC | SHF_WRITE,
+                addr: self.layout.bss,
+                offset: rodata_end,
+                size: self.bss_size as u64,
+                align: 8,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[3],
+                kind: SHT_SYMTAB,
+                offset: symtab_offset,
+                size: symtab.len() as u64,
+                link: 5,
+                info: first_global as u32,
+                align: 8,
+                entry_size: SYM_SIZE,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[4],
+                kind: SHT_STRTAB,
+                offset: strtab_offset,
+                size: strtab.len() as u64,
+                align: 1,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[5],
+                kind: SHT_STRTAB,
+                offset: shstrtab_offset,
+                size: shstrtab.len() as u64,
+                align: 1,
+                ..Default::default()
+            },
+        ];
+        let debug_headers = self
+            .debug_sections
+            .iter()
+            .enumerate()
+            .map(|(i, (_, bytes))| SectionHeader {
+                name: debug_names[i],
+                kind: SHT_PROGBITS,
+                offset: debug_offsets[i],
+                size: bytes.len() as u64,
+                align: 1,
+                ..Default::default()
+            });
+        for header in headers.into_iter().chain(debug_headers) {
+            header.write(&mut elf);
+        }
+
+        elf
+    }
+}
+
+/// A relocatable object for the system linker, laid out like an
+/// `Executable` but with its sections at address zero and the references
+/// it can't resolve itself left as relocations.
+#[derive(Debug)]
+pub struct Object {
+    pub text: Vec<u8>,
+    pub rodata: Vec<u8>,
+    pub bss_size: usize,
+    pub symbols: Vec<Symbol>,
+    /// Functions that other objects or libraries define.
+    pub externs: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// A 32-bit displacement in `.text`, relative to its own end, that the
+/// linker fills in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relocation {
+    /// To `target` bytes into `section`.
+    Section {
+        offset: u64,
+        section: Section,
+        target: u64,
+    },
+    /// To the function `Object::externs[index]`, through the PLT if it is
+    /// in a shared library.
+    Extern { offset: u64, index: usize },
+}
+
+impl Object {
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&self.to_bytes())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let (symtab, strtab, first_global) =
+            symbol_table(&self.symbols, |_| 0, true, &self.externs);
+        let first_extern = 1 + SECTION_SYMBOLS + self.symbols.len();
+
+        let mut rela = Vec::new();
+        for relocation in &self.relocations {
+            let (offset, symbol, kind, addend) = match *relocation {
+                Relocation::Section {
+                    offset,
+                    section,
+                    target,
+                } => (
+                    offset,
+                    section.index() as usize,
+                    R_X86_64_PC32,
+                    target as i64,
+                ),
+                Relocation::Extern { offset, index } => {
+                    (offset, first_extern + index, R_X86_64_PLT32, 0)
+                }
+            };
+            rela.put_u64(offset);
+            rela.put_u64((symbol as u64) << 32 | kind as u64);
+            // The displacement is taken from the end of its 4 bytes.
+            rela.put_u64((addend - 4) as u64);
+        }
+
+        let mut shstrtab = vec![0];
+        let mut name = |name: &str| {
+            let offset = shstrtab.len() as u32;
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+            offset
+        };
+        let names = [
+            name(".text"),
+            name(".rodata"),
+            name(".bss"),
+            name(".symtab"),
+            name(".strtab"),
+            name(".shstrtab"),
+            name(".rela.text"),
+            name(".note.GNU-stack"),
+        ];
+
+        let text_offset = align(EHDR_SIZE, 16);
+        let rodata_offset = text_offset + self.text.len() as u64;
+        let symtab_offset = align(rodata_offset + self.rodata.len() as u64, 8);
+        let strtab_offset = symtab_offset + symtab.len() as u64;
+        let shstrtab_offset = strtab_offset + strtab.len() as u64;
+        let rela_offset = align(shstrtab_offset + shstrtab.len() as u64, 8);
+        let end = rela_offset + rela.len() as u64;
+        let shdrs_offset = align(end, 8);
+
+        let mut elf = Vec::new();
+        put_header(
+            &mut elf,
+            ET_REL,
+            0,
+            0,
+            shdrs_offset,
+            names.len() as u16 + 1,
+            6,
+        );
+        elf.resize(text_offset as usize, 0);
+        elf.extend_from_slice(&self.text);
+        elf.extend_from_slice(&self.rodata);
+        elf.resize(symtab_offset as usize, 0);
+        elf.extend_from_slice(&symtab);
+        elf.extend_from_slice(&strtab);
+        elf.extend_from_slice(&shstrtab);
+        elf.resize(rela_offset as usize, 0);
+        elf.extend_from_slice(&rela);
+        elf.resize(shdrs_offset as usize, 0);
+
+        let headers = [
+            SectionHeader::default(),
+            SectionHeader {
+                name: names[0],
+                kind: SHT_PROGBITS,
+                flags: SHF_ALLOC | SHF_EXECINSTR,
+                offset: text_offset,
+                size: self.text.len() as u64,
+                align: 16,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[1],
+                kind: SHT_PROGBITS,
+                flags: SHF_ALLOC,
+                offset: rodata_offset,
+                size: self.rodata.len() as u64,
+                align: 1,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[2],
+                kind: SHT_NOBITS,
+                flags: SHF_ALLOC | SHF_WRITE,
+                offset: symtab_offset,
+                size: self.bss_size as u64,
+                align: 8,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[3],
+                kind: SHT_SYMTAB,
+                offset: symtab_offset,
+                size: symtab.len() as u64,
+                link: 5,
+                info: first_global as u32,
+                align: 8,
+                entry_size: SYM_SIZE,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[4],
+                kind: SHT_STRTAB,
+                offset: strtab_offset,
+                size: strtab.len() as u64,
+                align: 1,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[5],
+                kind: SHT_STRTAB,
+                offset: shstrtab_offset,
+                size: shstrtab.len() as u64,
+                align: 1,
+                ..Default::default()
+            },
+            SectionHeader {
+                name: names[6],
+                kind: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                offset: rela_offset,
+                size: rela.len() as u64,
+                link: 4,
+                info: Section::Text.index() as u32,
+                align: 8,
+                entry_size: RELA_SIZE,
+                ..Default::default()
+            },
+            // Without it, linkers assume the code needs an executable stack.
+            SectionHeader {
+                name: names[7],
+                kind: SHT_PROGBITS,
+                offset: end,
+                align: 1,
+                ..Default::default()
+            },
+        ];
+        for header in headers {
+            header.write(&mut elf);
+        }
+
+        elf
+    }
+}
+
+/// The number of section symbols `symbol_table` adds, one for each
+/// `Section`.
+const SECTION_SYMBOLS: usize = 3;
+
+/// Builds `.symtab` and `.strtab`, returning them with the index of the
+/// first global symbol. The null symbol comes first, then a symbol for each
+/// section if `section_symbols` is set, the local `symbols`, the global ones,
+/// and last the undefined `externs`. Each symbol's value is its offset plus
+/// `addr` of its section.
+fn symbol_table(
+    symbols: &[Symbol],
+    addr: impl Fn(Section) -> u64,
+    section_symbols: bool,
+    externs: &[String],
+) -> (Vec<u8>, Vec<u8>, usize) {
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; SYM_SIZE as usize];
+    if section_symbols {
+        for section in [Section::Text, Section::Rodata, Section::Bss] {
+            symtab.put_u32(0);
+            symtab.push(STB_LOCAL << 4 | STT_SECTION);
+            symtab.push(0);
+            symtab.put_u16(section.index());
+            symtab.put_u64(addr(section));
+            symtab.put_u64(0);
+        }
+    }
+
+    // Locals must come before globals.
+    let mut symbols = symbols.iter().collect::<Vec<_>>();
+    symbols.sort_by_key(|symbol| symbol.global);
+    let first_global =
+        symtab.len() / SYM_SIZE as usize + symbols.iter().filter(|symbol| !symbol.global).count();
+
+    for symbol in &symbols {
+        symtab.put_u32(strtab.len() as u32);
+        strtab.extend_from_slice(symbol.name.as_bytes());
+        strtab.push(0);
+
+        let bind = if symbol.global { STB_GLOBAL } else { STB_LOCAL };
+        let kind = match symbol.section {
+            Section::Text => STT_FUNC,
+            Section::Rodata | Section::Bss => STT_OBJECT,
+        };
+        symtab.push(bind << 4 | kind);
+        symtab.push(0);
+        symtab.put_u16(symbol.section.index());
+        symtab.put_u64(addr(symbol.section) + symbol.offset);
+        symtab.put_u64(symbol.size);
+    }
+
+    for name in externs {
+        symtab.put_u32(strtab.len() as u32);
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+
+        symtab.push(STB_GLOBAL << 4 | STT_NOTYPE);
+        symtab.push(0);
+        symtab.put_u16(SHN_UNDEF);
+        symtab.put_u64(0);
+        symtab.put_u64(0);
+    }
+
+    (symtab, strtab, first_global)
+}
+
+/// Writes the ELF header of a file with `segments` program headers right
+/// after it and `sections` section headers at `shdrs_offset`.
+fn put_header(
+    elf: &mut Vec<u8>,
+    kind: u16,
+    entry: u64,
+    segments: u16,
+    shdrs_offset: u64,
+    sections: u16,
+    shstrndx: u16,
+) {
+    elf.extend_from_slice(b"\x7fELF");
+    elf.extend_from_slice(&[2, 1, 1, 0]); // 64-bit, little-endian, version 1, System V
+    elf.resize(16, 0);
+    elf.put_u16(kind);
+    elf.put_u16(62); // EM_X86_64
+    elf.put_u32(1);
+    elf.put_u64(entry);
+    elf.put_u64(if segments > 0 { EHDR_SIZE } else { 0 });
+    elf.put_u64(shdrs_offset);
+    elf.put_u32(0);
+    elf.put_u16(EHDR_SIZE as u16);
+    elf.put_u16(if segments > 0 { PHDR_SIZE as u16 } else { 0 });
+    elf.put_u16(segments);
+    elf.put_u16(SHDR_SIZE as u16);
+    elf.put_u16(sections);
+    elf.put_u16(shstrndx);
+}
+
+#[derive(Default)]
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+impl SectionHeader {
+    fn write(&self, elf: &mut Vec<u8>) {
+        elf.put_u32(self.name);
+        elf.put_u32(self.kind);
+        elf.put_u64(self.flags);
+        elf.put_u64(self.addr);
+        elf.put_u64(self.offset);
+        elf.put_u64(self.size);
+        elf.put_u32(self.link);
+        elf.put_u32(self.info);
+        elf.put_u64(self.align);
+        elf.put_u64(self.entry_size);
+    }
+}
+
+fn align(n: u64, to: u64) -> u64 {
+    n.next_multiple_of(to)
+}
+
+/// Little-endian writes, as every field in the file is.
+trait PutLe {
+    fn put_u16(&mut self, n: u16);
+    fn put_u32(&mut self, n: u32);
+    fn put_u64(&mut self, n: u64);
+}
+
+impl PutLe for Vec<u8> {
+    fn put_u16(&mut self, n: u16) {
+        self.extend_from_slice(&n.to_le_bytes())
+    }
+
+    fn put_u32(&mut self, n: u32) {
+        self.extend_from_slice(&n.to_le_bytes())
+    }
+
+    fn put_u64(&mut self, n: u64) {
+        self.extend_from_slice(&n.to_le_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(elf: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(elf[at..at + 2].try_into().unwrap())
+    }
+
+    fn u64_at(elf: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(elf[at..at + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn sections_are_loaded_where_the_layout_says() {
+        let layout = Layout::new(5, 3);
+        assert_eq!(layout.addr(Section::Text), 0x40_1000);
+        assert_eq!(layout.addr(Section::Rodata), 0x40_2000);
+        assert_eq!(layout.addr(Section::Bss), 0x40_3000);
+
+        let executable = Executable {
+            layout,
+            entry: 0x40_1000,
+            text: vec![0x90; 5],
+            rodata: b"abc".to_vec(),
+            bss_size: 64,
+            symbols: vec![Symbol {
+                name: "_start".into(),
+                section: Section::Text,
+                offset: 0,
+                size: 0,
+                global: true,
+            }],
+            debug_sections: Vec::new(),
+        };
+        let elf = executable.to_bytes();
+
+        assert_eq!(&elf[..4], b"\x7fELF");
+        assert_eq!(u64_at(&elf, 24), 0x40_1000);
+        assert_eq!(u16_at(&elf, 56), 3);
+        assert_eq!(&elf[0x1000..0x1005], [0x90; 5]);
+        assert_eq!(&elf[0x2000..0x2003], b"abc");
+
+        // The `.bss` segment takes no room in the file.
+        let bss = EHDR_SIZE as usize + 2 * PHDR_SIZE as usize;
+        assert_eq!(u64_at(&elf, bss + 16), 0x40_3000);
+        assert_eq!(u64_at(&elf, bss + 32), 0);
+        assert_eq!(u64_at(&elf, bss + 40), 64);
+    }
+}
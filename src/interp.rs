@@ -0,0 +1,510 @@
+//! A reference interpreter for the compiler IR.
+//!
+//! The data stack is modelled as 8-byte slots exactly like `x86_64gen` lays
+//! it out: strings are a pointer slot below a length slot, booleans are all
+//! ones or all zeros, and quotations are the id of their proc. Pointers index
+//! into a flat byte memory that holds the string literals, the command-line
+//! arguments, the environment and everything read from the input.
+//!
+//! Hand-written IR is never type-checked, so every stack access and pointer
+//! is checked and reported as a [`RuntimeError`] rather than trusted.
+
+use std::{
+    env, fmt,
+    io::{self, BufRead, Write},
+    os::unix::ffi::OsStrExt,
+};
+
+use crate::compiler::{Entry, Instruction, Proc};
+
+#[derive(Debug)]
+pub enum RuntimeError {
+    DataStackOverflow,
+    DataStackUnderflow,
+    InvalidString {
+        ptr: u64,
+        len: u64,
+    },
+    InvalidQuotation(u64),
+    DivisionByZero,
+    /// The interpreter can't call into C.
+    ForeignCall(String),
+    /// Nor make system calls.
+    Syscall,
+    Io(io::Error),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::DataStackOverflow => write!(f, "data stack overflow"),
+            RuntimeError::DataStackUnderflow => write!(f, "data stack underflow"),
+            RuntimeError::InvalidString { ptr, len } => {
+                write!(f, "invalid string of length {len} at {ptr}")
+            }
+            RuntimeError::InvalidQuotation(q) => write!(f, "invalid quotation {q}"),
+            RuntimeError::DivisionByZero => write!(f, "division by zero"),
+            RuntimeError::ForeignCall(name) => {
+                write!(f, "cannot call C function `{name}` in the interpreter")
+            }
+            RuntimeError::Syscall => write!(f, "cannot make system calls in the interpreter"),
+            RuntimeError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<io::Error> for RuntimeError {
+    fn from(e: io::Error) -> Self {
+        RuntimeError::Io(e)
+    }
+}
+
+struct Frame {
+    proc: usize,
+    pc: usize,
+}
+
+pub struct Interpreter<'a, 'src, R: BufRead, W: Write> {
+    procs: &'a [Proc<'src>],
+    memory: Vec<u8>,
+    string_literals: Vec<(u64, u64)>,
+    args: Vec<(u64, u64)>,
+    /// The `NAME=value` strings of the environment.
+    env: Vec<(u64, u64)>,
+    stack: Vec<u64>,
+    stack_slots: usize,
+    input: R,
+    out: W,
+}
+
+impl<'a, 'src, R: BufRead, W: Write> Interpreter<'a, 'src, R, W> {
+    pub fn new(
+        procs: &'a [Proc<'src>],
+        string_literals: &[Box<str>],
+        args: &[String],
+        stack_slots: usize,
+        input: R,
+        out: W,
+    ) -> Self {
+        let mut memory = Vec::new();
+        let mut store = |bytes: &[u8]| {
+            let ptr = memory.len() as u64;
+            memory.extend_from_slice(bytes);
+            (ptr, bytes.len() as u64)
+        };
+        let string_literals = string_literals
+            .iter()
+            .map(|s| store(s.as_bytes()))
+            .collect();
+        let args = args.iter().map(|arg| store(arg.as_bytes())).collect();
+        let env = env::vars_os()
+            .map(|(name, value)| store(&[name.as_bytes(), b"=", value.as_bytes()].concat()))
+            .collect();
+
+        Self {
+            procs,
+            memory,
+            string_literals,
+            args,
+            env,
+            stack: Vec::new(),
+            stack_slots,
+            input,
+            out,
+        }
+    }
+
+    /// Runs `entry` to completion with the command-line arguments `args`
+    /// and room for `stack_slots` on the data stack, reading from `input`
+    /// and writing to `out`, and returns the process exit code.
+    pub fn run(
+        entry: Entry,
+        procs: &'a [Proc<'src>],
+        string_literals: &[Box<str>],
+        args: &[String],
+        stack_slots: usize,
+        input: R,
+        out: W,
+    ) -> Result<i64, RuntimeError> {
+        let mut interpreter = Self::new(procs, string_literals, args, stack_slots, input, out);
+
+        if let Some(code) = interpreter.call(entry.label().id())? {
+            return Ok(code);
+        }
+
+        interpreter.out.flush()?;
+        if entry.returns_exit_code() {
+            Ok(interpreter.pop()? as i64)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn push(&mut self, slot: u64) -> Result<(), RuntimeError> {
+        if self.stack.len() == self.stack_slots {
+            return Err(RuntimeError::DataStackOverflow);
+        }
+        self.stack.push(slot);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u64, RuntimeError> {
+        self.stack.pop().ok_or(RuntimeError::DataStackUnderflow)
+    }
+
+    /// The value of the environment variable `name`, if it is set. A name
+    /// with `=` or a null byte in it never is.
+    fn getenv(&self, name: &[u8]) -> Option<(u64, u64)> {
+        if name.contains(&b'=') || name.contains(&0) {
+            return None;
+        }
+        self.env.iter().find_map(|&(ptr, len)| {
+            let entry = string(&self.memory, ptr, len).ok()?;
+            let value = entry.strip_prefix(name)?.strip_prefix(b"=")?;
+            Some((ptr + len - value.len() as u64, value.len() as u64))
+        })
+    }
+
+    /// Reads from the input into memory with `read`, first writing out
+    /// anything a prompt may be waiting in.
+    fn read(
+        &mut self,
+        read: impl FnOnce(&mut R, &mut Vec<u8>) -> io::Result<usize>,
+    ) -> Result<(u64, u64), RuntimeError> {
+        self.out.flush()?;
+        let ptr = self.memory.len();
+        read(&mut self.input, &mut self.memory)?;
+        Ok((ptr as u64, (self.memory.len() - ptr) as u64))
+    }
+
+    /// The index of the lowest of the top `size` slots.
+    fn top(&self, size: usize) -> Result<usize, RuntimeError> {
+        self.stack
+            .len()
+            .checked_sub(size)
+            .ok_or(RuntimeError::DataStackUnderflow)
+    }
+
+    /// Runs `proc` until it returns. Returns the exit code if the program
+    /// exits.
+    fn call(&mut self, proc: usize) -> Result<Option<i64>, RuntimeError> {
+        let mut frames = vec![Frame { proc, pc: 0 }];
+
+        while let Some(frame) = frames.last_mut() {
+            let Some(&(_, instruction)) = self.procs[frame.proc].code().get(frame.pc) else {
+                frames.pop();
+                continue;
+            };
+            frame.pc += 1;
+            // A call in tail position replaces the current frame, so tail
+            // recursion runs in constant space as it does natively.
+            let tail = frame.pc == self.procs[frame.proc].code().len();
+
+            match instruction {
+                Instruction::PushInt(i) => self.push(i as u64)?,
+                Instruction::PushBool(b) => self.push(if b { u64::MAX } else { 0 })?,
+                Instruction::PushString(i) => {
+                    let (ptr, len) = self.string_literals[i];
+                    self.push(ptr)?;
+                    self.push(len)?;
+                }
+                Instruction::PushQuote(q) => self.push(q.id() as u64)?,
+
+                Instruction::Add => self.binary(i64::wrapping_add)?,
+                Instruction::Sub => self.binary(i64::wrapping_sub)?,
+                Instruction::Mul => self.binary(i64::wrapping_mul)?,
+                Instruction::Div => {
+                    if self.stack.last() == Some(&0) {
+                        return Err(RuntimeError::DivisionByZero);
+                    }
+                    self.binary(i64::wrapping_div)?
+                }
+                Instruction::Eq => self.binary(|a, b| -((a == b) as i64))?,
+                Instruction::Lt => self.binary(|a, b| -((a < b) as i64))?,
+
+                Instruction::Exit => {
+                    self.out.flush()?;
+                    return Ok(Some(self.pop()? as i64));
+                }
+                Instruction::Puts => {
+                    let len = self.pop()?;
+                    let ptr = self.pop()?;
+                    let bytes = string(&self.memory, ptr, len)?;
+                    self.out.write_all(bytes)?;
+                }
+
+                Instruction::Argc => self.push(self.args.len() as u64)?,
+                Instruction::ArgvNth => {
+                    let n = self.pop()?;
+                    let (ptr, len) = usize::try_from(n)
+                        .ok()
+                        .and_then(|n| self.args.get(n))
+                        .copied()
+                        .unwrap_or((0, 0));
+                    self.push(ptr)?;
+                    self.push(len)?;
+                }
+                Instruction::Getenv => {
+                    let len = self.pop()?;
+                    let ptr = self.pop()?;
+                    let value = self.getenv(string(&self.memory, ptr, len)?);
+                    let (ptr, len) = value.unwrap_or((0, 0));
+                    self.push(ptr)?;
+                    self.push(len)?;
+                    self.push(if value.is_some() { u64::MAX } else { 0 })?;
+                }
+                Instruction::ReadLine => {
+                    let (ptr, mut len) =
+                        self.read(|input, memory| input.read_until(b'\n', memory))?;
+                    let read = len > 0;
+                    if self.memory.last() == Some(&b'\n') && read {
+                        len -= 1;
+                    }
+                    self.push(ptr)?;
+                    self.push(len)?;
+                    self.push(if read { u64::MAX } else { 0 })?;
+                }
+                Instruction::ReadAll => {
+                    let (ptr, len) = self.read(|input, memory| input.read_to_end(memory))?;
+                    self.push(ptr)?;
+                    self.push(len)?;
+                }
+
+                Instruction::Syscall { .. } => return Err(RuntimeError::Syscall),
+
+                Instruction::Dup { size } => {
+                    let start = self.top(size)?;
+                    for i in start..start + size {
+                        self.push(self.stack[i])?;
+                    }
+                }
+                Instruction::Drop { size } => {
+                    let len = self.top(size)?;
+                    self.stack.truncate(len);
+                }
+                Instruction::Swap { size_a, size_b } => {
+                    let start = self.top(size_a + size_b)?;
+                    self.stack[start..].rotate_left(size_b);
+                }
+                Instruction::Over { size_a, size_b } => {
+                    let start = self.top(size_a + size_b)?;
+                    for i in start..start + size_b {
+                        self.push(self.stack[i])?;
+                    }
+                }
+                Instruction::Branch { size } => {
+                    let cond = self.top(2 * size + 1)?;
+                    let chosen = if self.stack[cond] != 0 {
+                        cond + 1
+                    } else {
+                        cond + 1 + size
+                    };
+                    self.stack.copy_within(chosen..chosen + size, cond);
+                    self.stack.truncate(cond + size);
+                }
+
+                Instruction::Apply => {
+                    let quotation = self.pop()?;
+                    let proc = usize::try_from(quotation)
+                        .ok()
+                        .filter(|&proc| proc < self.procs.len())
+                        .ok_or(RuntimeError::InvalidQuotation(quotation))?;
+                    if tail {
+                        frames.pop();
+                    }
+                    frames.push(Frame { proc, pc: 0 });
+                }
+                Instruction::Call(label) => {
+                    if tail {
+                        frames.pop();
+                    }
+                    frames.push(Frame {
+                        proc: label.id(),
+                        pc: 0,
+                    });
+                }
+                Instruction::Ffi { name, .. } => {
+                    return Err(RuntimeError::ForeignCall(name.into()));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn binary(&mut self, op: impl FnOnce(i64, i64) -> i64) -> Result<(), RuntimeError> {
+        let b = self.pop()? as i64;
+        let a = self.pop()? as i64;
+        self.stack.push(op(a, b) as u64);
+        Ok(())
+    }
+}
+
+/// The `len` bytes of `memory` from `ptr`.
+fn string(memory: &[u8], ptr: u64, len: u64) -> Result<&[u8], RuntimeError> {
+    usize::try_from(ptr)
+        .ok()
+        .zip(usize::try_from(len).ok())
+        .and_then(|(ptr, len)| memory.get(ptr..ptr.checked_add(len)?))
+        .ok_or(RuntimeError::InvalidString { ptr, len })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        analyzer::Analyzer,
+        compiler::{Compiler, DATA_STACK_SLOTS},
+        ir,
+        lexer::Lexer,
+    };
+
+    fn run(source: &str) -> (Result<i64, RuntimeError>, String) {
+        run_with_stack(source, DATA_STACK_SLOTS)
+    }
+
+    fn run_with_stack(source: &str, stack_slots: usize) -> (Result<i64, RuntimeError>, String) {
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        let (entry, procs, strings) = Compiler::compile(defs).unwrap();
+        let mut out = Vec::new();
+        let result = Interpreter::run(
+            entry.unwrap(),
+            &procs,
+            &strings,
+            &[],
+            stack_slots,
+            io::empty(),
+            &mut out,
+        );
+        (result, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn arithmetic_and_exit_code() {
+        let (result, _) = run(": square dup * ; : main 3 square 1 + 2 - ;");
+        assert_eq!(result.unwrap(), 8);
+    }
+
+    #[test]
+    fn shuffles_respect_value_sizes() {
+        let (result, out) = run(": main \"a\" 1 swap puts \"b\" over drop puts ;");
+        assert_eq!(out, "ab");
+        assert_eq!(result.unwrap(), 1);
+    }
+
+    #[test]
+    fn branch_and_apply() {
+        let (result, out) = run(": main false [ \"yes\" ] [ \"no\" ] ? apply puts
+                    true [ 1 ] [ 2 ] ? apply ;");
+        assert_eq!(out, "no");
+        assert_eq!(result.unwrap(), 1);
+    }
+
+    #[test]
+    fn comparisons_push_bools() {
+        let (result, _) = run(": main 2 3 < [ 4 ] [ 5 ] ? apply 4 = [ 1 ] [ 0 ] ? apply ;");
+        assert_eq!(result.unwrap(), 1);
+    }
+
+    #[test]
+    fn tail_recursion() {
+        let (result, _) = run(": count dup 0 = [ drop ] [ 1 - count ] ? apply ;
+                               : main 1000000 count 7 ;");
+        assert_eq!(result.unwrap(), 7);
+    }
+
+    #[test]
+    fn stack_size_is_configurable() {
+        let source = ": sum dup 0 = [ ] [ dup 1 - sum + ] ? apply ; : main 100 sum ;";
+        assert_eq!(run_with_stack(source, 104).0.unwrap(), 5050);
+        assert!(matches!(
+            run_with_stack(source, 103).0,
+            Err(RuntimeError::DataStackOverflow)
+        ));
+    }
+
+    #[test]
+    fn arguments_are_read_from_memory() {
+        let source = ": show argv-nth puts ;
+                      : main 1 show 0 show 2 show 0 1 - show \"A=B\" getenv drop puts argc ;";
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        let (entry, procs, strings) = Compiler::compile(defs).unwrap();
+        let args = ["prog".to_string(), "arg".to_string()];
+        let mut out = Vec::new();
+        let result = Interpreter::run(
+            entry.unwrap(),
+            &procs,
+            &strings,
+            &args,
+            DATA_STACK_SLOTS,
+            io::empty(),
+            &mut out,
+        );
+        assert_eq!(out, b"argprog");
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[test]
+    fn input_is_kept_in_memory() {
+        let source = ": line read-line drop puts \"|\" puts ;
+                      : main line line read-all read-line swap drop [ 1 ] [ 2 ] ? apply
+                      swap puts line ;";
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        let (entry, procs, strings) = Compiler::compile(defs).unwrap();
+        let mut out = Vec::new();
+        let result = Interpreter::run(
+            entry.unwrap(),
+            &procs,
+            &strings,
+            &[],
+            DATA_STACK_SLOTS,
+            &b"a\n\nb\nc"[..],
+            &mut out,
+        );
+        assert_eq!(out, b"a||b\nc|");
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[test]
+    fn exit_stops_the_program() {
+        let (result, out) = run(": main \"before\" puts 7 exit \"after\" puts ;");
+        assert_eq!(out, "before");
+        assert_eq!(result.unwrap(), 7);
+    }
+
+    #[test]
+    fn malformed_ir_fails_without_panicking() {
+        let run_ir = |text| {
+            let (entry, procs, strings) = ir::parse(text, None).unwrap();
+            Interpreter::run(
+                entry,
+                &procs,
+                &strings,
+                &[],
+                DATA_STACK_SLOTS,
+                io::empty(),
+                Vec::new(),
+            )
+        };
+
+        assert!(matches!(
+            run_ir("entry @0\nproc @0\n    drop 1"),
+            Err(RuntimeError::DataStackUnderflow)
+        ));
+        assert!(matches!(
+            run_ir("entry @0\nproc @0\n    push-int 1\n    add"),
+            Err(RuntimeError::DataStackUnderflow)
+        ));
+        assert!(matches!(
+            run_ir("entry @0\nproc @0\n    push-int 1000000000\n    push-int 9\n    puts"),
+            Err(RuntimeError::InvalidString {
+                ptr: 1000000000,
+                len: 9
+            })
+        ));
+        assert!(matches!(
+            run_ir("entry @0\nproc @0\n    push-int 5\n    apply"),
+            Err(RuntimeError::InvalidQuotation(5))
+        ));
+    }
+}
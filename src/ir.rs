@@ -0,0 +1,546 @@
+//! A textual form of the `compiler::Instruction` stream.
+//!
+//! ```text
+//! entry @1:main
+//! string 0 "hello\n"
+//!
+//! proc @0:square ( 1 -- 1 )
+//!     1:10    dup 1
+//!     1:14    mul
+//!
+//! proc @1:main ( 0 -- 0 )
+//!     2:8     push-string 0
+//!     2:15    puts
+//! ```
+//!
+//! A proc header may end with the number of slots the proc reads and leaves
+//! on the stack. Every instruction may be prefixed by the `line:col` of the
+//! source it was compiled from. Comments start with `;`.
+
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+use crate::{
+    compiler::{Effect, Entry, Instruction, Label, Proc},
+    diagnostic,
+    lexer::Span,
+};
+
+/// Displays a label the way the IR writes it, e.g. `@0:square`.
+pub struct IrLabel<'a>(pub Label<'a>);
+
+impl fmt::Display for IrLabel<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.name() {
+            Some(name) => write!(f, "@{}:{name}", self.0.id()),
+            None => write!(f, "@{}", self.0.id()),
+        }
+    }
+}
+
+impl fmt::Display for Instruction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::PushInt(i) => write!(f, "push-int {i}"),
+            Instruction::PushBool(b) => write!(f, "push-bool {b}"),
+            Instruction::PushString(i) => write!(f, "push-string {i}"),
+            Instruction::PushQuote(q) => write!(f, "push-quote {}", IrLabel(q)),
+            Instruction::Add => write!(f, "add"),
+            Instruction::Sub => write!(f, "sub"),
+            Instruction::Mul => write!(f, "mul"),
+            Instruction::Div => write!(f, "div"),
+            Instruction::Eq => write!(f, "eq"),
+            Instruction::Lt => write!(f, "lt"),
+            Instruction::Exit => write!(f, "exit"),
+            Instruction::Puts => write!(f, "puts"),
+            Instruction::Argc => write!(f, "argc"),
+            Instruction::ArgvNth => write!(f, "argv-nth"),
+            Instruction::Getenv => write!(f, "getenv"),
+            Instruction::ReadLine => write!(f, "read-line"),
+            Instruction::ReadAll => write!(f, "read-all"),
+            Instruction::Syscall { args } => write!(f, "syscall {args}"),
+            Instruction::Dup { size } => write!(f, "dup {size}"),
+            Instruction::Swap { size_a, size_b } => write!(f, "swap {size_a} {size_b}"),
+            Instruction::Drop { size } => write!(f, "drop {size}"),
+            Instruction::Over { size_a, size_b } => write!(f, "over {size_a} {size_b}"),
+            Instruction::Apply => write!(f, "apply"),
+            Instruction::Branch { size } => write!(f, "branch {size}"),
+            Instruction::Call(label) => write!(f, "call {}", IrLabel(label)),
+            Instruction::Ffi {
+                name,
+                inputs,
+                outputs,
+            } => write!(f, "ffi {name} {inputs} {outputs}"),
+        }
+    }
+}
+
+pub fn print(
+    entry: Entry,
+    procs: &[Proc],
+    string_literals: &[Box<str>],
+    source: &str,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    write!(out, "entry {}", IrLabel(entry.label()))?;
+    if entry.returns_exit_code() {
+        write!(out, " exit-code")?;
+    }
+    writeln!(out)?;
+
+    for (i, string_literal) in string_literals.iter().enumerate() {
+        writeln!(out, "string {i} {string_literal:?}")?;
+    }
+
+    for proc in procs {
+        writeln!(out)?;
+        write!(out, "proc {}", IrLabel(proc.label()))?;
+        if let Some(effect) = proc.effect() {
+            write!(out, " ( {} -- {} )", effect.inputs, effect.outputs)?;
+        }
+        writeln!(out)?;
+        for (span, instruction) in proc.code() {
+            let (ln, col) = diagnostic::line_col(source, span.parts().0);
+            writeln!(out, "    {:<8}{instruction}", format!("{ln}:{col}"))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    line: usize,
+    message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+type Program<'t> = (Entry<'t>, Vec<Proc<'t>>, Vec<Box<str>>);
+
+/// Parses the output of [`print`]. `source` is the Zila source the
+/// `line:col` locations refer to; without it, locations are ignored.
+pub fn parse<'t>(text: &'t str, source: Option<&str>) -> Result<Program<'t>, ParseError> {
+    let mut entry = None;
+    let mut procs: Vec<Proc<'t>> = Vec::new();
+    let mut string_literals = Vec::new();
+    // Procs and strings may be referenced before they are defined, so
+    // references are checked once the whole file is read. Each proc
+    // reference is kept with its line and the proc and instruction it is
+    // in, or `None` for the entry.
+    let mut label_refs = Vec::new();
+    let mut string_refs = Vec::new();
+
+    for (i, raw_line) in text.lines().enumerate() {
+        let line = i + 1;
+        let error = |message: String| ParseError { line, message };
+
+        let trimmed = raw_line.trim_start();
+        if let Some(rest) = trimmed.strip_prefix("string ") {
+            let (id, literal) = rest.trim_start().split_once(' ').unwrap_or((rest, ""));
+            if id.parse() != Ok(string_literals.len()) {
+                return Err(error(format!("expected string {}", string_literals.len())));
+            }
+            string_literals.push(unescape(literal.trim()).map_err(error)?);
+            continue;
+        }
+
+        let code = trimmed.split(';').next().unwrap_or_default();
+        let mut tokens = code.split_whitespace().peekable();
+        let Some(&first) = tokens.peek() else {
+            continue;
+        };
+
+        match first {
+            "entry" => {
+                tokens.next();
+                let label = parse_label(tokens.next()).map_err(error)?;
+                let returns_exit_code = match tokens.next() {
+                    None => false,
+                    Some("exit-code") => true,
+                    Some(t) => return Err(error(format!("unexpected `{t}`"))),
+                };
+                entry = Some(Entry::new(label, returns_exit_code));
+                label_refs.push((line, None, label));
+            }
+            "proc" => {
+                tokens.next();
+                let label = parse_label(tokens.next()).map_err(error)?;
+                if label.id() != procs.len() {
+                    return Err(error(format!("expected proc @{}", procs.len())));
+                }
+                let effect = parse_effect(tokens).map_err(error)?;
+                procs.push(Proc::new(label, effect));
+            }
+            _ => {
+                let Some(proc_index) = procs.len().checked_sub(1) else {
+                    return Err(error("instruction outside of a proc".into()));
+                };
+                let proc = &mut procs[proc_index];
+
+                let span = match first.split_once(':') {
+                    Some((ln, col)) if first.starts_with(|c: char| c.is_ascii_digit()) => {
+                        tokens.next();
+                        let location = ln.parse().ok().zip(col.parse().ok());
+                        let offset = match (location, source) {
+                            (Some(_), None) => 0,
+                            (Some((ln, col)), Some(source)) => {
+                                diagnostic::offset_of(source, ln, col).ok_or_else(|| {
+                                    error(format!("location `{first}` is not in the source"))
+                                })?
+                            }
+                            (None, _) => return Err(error(format!("invalid location `{first}`"))),
+                        };
+                        Span::new(offset, offset)
+                    }
+                    _ => Span::new(0, 0),
+                };
+
+                let instruction = parse_instruction(&mut tokens).map_err(error)?;
+                if let Some(t) = tokens.next() {
+                    return Err(error(format!("unexpected `{t}`")));
+                }
+                match instruction {
+                    Instruction::Call(label) | Instruction::PushQuote(label) => {
+                        label_refs.push((line, Some((proc_index, proc.code().len())), label))
+                    }
+                    Instruction::PushString(id) => string_refs.push((line, id)),
+                    _ => (),
+                }
+                proc.push(span, instruction);
+            }
+        }
+    }
+
+    let Some(mut entry) = entry else {
+        return Err(ParseError {
+            line: 1,
+            message: "missing `entry`".into(),
+        });
+    };
+
+    // A reference finds its proc by id, so it gets the proc's name even
+    // if it was written without one, and a name it does give must match.
+    for (line, site, label) in label_refs {
+        let Some(defined) = procs.get(label.id()).map(Proc::label) else {
+            return Err(ParseError {
+                line,
+                message: format!("undefined proc {}", IrLabel(label)),
+            });
+        };
+        if label
+            .name()
+            .is_some_and(|name| Some(name) != defined.name())
+        {
+            return Err(ParseError {
+                line,
+                message: format!(
+                    "label {} does not match proc {}",
+                    IrLabel(label),
+                    IrLabel(defined)
+                ),
+            });
+        }
+
+        match site {
+            None => entry = Entry::new(defined, entry.returns_exit_code()),
+            Some((proc, i)) => {
+                if let (_, Instruction::Call(label) | Instruction::PushQuote(label)) =
+                    &mut procs[proc].code_mut()[i]
+                {
+                    *label = defined;
+                }
+            }
+        }
+    }
+    if let Some((line, id)) = string_refs
+        .into_iter()
+        .find(|(_, id)| *id >= string_literals.len())
+    {
+        return Err(ParseError {
+            line,
+            message: format!("undefined string {id}"),
+        });
+    }
+
+    Ok((entry, procs, string_literals))
+}
+
+fn parse_label(token: Option<&str>) -> Result<Label<'_>, String> {
+    let token = token.ok_or("expected label")?;
+    let label = token
+        .strip_prefix('@')
+        .ok_or_else(|| format!("expected label, found `{token}`"))?;
+    let (id, name) = match label.split_once(':') {
+        Some((id, name)) => (id, Some(name)),
+        None => (label, None),
+    };
+    let id = id.parse().map_err(|_| format!("invalid label `{token}`"))?;
+    Ok(Label::new(id, name))
+}
+
+/// Parses the optional `( inputs -- outputs )` after a proc's label.
+fn parse_effect<'t>(tokens: impl Iterator<Item = &'t str>) -> Result<Option<Effect>, String> {
+    let tokens = tokens.collect::<Vec<_>>();
+    let slots = |token: &str| {
+        token
+            .parse()
+            .map_err(|_| format!("expected a slot count, found `{token}`"))
+    };
+
+    match tokens[..] {
+        [] => Ok(None),
+        ["(", inputs, "--", outputs, ")"] => Ok(Some(Effect {
+            inputs: slots(inputs)?,
+            outputs: slots(outputs)?,
+        })),
+        _ => Err(format!("invalid effect `{}`", tokens.join(" "))),
+    }
+}
+
+fn parse_instruction<'t>(
+    tokens: &mut impl Iterator<Item = &'t str>,
+) -> Result<Instruction<'t>, String> {
+    let mnemonic = tokens.next().ok_or("expected instruction")?;
+    let mut operand = |what: &str| -> Result<&'t str, String> {
+        tokens
+            .next()
+            .ok_or_else(|| format!("`{mnemonic}` expects {what}"))
+    };
+    let mut number = |what: &str| -> Result<usize, String> {
+        let token = operand(what)?;
+        token
+            .parse()
+            .map_err(|_| format!("`{mnemonic}` expects {what}, found `{token}`"))
+    };
+
+    Ok(match mnemonic {
+        "push-int" => {
+            let token = operand("an integer")?;
+            Instruction::PushInt(
+                token
+                    .parse()
+                    .map_err(|_| format!("invalid integer `{token}`"))?,
+            )
+        }
+        "push-bool" => match operand("a bool")? {
+            "true" => Instruction::PushBool(true),
+            "false" => Instruction::PushBool(false),
+            t => return Err(format!("invalid bool `{t}`")),
+        },
+        "push-string" => Instruction::PushString(number("a string id")?),
+        "push-quote" => Instruction::PushQuote(parse_label(Some(operand("a label")?))?),
+        "add" => Instruction::Add,
+        "sub" => Instruction::Sub,
+        "mul" => Instruction::Mul,
+        "div" => Instruction::Div,
+        "eq" => Instruction::Eq,
+        "lt" => Instruction::Lt,
+        "exit" => Instruction::Exit,
+        "puts" => Instruction::Puts,
+        "argc" => Instruction::Argc,
+        "argv-nth" => Instruction::ArgvNth,
+        "getenv" => Instruction::Getenv,
+        "read-line" => Instruction::ReadLine,
+        "read-all" => Instruction::ReadAll,
+        "syscall" => Instruction::Syscall {
+            args: number("an argument count")?,
+        },
+        "dup" => Instruction::Dup {
+            size: number("a size")?,
+        },
+        "swap" => Instruction::Swap {
+            size_a: number("a size")?,
+            size_b: number("a size")?,
+        },
+        "drop" => Instruction::Drop {
+            size: number("a size")?,
+        },
+        "over" => Instruction::Over {
+            size_a: number("a size")?,
+            size_b: number("a size")?,
+        },
+        "apply" => Instruction::Apply,
+        "branch" => Instruction::Branch {
+            size: number("a size")?,
+        },
+        "call" => Instruction::Call(parse_label(Some(operand("a label")?))?),
+        "ffi" => {
+            let name = operand("a C function")?;
+            let [inputs, outputs] =
+                [operand("a slot count")?, operand("a slot count")?].map(|token| {
+                    token
+                        .parse()
+                        .map_err(|_| format!("`ffi` expects a slot count, found `{token}`"))
+                });
+            Instruction::Ffi {
+                name,
+                inputs: inputs?,
+                outputs: outputs?,
+            }
+        }
+        _ => return Err(format!("unknown instruction `{mnemonic}`")),
+    })
+}
+
+/// Reads a string literal as written by `{:?}`.
+fn unescape(literal: &str) -> Result<Box<str>, String> {
+    let inner = literal
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(|| format!("expected string literal, found `{literal}`"))?;
+
+    let mut unescaped = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('0') => unescaped.push('\0'),
+            Some(c @ ('\\' | '"' | '\'')) => unescaped.push(c),
+            Some('u') => {
+                let rest = chars.as_str();
+                let code = rest
+                    .strip_prefix('{')
+                    .and_then(|r| r.split_once('}'))
+                    .and_then(|(hex, _)| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32)
+                    .ok_or("invalid unicode escape")?;
+                unescaped.push(code);
+                let close = rest.find('}').unwrap_or_default();
+                chars = rest[close + 1..].chars();
+            }
+            c => return Err(format!("invalid escape `\\{}`", c.unwrap_or(' '))),
+        }
+    }
+
+    Ok(unescaped.into_boxed_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analyzer::Analyzer, compiler::Compiler, lexer::Lexer};
+
+    fn dump(entry: Entry, procs: &[Proc], strings: &[Box<str>], source: &str) -> String {
+        let mut out = Vec::new();
+        print(entry, procs, strings, source, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn compiled_program_round_trips() {
+        let source = ": square dup * ;\n: main \"hi\\n\" puts 3 square [ 1 + ] apply ;\n";
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        let (entry, procs, strings) = Compiler::compile(defs).unwrap();
+        let text = dump(entry.unwrap(), &procs, &strings, source);
+
+        assert_eq!(
+            text,
+            "entry @1:main exit-code
+string 0 \"hi\\n\"
+
+proc @0:square ( 1 -- 1 )
+    1:10    dup 1
+    1:14    mul
+
+proc @1:main ( 0 -- 1 )
+    2:8     push-string 0
+    2:15    puts
+    2:20    push-int 3
+    2:22    call @0:square
+    2:29    push-quote @2
+    2:37    apply
+
+proc @2 ( 1 -- 1 )
+    2:31    push-int 1
+    2:33    add
+"
+        );
+
+        let (entry, procs, strings) = parse(&text, Some(source)).unwrap();
+        assert_eq!(dump(entry, &procs, &strings, source), text);
+    }
+
+    #[test]
+    fn hand_written_ir_parses() {
+        let text = "
+            ; sums two numbers
+            entry @0:main
+            proc @0:main
+                push-int 2   ; comment
+                push-int 3
+                add
+                push-bool false
+                drop 1
+                ffi labs 1 1
+                call @1
+            proc @1
+        ";
+        let (entry, procs, strings) = parse(text, None).unwrap();
+        assert_eq!(entry.label().id(), 0);
+        assert_eq!(procs.len(), 2);
+        assert!(strings.is_empty());
+        assert!(matches!(procs[0].code()[2].1, Instruction::Add));
+        assert!(matches!(
+            procs[0].code()[5].1,
+            Instruction::Ffi {
+                name: "labs",
+                inputs: 1,
+                outputs: 1
+            }
+        ));
+    }
+
+    #[test]
+    fn references_take_the_name_of_their_proc() {
+        let text = "entry @0\nproc @0:main\n    push-quote @1\n    call @1\nproc @1:foo\n";
+        let (entry, procs, _) = parse(text, None).unwrap();
+        assert_eq!(entry.label().name(), Some("main"));
+        for (_, instruction) in procs[0].code() {
+            let (Instruction::Call(label) | Instruction::PushQuote(label)) = instruction else {
+                panic!("expected a reference, found `{instruction}`");
+            };
+            assert_eq!(label.name(), Some("foo"));
+        }
+    }
+
+    #[test]
+    fn errors_report_the_line() {
+        let err = parse("entry @0\nproc @0\n    frobnicate\n", None).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: unknown instruction `frobnicate`");
+
+        let err = parse("entry @0\nproc @0\n    call @4\n", None).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: undefined proc @4");
+
+        let err = parse("entry @0\nproc @0\n    call @7\n    puts\n", None).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: undefined proc @7");
+
+        let err = parse("entry @0\nproc @0:main\n    call @0:foo\n", None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 3: label @0:foo does not match proc @0:main"
+        );
+
+        let err = parse("entry @2\nproc @0\n", None).unwrap_err();
+        assert_eq!(err.to_string(), "line 1: undefined proc @2");
+
+        let err = parse("entry @0\nproc @0\n    push-string 3\n    puts\n", None).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: undefined string 3");
+
+        let err = parse("entry @0\nproc @0 ( 1 -> 0 )\n", None).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: invalid effect `( 1 -> 0 )`");
+    }
+}
@@ -1,5 +1,3 @@
-use std::ops::Range;
-
 #[derive(Debug, Clone, Copy)]
 pub struct Span {
     start: usize,
@@ -7,7 +5,7 @@ pub struct Span {
 }
 
 impl Span {
-    fn new(start: usize, end: usize) -> Self {
+    pub fn new(start: usize, end: usize) -> Self {
         Self { start, end }
     }
 
@@ -41,13 +39,6 @@ impl<'src> Word<'src> {
     pub fn span(&self) -> Span {
         self.span
     }
-
-    pub fn word(&self) -> &'src str {
-        let Token::Symbol(word) = self.token() else {
-            unreachable!();
-        };
-        word
-    }
 }
 
 pub struct Lexer<'src> {
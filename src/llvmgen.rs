@@ -0,0 +1,747 @@
+//! Translates the IR to textual LLVM IR, so LLVM's optimizer can be used
+//! without linking LLVM into zila.
+//!
+//! The data stack is the global `@stack`, indexed by `@sp`, with the same
+//! slot layout as the other backends. Every proc becomes a `void ()`
+//! function and tail calls are `musttail`, which LLVM turns into jumps at
+//! every optimization level. Output is written with `write`, so nothing is
+//! lost when a program calls `exit`.
+
+use std::io::{self, Write};
+
+use crate::{
+    compiler::{self, Entry, Instruction, Label, Proc},
+    lexer::Span,
+};
+
+const PRELUDE: &str = r#"declare i64 @write(i32, ptr, i64)
+declare void @exit(i32) noreturn
+declare i32 @raise(i32)
+declare i64 @strlen(ptr)
+declare ptr @memchr(ptr, i32, i64)
+declare ptr @memcpy(ptr, ptr, i64)
+declare ptr @malloc(i64)
+declare void @free(ptr)
+declare ptr @getenv(ptr)
+declare i32 @getchar()
+declare ptr @realloc(ptr, i64)
+
+@argc = internal global i64 0
+@argv = internal global ptr null
+@empty = private unnamed_addr constant [1 x i8] zeroinitializer
+@out_of_memory_message = private unnamed_addr constant [14 x i8] c"out of memory\0A"
+
+@underflow_message = private unnamed_addr constant [21 x i8] c"data stack underflow\0A"
+@overflow_message = private unnamed_addr constant [20 x i8] c"data stack overflow\0A"
+
+define internal void @stack_underflow() noreturn cold {
+  call i64 @write(i32 2, ptr @underflow_message, i64 21)
+  call void @exit(i32 1)
+  unreachable
+}
+
+define internal void @stack_overflow() noreturn cold {
+  call i64 @write(i32 2, ptr @overflow_message, i64 20)
+  call void @exit(i32 1)
+  unreachable
+}
+
+; Traps like `idiv` does rather than leaving the result undefined.
+define internal i64 @divide(i64 %a, i64 %b) {
+  %zero = icmp eq i64 %b, 0
+  %min = icmp eq i64 %a, -9223372036854775808
+  %minus_one = icmp eq i64 %b, -1
+  %overflow = and i1 %min, %minus_one
+  %trap = or i1 %zero, %overflow
+  br i1 %trap, label %fpe, label %ok
+
+fpe:
+  call i32 @raise(i32 8)
+  unreachable
+
+ok:
+  %quotient = sdiv i64 %a, %b
+  ret i64 %quotient
+}
+
+; Command-line argument `n`, or the empty string past the last one.
+define internal ptr @argv_nth(i64 %n) {
+  %argc = load i64, ptr @argc
+  %found = icmp ult i64 %n, %argc
+  br i1 %found, label %arg, label %past_end
+
+arg:
+  %argv = load ptr, ptr @argv
+  %slot = getelementptr ptr, ptr %argv, i64 %n
+  %value = load ptr, ptr %slot
+  ret ptr %value
+
+past_end:
+  ret ptr @empty
+}
+
+; The value of the variable named by the `len` bytes at `name`, or null if
+; it isn't set. Names with `=` or a null byte in them never are.
+define internal ptr @env_value(ptr %name, i64 %len) {
+  %equals = call ptr @memchr(ptr %name, i32 61, i64 %len)
+  %has_equals = icmp ne ptr %equals, null
+  %null = call ptr @memchr(ptr %name, i32 0, i64 %len)
+  %has_null = icmp ne ptr %null, null
+  %invalid = or i1 %has_equals, %has_null
+  br i1 %invalid, label %unset, label %copy
+
+copy:
+  %size = add i64 %len, 1
+  %name_z = call ptr @malloc(i64 %size)
+  %failed = icmp eq ptr %name_z, null
+  br i1 %failed, label %unset, label %lookup
+
+lookup:
+  call ptr @memcpy(ptr %name_z, ptr %name, i64 %len)
+  %end = getelementptr i8, ptr %name_z, i64 %len
+  store i8 0, ptr %end
+  %value = call ptr @getenv(ptr %name_z)
+  call void @free(ptr %name_z)
+  ret ptr %value
+
+unset:
+  ret ptr null
+}
+
+; Reads stdin up to the next newline, or to its end if `line` is false, into
+; a buffer that is never freed, and stores its address and length in the two
+; slots at `string`. Returns false if the input had already ended.
+define internal i1 @read_input(i1 %line, ptr %string) {
+entry:
+  %buffer = alloca ptr
+  %size = alloca i64
+  %len = alloca i64
+  store ptr null, ptr %buffer
+  store i64 0, ptr %size
+  store i64 0, ptr %len
+  br label %next
+
+next:
+  %c = call i32 @getchar()
+  %eof = icmp slt i32 %c, 0
+  br i1 %eof, label %done, label %byte
+
+byte:
+  %newline = icmp eq i32 %c, 10
+  %line_end = and i1 %line, %newline
+  br i1 %line_end, label %done, label %room
+
+room:
+  %n = load i64, ptr %len
+  %old_size = load i64, ptr %size
+  %full = icmp eq i64 %n, %old_size
+  br i1 %full, label %grow, label %append
+
+grow:
+  %doubled = shl i64 %old_size, 1
+  %new_size = add i64 %doubled, 64
+  %old = load ptr, ptr %buffer
+  %grown = call ptr @realloc(ptr %old, i64 %new_size)
+  %failed = icmp eq ptr %grown, null
+  br i1 %failed, label %out_of_memory, label %grew
+
+grew:
+  store ptr %grown, ptr %buffer
+  store i64 %new_size, ptr %size
+  br label %append
+
+append:
+  %s = load ptr, ptr %buffer
+  %at = getelementptr i8, ptr %s, i64 %n
+  %b = trunc i32 %c to i8
+  store i8 %b, ptr %at
+  %more = add i64 %n, 1
+  store i64 %more, ptr %len
+  br label %next
+
+done:
+  %result = load ptr, ptr %buffer
+  %address = ptrtoint ptr %result to i64
+  store i64 %address, ptr %string
+  %length = load i64, ptr %len
+  %length_slot = getelementptr i64, ptr %string, i64 1
+  store i64 %length, ptr %length_slot
+  %read = icmp ne i64 %length, 0
+  %more_input = xor i1 %eof, true
+  %found = or i1 %read, %more_input
+  ret i1 %found
+
+out_of_memory:
+  call i64 @write(i32 2, ptr @out_of_memory_message, i64 14)
+  call void @exit(i32 1)
+  unreachable
+}
+"#;
+
+/// The LLVM name of `label`. Quoting allows any name, so it is the same
+/// as the native symbol.
+fn ll_name(label: Label) -> String {
+    format!("@\"{label}\"")
+}
+
+/// An LLVM string constant with the bytes of `s`.
+fn ll_string(s: &str) -> String {
+    let mut literal = String::from("c\"");
+    for b in s.bytes() {
+        if b.is_ascii_graphic() && b != b'"' && b != b'\\' || b == b' ' {
+            literal.push(b as char);
+        } else {
+            literal.push_str(&format!("\\{b:02X}"));
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Writes the program as an LLVM module whose data stack holds
+/// `stack_slots` slots.
+pub fn generate(
+    entry: Entry,
+    procs: &[Proc],
+    string_literals: &[Box<str>],
+    stack_slots: usize,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "@stack = internal global [{stack_slots} x i64] zeroinitializer"
+    )?;
+    writeln!(out, "@sp = internal global i64 0")?;
+    for (i, string_literal) in string_literals.iter().enumerate() {
+        writeln!(
+            out,
+            "@str.{i} = private unnamed_addr constant [{} x i8] {}",
+            string_literal.len(),
+            ll_string(string_literal)
+        )?;
+    }
+
+    writeln!(out)?;
+    write!(out, "{PRELUDE}")?;
+
+    for proc in procs {
+        writeln!(out)?;
+        let mut generator = ProcGenerator {
+            code: String::new(),
+            temps: 0,
+            blocks: 0,
+            stack_slots,
+            string_literals,
+        };
+        generator.gen_proc(proc);
+        write!(out, "{}", generator.code)?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "define i32 @main(i32 %argc, ptr %argv) {{")?;
+    writeln!(out, "  %argc.64 = sext i32 %argc to i64")?;
+    writeln!(out, "  store i64 %argc.64, ptr @argc")?;
+    writeln!(out, "  store ptr %argv, ptr @argv")?;
+    writeln!(out, "  call void {}()", ll_name(entry.label()))?;
+    if entry.returns_exit_code() {
+        writeln!(out, "  %sp = load i64, ptr @sp")?;
+        writeln!(out, "  %top = add i64 %sp, -1")?;
+        writeln!(out, "  %slot = getelementptr i64, ptr @stack, i64 %top")?;
+        writeln!(out, "  %value = load i64, ptr %slot")?;
+        writeln!(out, "  %code = trunc i64 %value to i32")?;
+        writeln!(out, "  ret i32 %code")?;
+    } else {
+        writeln!(out, "  ret i32 0")?;
+    }
+    writeln!(out, "}}")
+}
+
+struct ProcGenerator<'a> {
+    code: String,
+    /// The number of `%tN` values defined so far.
+    temps: usize,
+    /// The number of `checkN` blocks defined so far.
+    blocks: usize,
+    stack_slots: usize,
+    string_literals: &'a [Box<str>],
+}
+
+impl ProcGenerator<'_> {
+    fn line(&mut self, line: impl AsRef<str>) {
+        self.code.push_str("  ");
+        self.code.push_str(line.as_ref());
+        self.code.push('\n');
+    }
+
+    /// Defines a fresh value as `rhs` and returns its name.
+    fn temp(&mut self, rhs: impl AsRef<str>) -> String {
+        let name = format!("%t{}", self.temps);
+        self.temps += 1;
+        self.line(format!("{name} = {}", rhs.as_ref()));
+        name
+    }
+
+    fn load_sp(&mut self) -> String {
+        self.temp("load i64, ptr @sp")
+    }
+
+    /// Moves `@sp`, which was `sp`, by `delta` slots.
+    fn move_sp(&mut self, sp: &str, delta: isize) {
+        if delta != 0 {
+            let moved = self.temp(format!("add i64 {sp}, {delta}"));
+            self.line(format!("store i64 {moved}, ptr @sp"));
+        }
+    }
+
+    /// A pointer to the slot `offset` slots from `sp`.
+    fn slot(&mut self, sp: &str, offset: isize) -> String {
+        let index = if offset == 0 {
+            sp.to_string()
+        } else {
+            self.temp(format!("add i64 {sp}, {offset}"))
+        };
+        self.temp(format!("getelementptr i64, ptr @stack, i64 {index}"))
+    }
+
+    fn load(&mut self, sp: &str, offset: isize) -> String {
+        let slot = self.slot(sp, offset);
+        self.temp(format!("load i64, ptr {slot}"))
+    }
+
+    fn store(&mut self, value: &str, sp: &str, offset: isize) {
+        let slot = self.slot(sp, offset);
+        self.line(format!("store i64 {value}, ptr {slot}"));
+    }
+
+    fn gen_proc(&mut self, proc: &Proc) {
+        self.code.push_str(&format!(
+            "define internal void {}() {{\n",
+            ll_name(proc.label())
+        ));
+
+        let instructions = proc.code();
+        self.gen_stack_check(instructions);
+        for (i, &(span, instruction)) in instructions.iter().enumerate() {
+            self.line(format!("; {span:?} -- {instruction}"));
+            let tail = i + 1 == instructions.len();
+            self.gen_instruction(instruction, tail);
+
+            if !tail && matches!(instruction, Instruction::Call(_) | Instruction::Apply) {
+                self.gen_stack_check(&instructions[i + 1..]);
+            }
+        }
+
+        if !matches!(
+            instructions.last(),
+            Some((_, Instruction::Call(_) | Instruction::Apply))
+        ) {
+            self.line("ret void");
+        }
+        self.code.push_str("}\n");
+    }
+
+    /// Checks that the stack can take what `instructions` do to it up to
+    /// the next call.
+    fn gen_stack_check(&mut self, instructions: &[(Span, Instruction)]) {
+        let (below, above) = compiler::segment_bounds(instructions);
+        if below == 0 && above == 0 {
+            return;
+        }
+
+        let sp = self.load_sp();
+        if below > 0 {
+            let failed = self.temp(format!("icmp ult i64 {sp}, {below}"));
+            self.gen_check(&failed, "@stack_underflow");
+        }
+        if above > 0 {
+            let room = self.temp(format!("sub i64 {}, {sp}", self.stack_slots));
+            let failed = self.temp(format!("icmp ult i64 {room}, {above}"));
+            self.gen_check(&failed, "@stack_overflow");
+        }
+    }
+
+    /// Calls `error` if `failed` is true.
+    fn gen_check(&mut self, failed: &str, error: &str) {
+        let block = self.blocks;
+        self.blocks += 1;
+        self.line(format!(
+            "br i1 {failed}, label %check{block}.failed, label %check{block}.ok"
+        ));
+        self.code.push_str(&format!("\ncheck{block}.failed:\n"));
+        self.line(format!("call void {error}()"));
+        self.line("unreachable");
+        self.code.push_str(&format!("\ncheck{block}.ok:\n"));
+    }
+
+    fn gen_call(&mut self, callee: &str, tail: bool) {
+        if tail {
+            self.line(format!("musttail call void {callee}()"));
+            self.line("ret void");
+        } else {
+            self.line(format!("call void {callee}()"));
+        }
+    }
+
+    /// Stores the null-terminated string `s` as a pointer and length in the
+    /// two slots from `offset` slots from `sp`.
+    fn gen_string(&mut self, s: &str, sp: &str, offset: isize) {
+        let ptr = self.temp(format!("ptrtoint ptr {s} to i64"));
+        self.store(&ptr, sp, offset);
+        let len = self.temp(format!("call i64 @strlen(ptr {s})"));
+        self.store(&len, sp, offset + 1);
+    }
+
+    fn gen_binary(&mut self, op: impl FnOnce(&mut Self, &str, &str) -> String) {
+        let sp = self.load_sp();
+        let a = self.load(&sp, -2);
+        let b = self.load(&sp, -1);
+        let result = op(self, &a, &b);
+        self.store(&result, &sp, -2);
+        self.move_sp(&sp, -1);
+    }
+
+    fn gen_instruction(&mut self, instruction: Instruction, tail: bool) {
+        match instruction {
+            Instruction::PushInt(i) => self.gen_push(&[i.to_string()]),
+            Instruction::PushBool(b) => self.gen_push(&[if b { "-1" } else { "0" }.to_string()]),
+            Instruction::PushString(i) => {
+                let len = self.string_literals[i].len();
+                self.gen_push(&[format!("ptrtoint (ptr @str.{i} to i64)"), len.to_string()])
+            }
+            Instruction::PushQuote(q) => {
+                self.gen_push(&[format!("ptrtoint (ptr {} to i64)", ll_name(q))])
+            }
+
+            Instruction::Add => self.gen_binary(|g, a, b| g.temp(format!("add i64 {a}, {b}"))),
+            Instruction::Sub => self.gen_binary(|g, a, b| g.temp(format!("sub i64 {a}, {b}"))),
+            Instruction::Mul => self.gen_binary(|g, a, b| g.temp(format!("mul i64 {a}, {b}"))),
+            Instruction::Div => {
+                self.gen_binary(|g, a, b| g.temp(format!("call i64 @divide(i64 {a}, i64 {b})")))
+            }
+
+            Instruction::Eq => self.gen_binary(|g, a, b| {
+                let eq = g.temp(format!("icmp eq i64 {a}, {b}"));
+                g.temp(format!("sext i1 {eq} to i64"))
+            }),
+            Instruction::Lt => self.gen_binary(|g, a, b| {
+                let lt = g.temp(format!("icmp slt i64 {a}, {b}"));
+                g.temp(format!("sext i1 {lt} to i64"))
+            }),
+
+            Instruction::Exit => {
+                let sp = self.load_sp();
+                let code = self.load(&sp, -1);
+                let code = self.temp(format!("trunc i64 {code} to i32"));
+                self.move_sp(&sp, -1);
+                self.line(format!("call void @exit(i32 {code})"));
+            }
+
+            Instruction::Puts => {
+                let sp = self.load_sp();
+                let ptr = self.load(&sp, -2);
+                let len = self.load(&sp, -1);
+                self.move_sp(&sp, -2);
+                let ptr = self.temp(format!("inttoptr i64 {ptr} to ptr"));
+                self.line(format!("call i64 @write(i32 1, ptr {ptr}, i64 {len})"));
+            }
+
+            Instruction::Argc => {
+                let sp = self.load_sp();
+                let argc = self.temp("load i64, ptr @argc");
+                self.store(&argc, &sp, 0);
+                self.move_sp(&sp, 1);
+            }
+            Instruction::ArgvNth => {
+                let sp = self.load_sp();
+                let n = self.load(&sp, -1);
+                let arg = self.temp(format!("call ptr @argv_nth(i64 {n})"));
+                self.gen_string(&arg, &sp, -1);
+                self.move_sp(&sp, 1);
+            }
+            Instruction::Getenv => {
+                let sp = self.load_sp();
+                let ptr = self.load(&sp, -2);
+                let len = self.load(&sp, -1);
+                let name = self.temp(format!("inttoptr i64 {ptr} to ptr"));
+                let value = self.temp(format!("call ptr @env_value(ptr {name}, i64 {len})"));
+                let set = self.temp(format!("icmp ne ptr {value}, null"));
+                let value = self.temp(format!("select i1 {set}, ptr {value}, ptr @empty"));
+                self.gen_string(&value, &sp, -2);
+                let set = self.temp(format!("sext i1 {set} to i64"));
+                self.store(&set, &sp, 0);
+                self.move_sp(&sp, 1);
+            }
+            Instruction::ReadLine => {
+                let sp = self.load_sp();
+                let string = self.slot(&sp, 0);
+                let found = self.temp(format!("call i1 @read_input(i1 true, ptr {string})"));
+                let found = self.temp(format!("sext i1 {found} to i64"));
+                self.store(&found, &sp, 2);
+                self.move_sp(&sp, 3);
+            }
+            Instruction::ReadAll => {
+                let sp = self.load_sp();
+                let string = self.slot(&sp, 0);
+                self.line(format!("call i1 @read_input(i1 false, ptr {string})"));
+                self.move_sp(&sp, 2);
+            }
+
+            Instruction::Dup { size } => {
+                let sp = self.load_sp();
+                let size = size as isize;
+                for k in 0..size {
+                    let value = self.load(&sp, k - size);
+                    self.store(&value, &sp, k);
+                }
+                self.move_sp(&sp, size);
+            }
+            Instruction::Swap { size_a, size_b } => {
+                let sp = self.load_sp();
+                let total = (size_a + size_b) as isize;
+                let values = (-total..0)
+                    .map(|offset| self.load(&sp, offset))
+                    .collect::<Vec<_>>();
+                let swapped = values[size_b..].iter().chain(&values[..size_b]);
+                for (offset, value) in (-total..0).zip(swapped) {
+                    self.store(value, &sp, offset);
+                }
+            }
+            Instruction::Drop { size } => {
+                let sp = self.load_sp();
+                self.move_sp(&sp, -(size as isize));
+            }
+            Instruction::Over { size_a, size_b } => {
+                let sp = self.load_sp();
+                let total = (size_a + size_b) as isize;
+                for k in 0..size_b as isize {
+                    let value = self.load(&sp, k - total);
+                    self.store(&value, &sp, k);
+                }
+                self.move_sp(&sp, size_b as isize);
+            }
+
+            Instruction::Branch { size } => {
+                let sp = self.load_sp();
+                let size = size as isize;
+                let bottom = -(2 * size + 1);
+                let cond = self.load(&sp, bottom);
+                let cond = self.temp(format!("icmp ne i64 {cond}, 0"));
+                for k in 0..size {
+                    let t = self.load(&sp, bottom + 1 + k);
+                    let f = self.load(&sp, bottom + 1 + size + k);
+                    let value = self.temp(format!("select i1 {cond}, i64 {t}, i64 {f}"));
+                    self.store(&value, &sp, bottom + k);
+                }
+                self.move_sp(&sp, -(size + 1));
+            }
+
+            Instruction::Apply => {
+                let sp = self.load_sp();
+                let quote = self.load(&sp, -1);
+                self.move_sp(&sp, -1);
+                let callee = self.temp(format!("inttoptr i64 {quote} to ptr"));
+                self.gen_call(&callee, tail);
+            }
+            Instruction::Call(label) => self.gen_call(&ll_name(label), tail),
+            Instruction::Ffi { .. } => unreachable!("only x86_64 code calls C functions"),
+            Instruction::Syscall { .. } => unreachable!("only x86_64 code makes system calls"),
+        }
+    }
+
+    fn gen_push(&mut self, values: &[String]) {
+        let sp = self.load_sp();
+        for (offset, value) in values.iter().enumerate() {
+            self.store(value, &sp, offset as isize);
+        }
+        self.move_sp(&sp, values.len() as isize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf, process::Command};
+
+    use super::*;
+    use crate::{
+        analyzer::Analyzer,
+        compiler::{Compiler, DATA_STACK_SLOTS},
+        interp::Interpreter,
+        lexer::Lexer,
+        optimizer::{self, OptLevel},
+    };
+
+    fn generate_ll(source: &str, level: OptLevel, stack_slots: usize) -> String {
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        let (entry, mut procs, strings) = Compiler::compile(defs).unwrap();
+        optimizer::optimize(&mut procs, level);
+        let (entry, procs, strings) =
+            optimizer::eliminate_dead_code(entry.unwrap(), procs, strings);
+        let mut out = Vec::new();
+        generate(entry, &procs, &strings, stack_slots, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Builds `ll` with `clang` in a fresh temporary directory.
+    fn build_ll(ll: &str, name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("zila-ll-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (src, exe) = (dir.join("out.ll"), dir.join("out"));
+        fs::write(&src, ll).unwrap();
+
+        let clang = Command::new("clang")
+            .args(["-O2", "-o"])
+            .arg(&exe)
+            .arg(&src)
+            .status()
+            .unwrap();
+        assert!(clang.success());
+
+        exe
+    }
+
+    #[test]
+    fn strings_escape_quotes_and_control_characters() {
+        assert_eq!(ll_string("a \"b\"\\\n"), "c\"a \\22b\\22\\5C\\0A\"");
+    }
+
+    #[test]
+    fn module_matches_golden_output() {
+        let ll = generate_ll(": main 1 2 + ;", OptLevel::O0, 16);
+        let expected = format!(
+            r#"@stack = internal global [16 x i64] zeroinitializer
+@sp = internal global i64 0
+
+{PRELUDE}
+define internal void @"zila_0_main"() {{
+  %t0 = load i64, ptr @sp
+  %t1 = sub i64 16, %t0
+  %t2 = icmp ult i64 %t1, 2
+  br i1 %t2, label %check0.failed, label %check0.ok
+
+check0.failed:
+  call void @stack_overflow()
+  unreachable
+
+check0.ok:
+  ; Span {{ start: 7, end: 8 }} -- push-int 1
+  %t3 = load i64, ptr @sp
+  %t4 = getelementptr i64, ptr @stack, i64 %t3
+  store i64 1, ptr %t4
+  %t5 = add i64 %t3, 1
+  store i64 %t5, ptr @sp
+  ; Span {{ start: 9, end: 10 }} -- push-int 2
+  %t6 = load i64, ptr @sp
+  %t7 = getelementptr i64, ptr @stack, i64 %t6
+  store i64 2, ptr %t7
+  %t8 = add i64 %t6, 1
+  store i64 %t8, ptr @sp
+  ; Span {{ start: 11, end: 12 }} -- add
+  %t9 = load i64, ptr @sp
+  %t10 = add i64 %t9, -2
+  %t11 = getelementptr i64, ptr @stack, i64 %t10
+  %t12 = load i64, ptr %t11
+  %t13 = add i64 %t9, -1
+  %t14 = getelementptr i64, ptr @stack, i64 %t13
+  %t15 = load i64, ptr %t14
+  %t16 = add i64 %t12, %t15
+  %t17 = add i64 %t9, -2
+  %t18 = getelementptr i64, ptr @stack, i64 %t17
+  store i64 %t16, ptr %t18
+  %t19 = add i64 %t9, -1
+  store i64 %t19, ptr @sp
+  ret void
+}}
+
+define i32 @main(i32 %argc, ptr %argv) {{
+  %argc.64 = sext i32 %argc to i64
+  store i64 %argc.64, ptr @argc
+  store ptr %argv, ptr @argv
+  call void @"zila_0_main"()
+  %sp = load i64, ptr @sp
+  %top = add i64 %sp, -1
+  %slot = getelementptr i64, ptr @stack, i64 %top
+  %value = load i64, ptr %slot
+  %code = trunc i64 %value to i32
+  ret i32 %code
+}}
+"#
+        );
+        assert_eq!(ll, expected);
+    }
+
+    #[test]
+    fn strings_and_quotes_are_addresses() {
+        let ll = generate_ll(
+            ": main \"hi\" puts [ 1 ] apply exit ;",
+            OptLevel::O0,
+            DATA_STACK_SLOTS,
+        );
+        assert!(ll.contains("@str.0 = private unnamed_addr constant [2 x i8] c\"hi\"\n"));
+        assert!(ll.contains("store i64 ptrtoint (ptr @str.0 to i64), ptr %t4\n"));
+        assert!(ll.contains("store i64 ptrtoint (ptr @\"zila_1\" to i64), ptr %t"));
+        assert!(ll.contains("  call void @exit(i32 %t"));
+    }
+
+    #[test]
+    fn tail_calls_are_musttail() {
+        let ll = generate_ll(
+            ": loop dup 0 = [ drop ] [ 1 - loop ] ? apply ; : main 5 loop ;",
+            OptLevel::O0,
+            DATA_STACK_SLOTS,
+        );
+        assert!(ll.contains("  musttail call void @\"zila_0_loop\"()\n  ret void\n}"));
+        assert!(ll.contains(" to ptr\n  musttail call void %t"));
+        assert!(!ll.contains("\n  call void @\"zila_0_loop\"()"));
+    }
+
+    #[test]
+    #[ignore = "needs clang; run with `cargo test -- --ignored`"]
+    fn clang_builds_match_the_interpreter() {
+        let programs = [
+            ": main \"hello\\n\" puts 3 4 * 5 - 2 / ;",
+            ": main \"a\" 1 swap puts \"b\" over drop puts 2 swap - ;",
+            ": pick [ \"yes\" ] [ \"no\" ] ? apply ;
+             : main true pick puts false pick puts
+             1 2 < [ 10 ] [ 20 ] ? apply 3 3 = [ 4 ] [ 5 ] ? apply + ;",
+            ": count dup 0 = [ drop ] [ 1 - count ] ? apply ;
+             : main 10000000 count \"done\" puts ;",
+            ": s \"xy\" ;
+             : main 1 s 2 swap over drop dup puts puts + s 5 over puts drop puts \"end\" puts 7 exit ;",
+            ": main 0 7 - 2 / 7 0 2 - / * ;",
+        ];
+
+        for (i, source) in programs.into_iter().enumerate() {
+            let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+            let (entry, procs, strings) = Compiler::compile(defs).unwrap();
+            let mut out = Vec::new();
+            let code = Interpreter::run(
+                entry.unwrap(),
+                &procs,
+                &strings,
+                &[],
+                DATA_STACK_SLOTS,
+                io::empty(),
+                &mut out,
+            )
+            .unwrap();
+
+            for level in [OptLevel::O0, OptLevel::O2] {
+                let ll = generate_ll(source, level, DATA_STACK_SLOTS);
+                let exe = build_ll(&ll, &format!("{i}-{level:?}"));
+                let output = Command::new(exe).output().unwrap();
+                assert_eq!(output.stdout, out, "{source} at {level:?}");
+                assert_eq!(output.status.code(), Some(code as u8 as i32), "{source}");
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "needs clang; run with `cargo test -- --ignored`"]
+    fn stack_overflow_is_reported() {
+        let source = ": grow dup 0 = [ ] [ dup 1 - grow + ] ? apply ; : main 100 grow ;";
+        let exe = build_ll(&generate_ll(source, OptLevel::O1, 50), "overflow");
+        let output = Command::new(exe).output().unwrap();
+        assert_eq!(output.stderr, b"data stack overflow\n");
+        assert_eq!(output.status.code(), Some(1));
+    }
+}
@@ -1,21 +1,46 @@
 use std::{
+    env,
     fs::{self, File},
+    io::{self, Write},
+    os::unix::fs::PermissionsExt,
     path::Path,
-    process::{Command, ExitCode, ExitStatus},
+    process::{Command, ExitCode},
 };
 
+mod aarch64gen;
 mod analyzer;
+mod backend;
+mod cgen;
 mod command_parser;
 mod compiler;
+mod diagnostic;
+mod dwarf;
+mod elf;
+mod interp;
+mod ir;
 mod lexer;
+mod llvmgen;
+mod mangle;
+mod optimizer;
+mod verify;
+mod watgen;
+mod x86_64asm;
+mod x86_64enc;
 mod x86_64gen;
 
+use analyzer::{Analyzer, Def};
+use command_parser::{CommandResult, Emit, Mode, Target};
+use compiler::{Entry, Instruction, Proc};
+use lexer::Word;
+
 fn main() -> ExitCode {
     use command_parser::CommandParser;
     let command_parser = CommandParser::new();
 
-    let Ok(res) = command_parser.parse_commands() else {
-        return ExitCode::FAILURE;
+    let res = match command_parser.parse_commands() {
+        Ok(command_parser::Command::Compile(res)) => res,
+        Ok(command_parser::Command::Demangle(symbols)) => return demangle(&symbols),
+        Err(()) => return ExitCode::FAILURE,
     };
 
     let source = match fs::read_to_string(&res.file) {
@@ -27,79 +52,410 @@ fn main() -> ExitCode {
         }
     };
 
-    eprintln!("INFO: Compiling `{}`...", res.file.display(),);
-    if compile(&res.file, &source, &res.output_file).is_err() {
+    let color = res.color.enabled_for(&io::stderr());
+    let words = lexer::Lexer::new(&source).collect::<Vec<_>>();
+
+    let is_ir = res.file.extension().is_some_and(|ext| ext == "ir");
+    let (entry, mut procs, string_literals) = if is_ir {
+        match ir::parse(&source, None) {
+            // IR has no signatures to print, so checking it only parses it.
+            Ok(_) if res.mode == Mode::Check => return ExitCode::SUCCESS,
+            Ok(program) => program,
+            Err(e) => {
+                eprintln!("ERROR: {}: {e}", res.file.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        let Ok(defs) = analyze(&res.file, &source, &words, color) else {
+            return ExitCode::FAILURE;
+        };
+
+        if res.mode == Mode::Check {
+            print_signatures(&defs);
+            return ExitCode::SUCCESS;
+        }
+
+        if let Err(err) = analyzer::check_main(&defs) {
+            if let Err(e) =
+                analyzer::report_error(err, &res.file, &source, color, &mut io::stderr())
+            {
+                eprintln!("{e}");
+            }
+            return ExitCode::FAILURE;
+        }
+
+        let (entry, procs, string_literals) = match compiler::Compiler::compile(defs) {
+            Ok(program) => program,
+            Err(err) => {
+                if let Err(e) =
+                    analyzer::report_error(err, &res.file, &source, color, &mut io::stderr())
+                {
+                    eprintln!("{e}");
+                }
+                return ExitCode::FAILURE;
+            }
+        };
+        (
+            entry.expect("`main` is checked by the analyzer"),
+            procs,
+            string_literals,
+        )
+    };
+
+    let verify_ir = res.verify_ir || cfg!(debug_assertions);
+    if verify_ir && verify(&procs, if is_ir { "parsing" } else { "compilation" }).is_err() {
         return ExitCode::FAILURE;
     }
 
-    eprintln!(
-        "INFO: Running `nasm {}.asm -felf64 -o {}.o`",
-        res.output_file.display(),
-        res.output_file.display()
-    );
-    if bomb(
-        Command::new("nasm")
-            .arg(format!("{}.asm", res.output_file.display()))
-            .arg("-felf64")
-            .arg("-o")
-            .arg(format!("{}.o", res.output_file.display()))
-            .status(),
-    ) {
+    optimizer::optimize(&mut procs, res.opt_level);
+    let (entry, procs, string_literals) =
+        optimizer::eliminate_dead_code(entry, procs, string_literals);
+
+    if verify_ir && verify(&procs, "optimization").is_err() {
         return ExitCode::FAILURE;
     }
 
-    eprintln!(
-        "INFO: Running `ld -o {} {}.o`",
-        res.output_file.display(),
-        res.output_file.display()
-    );
-    if bomb(
-        Command::new("ld")
-            .arg("-o")
-            .arg(&res.output_file)
-            .arg(format!("{}.o", res.output_file.display()))
-            .status(),
-    ) {
+    if res.emit == Emit::Ir {
+        let path = format!("{}.ir", res.output_file.display());
+        if write_output(&path, |file| {
+            ir::print(entry, &procs, &string_literals, &source, file)
+        })
+        .is_err()
+        {
+            return ExitCode::FAILURE;
+        }
+
+        eprintln!("INFO: Generated `{path}`");
+        return ExitCode::SUCCESS;
+    }
+
+    if res.mode == (Mode::Run { interp: true }) {
+        // The source file stands in for the executable as the program's name.
+        let args = std::iter::once(res.file.display().to_string())
+            .chain(res.command_line_args.iter().cloned())
+            .collect::<Vec<_>>();
+        let stdout = io::stdout().lock();
+        return match interp::Interpreter::run(
+            entry,
+            &procs,
+            &string_literals,
+            &args,
+            res.stack_slots,
+            io::stdin().lock(),
+            stdout,
+        ) {
+            Ok(code) => ExitCode::from(code as u8),
+            Err(e) => {
+                eprintln!("ERROR: {e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if check_target(&res, &procs).is_err() {
         return ExitCode::FAILURE;
     }
 
+    eprintln!("INFO: Compiling `{}`...", res.file.display(),);
+    let built = match res.target {
+        Target::X86_64 => {
+            // IR carries no spans, so only Zila sources get line info.
+            let source = (!is_ir).then_some(source.as_str());
+            build_x86_64(&res, entry, &procs, &string_literals, source)
+        }
+        Target::Aarch64Linux => build_aarch64(&res, entry, &procs, &string_literals),
+        Target::C => build_c(&res, entry, &procs, &string_literals),
+        Target::Llvm => build_llvm(&res, entry, &procs, &string_literals),
+        Target::Wasm => build_wasm(&res, entry, &procs, &string_literals),
+    };
+    match built {
+        Ok(true) => (),
+        Ok(false) => return ExitCode::SUCCESS,
+        Err(()) => return ExitCode::FAILURE,
+    }
+
     eprintln!("INFO: Generated `./{}`", res.output_file.display());
 
+    if res.mode == (Mode::Run { interp: false }) {
+        let program = Path::new(".").join(&res.output_file);
+        eprintln!("INFO: Running `{}`", program.display());
+        return match Command::new(&program).args(&res.command_line_args).status() {
+            Ok(status) => ExitCode::from(status.code().unwrap_or(1) as u8),
+            Err(e) => {
+                eprintln!("ERROR: cannot run `{}`: {e}", program.display());
+                ExitCode::FAILURE
+            }
+        };
+    }
+
     ExitCode::SUCCESS
 }
 
-fn bomb<E>(r: Result<ExitStatus, E>) -> bool {
-    match r {
-        Err(_) => true,
-        Ok(status) => !status.success(),
+/// Only x86_64 code can make system calls, and it can only call C
+/// functions when it is linked with libc. AArch64 and WebAssembly code
+/// can't read the arguments, the environment or the input.
+fn check_target(res: &CommandResult, procs: &[Proc]) -> Result<(), ()> {
+    let x86_64 = res.target == Target::X86_64;
+    let hosted = matches!(res.target, Target::X86_64 | Target::C | Target::Llvm);
+    for (_, instruction) in procs.iter().flat_map(Proc::code) {
+        match instruction {
+            Instruction::Argc
+            | Instruction::ArgvNth
+            | Instruction::Getenv
+            | Instruction::ReadLine
+            | Instruction::ReadAll
+                if !hosted =>
+            {
+                eprintln!(
+                    "ERROR: `{instruction}` needs `--target=x86_64`, `--target=c` or `--target=llvm`"
+                );
+                return Err(());
+            }
+            Instruction::Syscall { args } if !x86_64 => {
+                eprintln!("ERROR: `syscall{args}` is only supported by `--target=x86_64`");
+                return Err(());
+            }
+            Instruction::Ffi { name, .. } if !(x86_64 && res.link_libc) => {
+                eprintln!(
+                    "ERROR: calling C function `{name}` needs `--link-libc`, which is x86_64 only"
+                );
+                return Err(());
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Writes the executable, or only the assembly with `--emit=asm`, with line
+/// info for `source` if it is given. With `--link-libc`, writes an object
+/// and links it with `$CC` or `cc` instead. Returns whether an executable
+/// was written.
+fn build_x86_64(
+    res: &CommandResult,
+    entry: Entry,
+    procs: &[Proc],
+    string_literals: &[Box<str>],
+    source: Option<&str>,
+) -> Result<bool, ()> {
+    // Register caching reorders stack traffic, so `-O0` keeps every slot in
+    // memory for the most literal translation of the IR.
+    let cached_slots = if res.opt_level == optimizer::OptLevel::O0 {
+        0
+    } else {
+        x86_64gen::CACHED_SLOTS
+    };
+    let mut backend = x86_64gen::X86_64::new(string_literals, cached_slots, res.stack_slots);
+    // Debuggers look the file up from wherever they are run.
+    let file = fs::canonicalize(&res.file).unwrap_or_else(|_| res.file.clone());
+    let file = file.display().to_string();
+    if let Some(source) = source {
+        backend = backend.with_source(&file, source);
+    }
+    if res.link_libc {
+        backend = backend.with_libc();
+    }
+    let assembly = backend::generate(backend, entry, procs);
+
+    if res.emit == Emit::Asm {
+        let path = format!("{}.asm", res.output_file.display());
+        write_output(&path, |file| write!(file, "{assembly}"))?;
+        eprintln!("INFO: Generated `{path}`");
+        return Ok(false);
+    }
+
+    if res.link_libc {
+        let object = x86_64enc::encode_object(&assembly).map_err(report_encode_error)?;
+        let path = format!("{}.o", res.output_file.display());
+        write_output(&path, |file| object.write(file))?;
+        let cc = env::var("CC").unwrap_or_else(|_| "cc".into());
+        return compile_source(&cc, &res.link_args, &path, &res.output_file);
+    }
+
+    let executable = x86_64enc::encode(&assembly).map_err(report_encode_error)?;
+    let path = res.output_file.display().to_string();
+    write_output(&path, |file| {
+        executable.write(file)?;
+        file.set_permissions(fs::Permissions::from_mode(0o755))
+    })?;
+    Ok(true)
+}
+
+fn report_encode_error(e: x86_64enc::UndefinedSymbol) {
+    eprintln!("ERROR: internal compiler error: cannot encode the assembly: {e}")
+}
+
+/// Writes the GNU assembly, then assembles and links it with the
+/// `aarch64-linux-gnu` binutils unless only the assembly was asked for.
+/// Returns whether an executable was written.
+fn build_aarch64(
+    res: &CommandResult,
+    entry: Entry,
+    procs: &[Proc],
+    string_literals: &[Box<str>],
+) -> Result<bool, ()> {
+    let backend = aarch64gen::Aarch64::new(string_literals, res.stack_slots);
+    let assembly = backend::generate(backend, entry, procs);
+    let path = format!("{}.s", res.output_file.display());
+    write_output(&path, |file| write!(file, "{assembly}"))?;
+
+    if res.emit == Emit::Asm {
+        eprintln!("INFO: Generated `{path}`");
+        return Ok(false);
+    }
+
+    let object = format!("{}.o", res.output_file.display());
+    compile_source::<&str>("aarch64-linux-gnu-as", &[], &path, Path::new(&object))?;
+    compile_source::<&str>("aarch64-linux-gnu-ld", &[], &object, &res.output_file)
+}
+
+/// Writes the C source, then builds it with `$CC` or `cc` unless only the
+/// source was asked for. Returns whether an executable was written.
+fn build_c(
+    res: &CommandResult,
+    entry: Entry,
+    procs: &[Proc],
+    string_literals: &[Box<str>],
+) -> Result<bool, ()> {
+    let path = format!("{}.c", res.output_file.display());
+    write_output(&path, |file| {
+        cgen::generate(entry, procs, string_literals, res.stack_slots, file)
+    })?;
+
+    if res.emit == Emit::C {
+        eprintln!("INFO: Generated `{path}`");
+        return Ok(false);
+    }
+
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".into());
+    compile_source(&cc, &["-std=c99", "-O2"], &path, &res.output_file)
+}
+
+/// Writes the LLVM IR, then builds it with `clang` unless only the IR was
+/// asked for. Returns whether an executable was written.
+fn build_llvm(
+    res: &CommandResult,
+    entry: Entry,
+    procs: &[Proc],
+    string_literals: &[Box<str>],
+) -> Result<bool, ()> {
+    let path = format!("{}.ll", res.output_file.display());
+    write_output(&path, |file| {
+        llvmgen::generate(entry, procs, string_literals, res.stack_slots, file)
+    })?;
+
+    if res.emit == Emit::Ll {
+        eprintln!("INFO: Generated `{path}`");
+        return Ok(false);
+    }
+
+    compile_source("clang", &["-O2"], &path, &res.output_file)
+}
+
+/// Writes the WebAssembly text, which is left to the host to load. Never
+/// writes an executable.
+fn build_wasm(
+    res: &CommandResult,
+    entry: Entry,
+    procs: &[Proc],
+    string_literals: &[Box<str>],
+) -> Result<bool, ()> {
+    let path = format!("{}.wat", res.output_file.display());
+    write_output(&path, |file| {
+        watgen::generate(entry, procs, string_literals, res.stack_slots, file)
+    })?;
+    eprintln!("INFO: Generated `{path}`");
+    Ok(false)
+}
+
+/// Runs `compiler` to build `output` from the source `path`. The `flags`
+/// come last, so that they can name libraries for `path` to link with.
+fn compile_source<S: AsRef<str>>(
+    compiler: &str,
+    flags: &[S],
+    path: &str,
+    output: &Path,
+) -> Result<bool, ()> {
+    let mut command_line = format!("{compiler} -o {} {path}", output.display());
+    for flag in flags {
+        command_line.push(' ');
+        command_line.push_str(flag.as_ref());
+    }
+    eprintln!("INFO: Running `{command_line}`");
+    match Command::new(compiler)
+        .arg("-o")
+        .arg(output)
+        .arg(path)
+        .args(flags.iter().map(AsRef::as_ref))
+        .status()
+    {
+        Ok(status) if status.success() => Ok(true),
+        Ok(status) => {
+            eprintln!("ERROR: `{compiler}` failed with {status}");
+            Err(())
+        }
+        Err(e) => {
+            eprintln!("ERROR: cannot run `{compiler}`: {e}");
+            Err(())
+        }
     }
 }
 
-fn compile(path: &Path, source: &str, output_path: &Path) -> Result<(), ()> {
-    use analyzer::Analyzer;
-    use compiler::Compiler;
-    use lexer::Lexer;
+/// Prints the word each symbol was mangled from, or the symbol itself if it
+/// isn't a mangled name. Without symbols, demangles stdin like `c++filt`.
+fn demangle(symbols: &[String]) -> ExitCode {
+    if !symbols.is_empty() {
+        for symbol in symbols {
+            println!("{}", mangle::demangle(symbol).as_deref().unwrap_or(symbol));
+        }
+        return ExitCode::SUCCESS;
+    }
 
-    let words = Lexer::new(source).collect::<Vec<_>>();
-    let defs = match Analyzer::analyze(words.iter().copied()) {
-        Ok(res) => Ok(res),
+    let mut stdout = io::stdout().lock();
+    for line in io::stdin().lines() {
+        let written = line.and_then(|line| writeln!(stdout, "{}", mangle::demangle_text(&line)));
+        if let Err(e) = written {
+            eprintln!("ERROR: cannot demangle stdin: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn analyze<'src>(
+    path: &Path,
+    source: &str,
+    words: &[Word<'src>],
+    color: bool,
+) -> Result<Vec<Def<'src>>, ()> {
+    match Analyzer::analyze(words.iter().copied()) {
+        Ok(defs) => Ok(defs),
         Err(err) => Err(
-            analyzer::report_error(err, path, source, &mut std::io::stderr())
+            analyzer::report_error(err, path, source, color, &mut io::stderr())
                 .map_err(|e| eprintln!("{e}"))?,
         ),
-    }?;
+    }
+}
 
-    let (main_proc, procs, string_literals) = Compiler::compile(defs);
+fn verify(procs: &[compiler::Proc], stage: &str) -> Result<(), ()> {
+    verify::verify(procs).map_err(|e| {
+        eprintln!("ERROR: internal compiler error: IR fails verification after {stage}: {e}")
+    })
+}
 
-    let mut file =
-        File::create(format!("{}.asm", output_path.display())).map_err(|e| eprintln!("{e}"))?;
-    x86_64gen::Generator::generate(
-        main_proc.expect("no `main`"),
-        &procs,
-        &string_literals,
-        &mut file,
-    )
-    .map_err(|e| eprintln!("{e}"))?;
+fn print_signatures(defs: &[Def]) {
+    for def in defs {
+        match def {
+            Def::WordDef { name, ty, .. } | Def::Ffi { name, ty, .. } => {
+                println!("{name} {}", ty.canonicalize())
+            }
+        }
+    }
+}
 
-    Ok(())
+fn write_output(path: &str, write: impl FnOnce(&mut File) -> io::Result<()>) -> Result<(), ()> {
+    let mut file =
+        File::create(path).map_err(|e| eprintln!("ERROR: cannot create `{path}`: {e}"))?;
+    write(&mut file).map_err(|e| eprintln!("ERROR: cannot write `{path}`: {e}"))
 }
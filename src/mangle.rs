@@ -0,0 +1,146 @@
+//! Symbol names for procs that any assembler, linker or C compiler accepts
+//! and that can be turned back into the word they came from.
+//!
+//! A proc is `zila_<id>` if it is a quotation, or `zila_<id>_<word>` with
+//! every character of the word other than an ASCII letter or digit written
+//! as its code point in lowercase hex between underscores: `2dup` is
+//! `zila_4_2dup` and `string>` is `zila_5_string_3e_`.
+
+use std::fmt::{self, Write};
+
+use crate::compiler::Label;
+
+const PREFIX: &str = "zila_";
+
+impl fmt::Display for Label<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{PREFIX}{}", self.id())?;
+        let Some(name) = self.name() else {
+            return Ok(());
+        };
+
+        f.write_char('_')?;
+        for c in name.chars() {
+            if c.is_ascii_alphanumeric() {
+                f.write_char(c)?;
+            } else {
+                write!(f, "_{:x}_", c as u32)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Turns a symbol back into the word it was mangled from, or `[quotation
+/// <id>]`. Returns `None` if it isn't a mangled proc name.
+pub fn demangle(symbol: &str) -> Option<String> {
+    let rest = symbol.strip_prefix(PREFIX)?;
+    let (id, name) = match rest.split_once('_') {
+        Some((id, name)) => (id, Some(name)),
+        None => (rest, None),
+    };
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let Some(name) = name else {
+        return Some(format!("[quotation {id}]"));
+    };
+
+    let mut word = String::new();
+    let mut rest = name;
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_alphanumeric() {
+            word.push(c);
+            rest = &rest[1..];
+            continue;
+        }
+
+        let (hex, after) = rest.strip_prefix('_')?.split_once('_')?;
+        // Only the shortest lowercase spelling is ever written, so there is
+        // exactly one mangled name for each word.
+        let c = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)?;
+        if c.is_ascii_alphanumeric() || format!("{:x}", c as u32) != hex {
+            return None;
+        }
+        word.push(c);
+        rest = after;
+    }
+    (!word.is_empty()).then_some(word)
+}
+
+/// Demangles every proc name in `text`, leaving the rest as it is.
+pub fn demangle_text(text: &str) -> String {
+    let is_symbol_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(is_symbol_char) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find(|c| !is_symbol_char(c)).unwrap_or(rest.len());
+        let symbol = &rest[..end];
+        match demangle(symbol) {
+            Some(word) => out.push_str(&word),
+            None => out.push_str(symbol),
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        let names = ["2dup", ">string", "string>", "is-even?", "a_b", "λ", "main"];
+        for (id, name) in names.into_iter().enumerate() {
+            let symbol = Label::new(id, Some(name)).to_string();
+            assert!(
+                symbol
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                "{symbol}"
+            );
+            assert_eq!(demangle(&symbol).as_deref(), Some(name));
+        }
+
+        assert_eq!(
+            Label::new(1, Some(">string")).to_string(),
+            "zila_1__3e_string"
+        );
+        assert_eq!(
+            Label::new(2, Some("string>")).to_string(),
+            "zila_2_string_3e_"
+        );
+        assert_eq!(Label::new(7, None).to_string(), "zila_7");
+        assert_eq!(demangle("zila_7").as_deref(), Some("[quotation 7]"));
+    }
+
+    #[test]
+    fn only_mangled_names_demangle() {
+        for symbol in [
+            "_start",
+            "zila_",
+            "zila_x_foo",
+            "zila_1_",
+            "zila_1_a_3e",
+            "zila_1__3E_",
+            "zila_1__03e_",
+            "zila_1__61_",
+            "zila_1__110000_",
+        ] {
+            assert_eq!(demangle(symbol), None, "{symbol}");
+        }
+    }
+
+    #[test]
+    fn text_keeps_everything_but_the_symbols() {
+        assert_eq!(
+            demangle_text("#0  0x401075 in zila_0_square () at t.zila:1\n  data_stack+8"),
+            "#0  0x401075 in square () at t.zila:1\n  data_stack+8"
+        );
+        assert_eq!(demangle_text("zila_3_2dup/zila_4"), "2dup/[quotation 4]");
+    }
+}
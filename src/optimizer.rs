@@ -0,0 +1,480 @@
+//! Rewrites over `Proc::code` that run between `Compiler::compile` and code
+//! generation.
+
+use crate::{
+    compiler::{Entry, Instruction, Label, Proc},
+    lexer::Span,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+}
+
+impl OptLevel {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "0" => Some(Self::O0),
+            "1" => Some(Self::O1),
+            "2" => Some(Self::O2),
+            _ => None,
+        }
+    }
+}
+
+/// Procs with at most this many instructions are inlined at `-O2`.
+const INLINE_THRESHOLD: usize = 8;
+
+/// How many times inlining is repeated, so that small words built from
+/// small words flatten without unbounded growth.
+const INLINE_ROUNDS: usize = 3;
+
+pub fn optimize(procs: &mut [Proc], level: OptLevel) {
+    if level == OptLevel::O0 {
+        return;
+    }
+
+    simplify_all(procs);
+
+    if level >= OptLevel::O2 {
+        for _ in 0..INLINE_ROUNDS {
+            inline(procs);
+            simplify_all(procs);
+        }
+    }
+}
+
+fn simplify_all(procs: &mut [Proc]) {
+    for proc in procs.iter_mut() {
+        let code = std::mem::take(proc.code_mut());
+        *proc.code_mut() = simplify(code);
+    }
+}
+
+/// Replaces calls to small procs with their bodies. A proc is never inlined
+/// into itself, and procs that call themselves directly are left alone.
+fn inline(procs: &mut [Proc]) {
+    let bodies = procs
+        .iter()
+        .map(|proc| proc.code().to_vec())
+        .collect::<Vec<_>>();
+
+    let inlinable = |id: usize, caller: usize| {
+        let body = &bodies[id];
+        id != caller
+            && body.len() <= INLINE_THRESHOLD
+            && !body
+                .iter()
+                .any(|(_, instruction)| matches!(instruction, Instruction::Call(l) if l.id() == id))
+    };
+
+    for (caller, proc) in procs.iter_mut().enumerate() {
+        let code = std::mem::take(proc.code_mut());
+        let mut inlined = Vec::with_capacity(code.len());
+
+        for (span, instruction) in code {
+            match instruction {
+                Instruction::Call(label) if inlinable(label.id(), caller) => {
+                    inlined.extend_from_slice(&bodies[label.id()])
+                }
+                _ => inlined.push((span, instruction)),
+            }
+        }
+
+        *proc.code_mut() = inlined;
+    }
+}
+
+/// Drops every proc that cannot be reached from `entry` through `Call` or
+/// `PushQuote`, and every string literal those procs don't push. The
+/// survivors are renumbered in their original order.
+pub fn eliminate_dead_code<'src>(
+    entry: Entry<'src>,
+    procs: Vec<Proc<'src>>,
+    string_literals: Vec<Box<str>>,
+) -> (Entry<'src>, Vec<Proc<'src>>, Vec<Box<str>>) {
+    let mut live_procs = vec![false; procs.len()];
+    let mut live_strings = vec![false; string_literals.len()];
+
+    let mut worklist = vec![entry.label().id()];
+    live_procs[entry.label().id()] = true;
+    while let Some(id) = worklist.pop() {
+        for (_, instruction) in procs[id].code() {
+            match instruction {
+                Instruction::Call(label) | Instruction::PushQuote(label)
+                    if !live_procs[label.id()] =>
+                {
+                    live_procs[label.id()] = true;
+                    worklist.push(label.id());
+                }
+                Instruction::PushString(i) => live_strings[*i] = true,
+                _ => (),
+            }
+        }
+    }
+
+    let renumber = |live: &[bool]| {
+        live.iter()
+            .scan(0, |next, &live| {
+                let id = *next;
+                *next += live as usize;
+                Some(id)
+            })
+            .collect::<Vec<_>>()
+    };
+    let proc_ids = renumber(&live_procs);
+    let string_ids = renumber(&live_strings);
+    let relabel = |label: Label<'src>| Label::new(proc_ids[label.id()], label.name());
+
+    let procs = procs
+        .into_iter()
+        .zip(&live_procs)
+        .filter(|(_, live)| **live)
+        .map(|(proc, _)| {
+            let mut relabeled = Proc::new(relabel(proc.label()), proc.effect());
+            for &(span, instruction) in proc.code() {
+                relabeled.push(
+                    span,
+                    match instruction {
+                        Instruction::Call(label) => Instruction::Call(relabel(label)),
+                        Instruction::PushQuote(label) => Instruction::PushQuote(relabel(label)),
+                        Instruction::PushString(i) => Instruction::PushString(string_ids[i]),
+                        instruction => instruction,
+                    },
+                );
+            }
+            relabeled
+        })
+        .collect();
+
+    let string_literals = string_literals
+        .into_iter()
+        .zip(live_strings)
+        .filter_map(|(s, live)| live.then_some(s))
+        .collect();
+
+    (
+        Entry::new(relabel(entry.label()), entry.returns_exit_code()),
+        procs,
+        string_literals,
+    )
+}
+
+/// The number of slots pushed by an instruction that only pushes a
+/// constant, or `None` for anything else.
+fn push_size(instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::PushInt(_) | Instruction::PushBool(_) | Instruction::PushQuote(_) => Some(1),
+        Instruction::PushString(_) => Some(2),
+        _ => None,
+    }
+}
+
+fn fold<'src>(a: isize, b: isize, op: Instruction) -> Option<Instruction<'src>> {
+    match op {
+        Instruction::Add => Some(Instruction::PushInt(a.wrapping_add(b))),
+        Instruction::Sub => Some(Instruction::PushInt(a.wrapping_sub(b))),
+        Instruction::Mul => Some(Instruction::PushInt(a.wrapping_mul(b))),
+        // Division by zero and `isize::MIN / -1` are left for the program to
+        // fail on at run time.
+        Instruction::Div => a.checked_div(b).map(Instruction::PushInt),
+        Instruction::Eq => Some(Instruction::PushBool(a == b)),
+        Instruction::Lt => Some(Instruction::PushBool(a < b)),
+        _ => None,
+    }
+}
+
+/// Runs the peephole rules over `code`. Each instruction is appended to the
+/// output and the rules are retried on the new tail until none applies, so
+/// one rewrite can expose the next, as in `2 3 + 4 *`.
+fn simplify<'src>(code: Vec<(Span, Instruction<'src>)>) -> Vec<(Span, Instruction<'src>)> {
+    let mut out = Vec::with_capacity(code.len());
+
+    for (span, instruction) in code {
+        out.push((span, instruction));
+        while reduce(&mut out) {}
+
+        // Nothing after an `exit` can run.
+        if matches!(instruction, Instruction::Exit) {
+            break;
+        }
+    }
+
+    out
+}
+
+fn reduce(out: &mut Vec<(Span, Instruction)>) -> bool {
+    use Instruction::*;
+
+    let (len, replacement) = match out.as_slice() {
+        [.., (_, PushInt(a)), (_, PushInt(b)), (span, op)] if fold(*a, *b, *op).is_some() => {
+            (3, vec![(*span, fold(*a, *b, *op).unwrap())])
+        }
+        [.., (_, PushInt(0)), (_, Add | Sub)] | [.., (_, PushInt(1)), (_, Mul | Div)] => {
+            (2, vec![])
+        }
+
+        [.., (_, Dup { size: a }), (_, Drop { size: b })] if a == b => (2, vec![]),
+        [
+            ..,
+            (_, Swap { size_a, size_b }),
+            (
+                _,
+                Swap {
+                    size_a: c,
+                    size_b: d,
+                },
+            ),
+        ] if size_a == d && size_b == c => (2, vec![]),
+        [.., (_, Over { size_b, .. }), (_, Drop { size })] if size_b == size => (2, vec![]),
+        [.., (_, push), (_, Drop { size })] if push_size(push) == Some(*size) => (2, vec![]),
+
+        [.., (_, PushQuote(q)), (span, Apply)] => (2, vec![(*span, Call(*q))]),
+
+        [.., (_, push), (span, Dup { size })] if push_size(push) == Some(*size) => {
+            (1, vec![(*span, *push)])
+        }
+        [.., a @ (_, x), b @ (_, y), (_, Swap { size_a, size_b })]
+            if push_size(y) == Some(*size_a) && push_size(x) == Some(*size_b) =>
+        {
+            (3, vec![*b, *a])
+        }
+        [.., a @ (_, x), b @ (_, y), (span, Over { size_a, size_b })]
+            if push_size(y) == Some(*size_a) && push_size(x) == Some(*size_b) =>
+        {
+            (3, vec![*a, *b, (*span, *x)])
+        }
+
+        [
+            ..,
+            (_, PushBool(cond)),
+            t @ (_, on_true),
+            f @ (_, on_false),
+            (_, Branch { size }),
+        ] if push_size(on_true) == Some(*size) && push_size(on_false) == Some(*size) => {
+            (4, vec![if *cond { *t } else { *f }])
+        }
+
+        _ => return false,
+    };
+
+    out.truncate(out.len() - len);
+    out.extend(replacement);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{
+        analyzer::Analyzer,
+        compiler::{Compiler, DATA_STACK_SLOTS},
+        interp::Interpreter,
+        ir,
+        lexer::Lexer,
+    };
+
+    fn optimized(text: &str) -> String {
+        optimized_at(text, OptLevel::O1)
+    }
+
+    fn optimized_at(text: &str, level: OptLevel) -> String {
+        let (entry, mut procs, _) = ir::parse(text, None).unwrap();
+        optimize(&mut procs, level);
+
+        procs[entry.label().id()]
+            .code()
+            .iter()
+            .map(|(_, instruction)| instruction.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn folds_constants() {
+        let text = "entry @0
+            proc @0
+                push-int 2
+                push-int 3
+                add
+                push-int 4
+                mul
+                push-int 1
+                mul";
+        assert_eq!(optimized(text), "push-int 20");
+    }
+
+    #[test]
+    fn removes_no_op_shuffles() {
+        let text = "entry @0
+            proc @0
+                call @0
+                dup 2
+                drop 2
+                swap 1 2
+                swap 2 1
+                over 1 2
+                drop 2
+                push-string 0
+                drop 2";
+        assert_eq!(optimized(&format!("{text}\nstring 0 \"\"")), "call @0");
+    }
+
+    #[test]
+    fn simplifies_known_branches() {
+        let text = "entry @0
+            proc @0
+                push-bool false
+                push-quote @1
+                push-quote @2
+                branch 1
+                apply
+            proc @1
+            proc @2";
+        assert_eq!(optimized(text), "call @2");
+
+        let text = "entry @0
+            proc @0
+                push-int 2
+                push-int 3
+                lt
+                push-int 4
+                push-int 5
+                branch 1";
+        assert_eq!(optimized(text), "push-int 4");
+    }
+
+    #[test]
+    fn keeps_division_by_zero_and_drops_code_after_exit() {
+        let text = "entry @0
+            proc @0
+                push-int 1
+                push-int 0
+                div
+                exit
+                push-int 3";
+        assert_eq!(optimized(text), "push-int 1\npush-int 0\ndiv\nexit");
+    }
+
+    #[test]
+    fn keeps_overflowing_division() {
+        let text = format!(
+            "entry @0
+            proc @0
+                push-int {}
+                push-int -1
+                div",
+            isize::MIN
+        );
+        assert_eq!(
+            optimized(&text),
+            format!("push-int {}\npush-int -1\ndiv", isize::MIN)
+        );
+    }
+
+    #[test]
+    fn inlines_small_words_and_quotations() {
+        let text = "entry @0
+            proc @0
+                push-int 3
+                call @1
+                push-quote @2
+                apply
+            proc @1
+                dup 1
+                mul
+            proc @2
+                push-int 1
+                add";
+        assert_eq!(optimized_at(text, OptLevel::O2), "push-int 10");
+    }
+
+    #[test]
+    fn does_not_inline_recursion() {
+        let text = "entry @0
+            proc @0
+                call @1
+            proc @1
+                call @1";
+        assert_eq!(optimized_at(text, OptLevel::O2), "call @1");
+    }
+
+    #[test]
+    fn removes_unreachable_procs_and_strings() {
+        let text = "entry @2:main
+            string 0 \"unused\"
+            string 1 \"used\"
+            proc @0:dead
+                push-string 0
+                puts
+            proc @1:helper
+                push-string 1
+                puts
+            proc @2:main
+                call @1:helper
+                push-quote @3
+                apply
+            proc @3
+            proc @4
+                call @0:dead";
+        let (entry, procs, strings) = ir::parse(text, None).unwrap();
+        let (entry, procs, strings) = eliminate_dead_code(entry, procs, strings);
+
+        let mut out = Vec::new();
+        ir::print(entry, &procs, &strings, "", &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "entry @1:main
+string 0 \"used\"
+
+proc @0:helper
+    1:1     push-string 0
+    1:1     puts
+
+proc @1:main
+    1:1     call @0:helper
+    1:1     push-quote @2
+    1:1     apply
+
+proc @2
+"
+        );
+    }
+
+    #[test]
+    fn optimized_programs_behave_the_same() {
+        let programs = [
+            ": main 2 3 + dup * 1 swap - ;",
+            ": main \"a\" 1 2 over swap drop + swap puts ;",
+            ": main true [ \"t\" ] [ \"f\" ] ? apply puts 9 [ 1 + ] apply ;",
+            ": sq dup * ; : main 3 [ sq ] apply sq [ 2 + ] apply ;",
+        ];
+
+        for source in programs {
+            let run = |level| {
+                let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+                let (entry, mut procs, strings) = Compiler::compile(defs).unwrap();
+                optimize(&mut procs, level);
+
+                let mut out = Vec::new();
+                let code = Interpreter::run(
+                    entry.unwrap(),
+                    &procs,
+                    &strings,
+                    &[],
+                    DATA_STACK_SLOTS,
+                    io::empty(),
+                    &mut out,
+                )
+                .unwrap();
+                (code, out)
+            };
+            assert_eq!(run(OptLevel::O0), run(OptLevel::O1), "{source}");
+            assert_eq!(run(OptLevel::O0), run(OptLevel::O2), "{source}");
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Checks that every `Proc` with a known `Effect` leaves the data stack the
+//! way the effect says, by following the depth of the stack through its code.
+//!
+//! Quotations are tracked while they sit on the stack, so `apply` can be
+//! checked against the effect of the proc it runs. Once the verifier can't
+//! tell what an `apply` or `call` does to the stack, it stops checking the
+//! rest of that proc.
+
+use std::fmt;
+
+use crate::{
+    compiler::{Effect, Instruction, Label, Proc},
+    ir::IrLabel,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Unknown,
+    Quote(Effect),
+}
+
+#[derive(Debug)]
+pub struct VerifyError<'src> {
+    proc: Label<'src>,
+    effect: Effect,
+    /// The offending instruction and its index, or `None` if the proc
+    /// ends with the wrong number of slots.
+    at: Option<(usize, Instruction<'src>)>,
+    message: String,
+}
+
+impl fmt::Display for VerifyError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Effect { inputs, outputs } = self.effect;
+        write!(f, "proc {} ( {inputs} -- {outputs} )", IrLabel(self.proc))?;
+        if let Some((index, instruction)) = self.at {
+            write!(f, ", instruction {index} `{instruction}`")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+pub fn verify<'src>(procs: &[Proc<'src>]) -> Result<(), VerifyError<'src>> {
+    for proc in procs {
+        if let Some(effect) = proc.effect() {
+            verify_proc(procs, proc, effect)?;
+        }
+    }
+    Ok(())
+}
+
+fn verify_proc<'src>(
+    procs: &[Proc<'src>],
+    proc: &Proc<'src>,
+    effect: Effect,
+) -> Result<(), VerifyError<'src>> {
+    let error = |at, message| VerifyError {
+        proc: proc.label(),
+        effect,
+        at,
+        message,
+    };
+    let effect_of = |id: usize| procs.get(id).and_then(Proc::effect);
+
+    let mut stack = vec![Value::Unknown; effect.inputs];
+    for (index, &(_, instruction)) in proc.code().iter().enumerate() {
+        let mut take = |n: usize| {
+            if n > stack.len() {
+                return Err(error(
+                    Some((index, instruction)),
+                    format!("reads {n} slots, but only {} are on the stack", stack.len()),
+                ));
+            }
+            Ok(stack.split_off(stack.len() - n))
+        };
+
+        match instruction {
+            Instruction::PushInt(_) | Instruction::PushBool(_) => stack.push(Value::Unknown),
+            Instruction::PushString(_) => stack.extend([Value::Unknown; 2]),
+            Instruction::PushQuote(label) => stack.push(match effect_of(label.id()) {
+                Some(effect) => Value::Quote(effect),
+                None => Value::Unknown,
+            }),
+
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::Eq
+            | Instruction::Lt => {
+                take(2)?;
+                stack.push(Value::Unknown);
+            }
+
+            // Nothing after `exit` runs, so the proc's effect doesn't apply.
+            Instruction::Exit => {
+                take(1)?;
+                return Ok(());
+            }
+
+            Instruction::Puts => {
+                take(2)?;
+            }
+            Instruction::Argc => stack.push(Value::Unknown),
+            Instruction::ArgvNth => {
+                take(1)?;
+                stack.extend([Value::Unknown; 2]);
+            }
+            Instruction::Getenv => {
+                take(2)?;
+                stack.extend([Value::Unknown; 3]);
+            }
+            Instruction::ReadLine => stack.extend([Value::Unknown; 3]),
+            Instruction::ReadAll => stack.extend([Value::Unknown; 2]),
+            Instruction::Syscall { args } => {
+                take(args + 1)?;
+                stack.push(Value::Unknown);
+            }
+            Instruction::Ffi {
+                inputs, outputs, ..
+            } => {
+                take(inputs)?;
+                stack.extend(vec![Value::Unknown; outputs]);
+            }
+
+            Instruction::Dup { size } => {
+                let a = take(size)?;
+                stack.extend(&a);
+                stack.extend(a);
+            }
+            Instruction::Swap { size_a, size_b } => {
+                let a = take(size_a)?;
+                let b = take(size_b)?;
+                stack.extend(a);
+                stack.extend(b);
+            }
+            Instruction::Drop { size } => {
+                take(size)?;
+            }
+            Instruction::Over { size_a, size_b } => {
+                let a = take(size_a)?;
+                let b = take(size_b)?;
+                stack.extend(&b);
+                stack.extend(a);
+                stack.extend(b);
+            }
+
+            Instruction::Branch { size } => {
+                let f = take(size)?;
+                let t = take(size)?;
+                take(1)?;
+                stack.extend(
+                    t.into_iter()
+                        .zip(f)
+                        .map(|(t, f)| if t == f { t } else { Value::Unknown }),
+                );
+            }
+
+            Instruction::Apply | Instruction::Call(_) => {
+                let callee = match instruction {
+                    Instruction::Call(label) => effect_of(label.id()),
+                    _ => match take(1)?[..] {
+                        [Value::Quote(effect)] => Some(effect),
+                        _ => None,
+                    },
+                };
+                let Some(callee) = callee else {
+                    return Ok(());
+                };
+
+                take(callee.inputs)?;
+                stack.extend(std::iter::repeat_n(Value::Unknown, callee.outputs));
+            }
+        }
+    }
+
+    if stack.len() != effect.outputs {
+        return Err(error(
+            None,
+            format!("ends with {} slots on the stack", stack.len()),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        analyzer::Analyzer,
+        compiler::Compiler,
+        ir,
+        lexer::Lexer,
+        optimizer::{self, OptLevel},
+    };
+
+    fn verify_ir(text: &str) -> Result<(), String> {
+        let (_, procs, _) = ir::parse(text, None).unwrap();
+        verify(&procs).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn compiled_programs_verify() {
+        let source = "
+            : square dup * ;
+            : greet puts ;
+            : pick [ 1 ] [ 2 ] ? apply ;
+            : countdown dup 0 = [ drop ] [ 1 - countdown ] ? apply ;
+            : main \"hi\\n\" dup greet 7 swap puts 3 square true pick + over drop 10 countdown ;
+        ";
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+            let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+            let (_, mut procs, _) = Compiler::compile(defs).unwrap();
+            assert!(procs.iter().all(|proc| proc.effect().is_some()));
+            verify(&procs).unwrap();
+
+            optimizer::optimize(&mut procs, level);
+            verify(&procs).unwrap();
+        }
+    }
+
+    #[test]
+    fn wrong_shuffle_sizes_are_caught() {
+        let text = "
+            entry @0:main
+            string 0 \"a\"
+            proc @0:main ( 0 -- 5 )
+                push-string 0
+                push-int 1
+                over 1 1
+        ";
+        assert_eq!(
+            verify_ir(text).unwrap_err(),
+            "proc @0:main ( 0 -- 5 ): ends with 4 slots on the stack"
+        );
+        assert!(verify_ir(&text.replace("over 1 1", "over 1 2")).is_ok());
+
+        assert_eq!(
+            verify_ir(&text.replace("over 1 1", "swap 1 3")).unwrap_err(),
+            "proc @0:main ( 0 -- 5 ), instruction 2 `swap 1 3`: \
+             reads 3 slots, but only 2 are on the stack"
+        );
+    }
+
+    #[test]
+    fn procs_cannot_read_below_their_inputs() {
+        let text = "
+            entry @0:main
+            proc @0:main ( 0 -- 1 )
+                push-int 2
+                call @1
+            proc @1 ( 1 -- 1 )
+                add
+        ";
+        assert_eq!(
+            verify_ir(text).unwrap_err(),
+            "proc @1 ( 1 -- 1 ), instruction 0 `add`: reads 2 slots, but only 1 are on the stack"
+        );
+    }
+
+    #[test]
+    fn quotations_are_checked_where_they_are_applied() {
+        let text = "
+            entry @0:main
+            proc @0:main ( 0 -- 1 )
+                push-int 2
+                push-bool true
+                push-quote @1
+                push-quote @2
+                branch 1
+                apply
+            proc @1 ( 2 -- 1 )
+                add
+            proc @2 ( 2 -- 1 )
+                mul
+        ";
+        assert_eq!(
+            verify_ir(text).unwrap_err(),
+            "proc @0:main ( 0 -- 1 ), instruction 5 `apply`: \
+             reads 2 slots, but only 1 are on the stack"
+        );
+        assert!(verify_ir(&text.replace("push-int 2", "push-int 2\npush-int 3")).is_ok());
+
+        // Without an effect for @2 the branches disagree, so `main` is only
+        // checked up to `apply`.
+        assert!(verify_ir(&text.replace("proc @2 ( 2 -- 1 )", "proc @2")).is_ok());
+    }
+}
@@ -0,0 +1,674 @@
+//! Translates the IR to the WebAssembly text format, for running zila
+//! programs in browsers and other wasm hosts.
+//!
+//! The data stack lives in linear memory after the string literals, with
+//! the same slot layout as the other backends, and `$sp` holds the address
+//! of the first free slot. A quotation is the index of its proc in the
+//! function table. Tail calls aren't available everywhere yet, so every
+//! proc returns the index of the proc to tail call, or -1, and `$run` calls
+//! them in a loop.
+//!
+//! The host provides `env.puts(ptr, len)` and `env.exit(code)`, which must
+//! not return, and calls the exported `main`, which returns the exit code.
+//! Running out of data stack traps with `unreachable`.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use crate::{
+    compiler::{self, Entry, Instruction, Label, Proc},
+    lexer::Span,
+};
+
+const PAGE_SIZE: usize = 0x1_0000;
+
+const RUN: &str = "  (func $run (param $next i32)
+    block $done
+      loop $again
+        local.get $next
+        i32.const -1
+        i32.eq
+        br_if $done
+        local.get $next
+        call_indirect (type $proc)
+        local.set $next
+        br $again
+      end
+    end)
+";
+
+/// The WAT identifier of `label`, its mangled name.
+fn wat_name(label: Label) -> String {
+    format!("${label}")
+}
+
+/// A WAT string with the bytes of `s`.
+fn wat_string(s: &str) -> String {
+    let mut literal = String::from("\"");
+    for b in s.bytes() {
+        if (b.is_ascii_graphic() || b == b' ') && b != b'"' && b != b'\\' {
+            literal.push(b as char);
+        } else {
+            literal.push_str(&format!("\\{b:02x}"));
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Writes the program as a WAT module whose data stack holds `stack_slots`
+/// slots.
+pub fn generate(
+    entry: Entry,
+    procs: &[Proc],
+    string_literals: &[Box<str>],
+    stack_slots: usize,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut strings = Vec::new();
+    let mut stack_base = 0;
+    for string_literal in string_literals {
+        strings.push(stack_base);
+        stack_base += string_literal.len();
+    }
+    let stack_base = stack_base.next_multiple_of(8);
+    let stack_end = stack_base + 8 * stack_slots;
+
+    // Quotations and calls refer to procs by their index in the table.
+    let table = procs
+        .iter()
+        .enumerate()
+        .map(|(i, proc)| (proc.label(), i))
+        .collect::<HashMap<_, _>>();
+
+    writeln!(out, "(module")?;
+    writeln!(out, "  (type $proc (func (result i32)))")?;
+    writeln!(
+        out,
+        "  (import \"env\" \"puts\" (func $puts (param i32 i32)))"
+    )?;
+    writeln!(out, "  (import \"env\" \"exit\" (func $exit (param i32)))")?;
+    writeln!(
+        out,
+        "  (memory (export \"memory\") {})",
+        stack_end.div_ceil(PAGE_SIZE).max(1)
+    )?;
+    for (string_literal, address) in string_literals.iter().zip(&strings) {
+        writeln!(
+            out,
+            "  (data (i32.const {address}) {})",
+            wat_string(string_literal)
+        )?;
+    }
+    writeln!(out, "  (global $sp (mut i32) (i32.const {stack_base}))")?;
+    writeln!(out, "  (table {} funcref)", procs.len())?;
+    write!(out, "  (elem (i32.const 0) func")?;
+    for proc in procs {
+        write!(out, " {}", wat_name(proc.label()))?;
+    }
+    writeln!(out, ")")?;
+
+    writeln!(out)?;
+    write!(out, "{RUN}")?;
+
+    let generator = Generator {
+        string_literals,
+        strings: &strings,
+        table: &table,
+        stack_base,
+        stack_end,
+    };
+    for proc in procs {
+        writeln!(out)?;
+        generator.gen_proc(proc, out)?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "  (func (export \"main\") (result i32)")?;
+    writeln!(out, "    i32.const {}", table[&entry.label()])?;
+    writeln!(out, "    call $run")?;
+    if entry.returns_exit_code() {
+        writeln!(out, "    global.get $sp")?;
+        writeln!(out, "    i32.const 8")?;
+        writeln!(out, "    i32.sub")?;
+        writeln!(out, "    i64.load")?;
+        writeln!(out, "    i32.wrap_i64)")?;
+    } else {
+        writeln!(out, "    i32.const 0)")?;
+    }
+    writeln!(out, ")")
+}
+
+struct Generator<'a, 'src> {
+    string_literals: &'a [Box<str>],
+    /// The address of each string literal.
+    strings: &'a [usize],
+    table: &'a HashMap<Label<'src>, usize>,
+    stack_base: usize,
+    stack_end: usize,
+}
+
+impl Generator<'_, '_> {
+    fn gen_proc(&self, proc: &Proc, out: &mut impl Write) -> io::Result<()> {
+        write!(out, "  (func {} (type $proc)", wat_name(proc.label()))?;
+        let instructions = proc.code();
+        // `swap` goes through locals, so there must be one per slot.
+        let locals = instructions
+            .iter()
+            .map(|(_, instruction)| match instruction {
+                Instruction::Swap { size_a, size_b } => size_a + size_b,
+                _ => 0,
+            })
+            .max()
+            .unwrap_or(0);
+        for i in 0..locals {
+            write!(out, " (local $t{i} i64)")?;
+        }
+        writeln!(out)?;
+
+        self.gen_stack_check(instructions, out)?;
+        for (i, &(span, instruction)) in instructions.iter().enumerate() {
+            writeln!(out, "    ;; {span:?} -- {instruction}")?;
+            let tail = i + 1 == instructions.len();
+            self.gen_instruction(instruction, tail, out)?;
+
+            if !tail && matches!(instruction, Instruction::Call(_) | Instruction::Apply) {
+                self.gen_stack_check(&instructions[i + 1..], out)?;
+            }
+        }
+
+        if !matches!(
+            instructions.last(),
+            Some((_, Instruction::Call(_) | Instruction::Apply))
+        ) {
+            writeln!(out, "    i32.const -1")?;
+        }
+        writeln!(out, "  )")
+    }
+
+    /// Checks that the stack can take what `instructions` do to it up to
+    /// the next call.
+    fn gen_stack_check(
+        &self,
+        instructions: &[(Span, Instruction)],
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        let (below, above) = compiler::segment_bounds(instructions);
+        if below > 0 {
+            writeln!(out, "    global.get $sp")?;
+            writeln!(out, "    i32.const {}", self.stack_base + 8 * below)?;
+            writeln!(out, "    i32.lt_u")?;
+            writeln!(out, "    if")?;
+            writeln!(out, "      unreachable")?;
+            writeln!(out, "    end")?;
+        }
+        if above > 0 {
+            writeln!(out, "    i32.const {}", self.stack_end)?;
+            writeln!(out, "    global.get $sp")?;
+            writeln!(out, "    i32.sub")?;
+            writeln!(out, "    i32.const {}", 8 * above)?;
+            writeln!(out, "    i32.lt_u")?;
+            writeln!(out, "    if")?;
+            writeln!(out, "      unreachable")?;
+            writeln!(out, "    end")?;
+        }
+        Ok(())
+    }
+
+    fn gen_instruction(
+        &self,
+        instruction: Instruction,
+        tail: bool,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        match instruction {
+            Instruction::PushInt(i) => gen_push(&[i as i64], out),
+            Instruction::PushBool(b) => gen_push(&[-(b as i64)], out),
+            Instruction::PushString(i) => gen_push(
+                &[self.strings[i] as i64, self.string_literals[i].len() as i64],
+                out,
+            ),
+            Instruction::PushQuote(q) => gen_push(&[self.table[&q] as i64], out),
+
+            Instruction::Add => gen_binary(&["i64.add"], out),
+            Instruction::Sub => gen_binary(&["i64.sub"], out),
+            Instruction::Mul => gen_binary(&["i64.mul"], out),
+            // Traps on division by zero and overflow, like `idiv`.
+            Instruction::Div => gen_binary(&["i64.div_s"], out),
+            // Comparisons give 0 or 1, which is negated into a bool.
+            Instruction::Eq => gen_binary(
+                &["i64.eq", "i64.extend_i32_u", "i64.const -1", "i64.mul"],
+                out,
+            ),
+            Instruction::Lt => gen_binary(
+                &["i64.lt_s", "i64.extend_i32_u", "i64.const -1", "i64.mul"],
+                out,
+            ),
+
+            Instruction::Exit => {
+                gen_load(-1, out)?;
+                writeln!(out, "    i32.wrap_i64")?;
+                gen_move_sp(-1, out)?;
+                writeln!(out, "    call $exit")?;
+                writeln!(out, "    unreachable")
+            }
+
+            Instruction::Puts => {
+                gen_load(-2, out)?;
+                writeln!(out, "    i32.wrap_i64")?;
+                gen_load(-1, out)?;
+                writeln!(out, "    i32.wrap_i64")?;
+                gen_move_sp(-2, out)?;
+                writeln!(out, "    call $puts")
+            }
+
+            Instruction::Dup { size } => gen_copy(size, size, out),
+            Instruction::Swap { size_a, size_b } => {
+                let total = (size_a + size_b) as isize;
+                for i in (0..total).rev() {
+                    gen_load(i - total, out)?;
+                    writeln!(out, "    local.set $t{i}")?;
+                }
+                // Below the `size_b` slots that were under `a`, then `b`.
+                let swapped = (size_b..size_a + size_b).chain(0..size_b);
+                for (offset, i) in (-total..0).zip(swapped) {
+                    gen_address(offset, out)?;
+                    writeln!(out, "    local.get $t{i}")?;
+                    writeln!(out, "    i64.store")?;
+                }
+                Ok(())
+            }
+            Instruction::Drop { size } => gen_move_sp(-(size as isize), out),
+            Instruction::Over { size_a, size_b } => gen_copy(size_a + size_b, size_b, out),
+
+            Instruction::Branch { size } => {
+                // Copies the true or the false value over the condition.
+                let bottom = -(2 * size as isize + 1);
+                gen_address(bottom, out)?;
+                gen_address(bottom + 1, out)?;
+                gen_address(bottom + 1 + size as isize, out)?;
+                gen_load(bottom, out)?;
+                writeln!(out, "    i64.const 0")?;
+                writeln!(out, "    i64.ne")?;
+                writeln!(out, "    select")?;
+                writeln!(out, "    i32.const {}", 8 * size)?;
+                writeln!(out, "    memory.copy")?;
+                gen_move_sp(-(size as isize + 1), out)
+            }
+
+            Instruction::Apply => {
+                gen_load(-1, out)?;
+                writeln!(out, "    i32.wrap_i64")?;
+                gen_move_sp(-1, out)?;
+                gen_call(tail, out)
+            }
+            Instruction::Call(label) => {
+                writeln!(out, "    i32.const {}", self.table[&label])?;
+                gen_call(tail, out)
+            }
+            Instruction::Ffi { .. } => unreachable!("only x86_64 code calls C functions"),
+            Instruction::Syscall { .. } => unreachable!("only x86_64 code makes system calls"),
+            Instruction::Argc
+            | Instruction::ArgvNth
+            | Instruction::Getenv
+            | Instruction::ReadLine
+            | Instruction::ReadAll => {
+                unreachable!(
+                    "only x86_64, C and LLVM code reads arguments, the environment and input"
+                )
+            }
+        }
+    }
+}
+
+/// Pushes the address of the slot `offset` slots from the top.
+fn gen_address(offset: isize, out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "    global.get $sp")?;
+    if offset != 0 {
+        writeln!(out, "    i32.const {}", 8 * offset)?;
+        writeln!(out, "    i32.add")?;
+    }
+    Ok(())
+}
+
+fn gen_load(offset: isize, out: &mut impl Write) -> io::Result<()> {
+    gen_address(offset, out)?;
+    writeln!(out, "    i64.load")
+}
+
+fn gen_move_sp(delta: isize, out: &mut impl Write) -> io::Result<()> {
+    if delta == 0 {
+        return Ok(());
+    }
+    writeln!(out, "    global.get $sp")?;
+    writeln!(out, "    i32.const {}", 8 * delta)?;
+    writeln!(out, "    i32.add")?;
+    writeln!(out, "    global.set $sp")
+}
+
+fn gen_push(values: &[i64], out: &mut impl Write) -> io::Result<()> {
+    for (offset, value) in values.iter().enumerate() {
+        gen_address(offset as isize, out)?;
+        writeln!(out, "    i64.const {value}")?;
+        writeln!(out, "    i64.store")?;
+    }
+    gen_move_sp(values.len() as isize, out)
+}
+
+/// Pops two slots and pushes what `ops` make of them.
+fn gen_binary(ops: &[&str], out: &mut impl Write) -> io::Result<()> {
+    gen_address(-2, out)?;
+    gen_load(-2, out)?;
+    gen_load(-1, out)?;
+    for op in ops {
+        writeln!(out, "    {op}")?;
+    }
+    writeln!(out, "    i64.store")?;
+    gen_move_sp(-1, out)
+}
+
+/// Pushes a copy of the `size` slots starting `from` slots below the top.
+fn gen_copy(from: usize, size: usize, out: &mut impl Write) -> io::Result<()> {
+    gen_address(0, out)?;
+    gen_address(-(from as isize), out)?;
+    writeln!(out, "    i32.const {}", 8 * size)?;
+    writeln!(out, "    memory.copy")?;
+    gen_move_sp(size as isize, out)
+}
+
+/// Calls the proc whose table index is on the operand stack, or returns it
+/// for `$run` to call.
+fn gen_call(tail: bool, out: &mut impl Write) -> io::Result<()> {
+    if tail {
+        writeln!(out, "    return")
+    } else {
+        writeln!(out, "    call $run")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process::Command};
+
+    use super::*;
+    use crate::{
+        analyzer::Analyzer,
+        compiler::{Compiler, DATA_STACK_SLOTS},
+        interp::Interpreter,
+        lexer::Lexer,
+        optimizer::{self, OptLevel},
+    };
+
+    /// The WAT for `source`, and what the interpreter makes of it.
+    fn generate_wat(source: &str, level: OptLevel, stack_slots: usize) -> (String, String, i32) {
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        let (entry, mut procs, strings) = Compiler::compile(defs).unwrap();
+        optimizer::optimize(&mut procs, level);
+        let (entry, procs, strings) =
+            optimizer::eliminate_dead_code(entry.unwrap(), procs, strings);
+
+        let mut out = Vec::new();
+        let code = Interpreter::run(
+            entry,
+            &procs,
+            &strings,
+            &[],
+            DATA_STACK_SLOTS,
+            io::empty(),
+            &mut out,
+        )
+        .unwrap();
+
+        let mut wat = Vec::new();
+        generate(entry, &procs, &strings, stack_slots, &mut wat).unwrap();
+        (
+            String::from_utf8(wat).unwrap(),
+            String::from_utf8(out).unwrap(),
+            code as u8 as i32,
+        )
+    }
+
+    #[test]
+    fn strings_escape_quotes_and_control_characters() {
+        assert_eq!(wat_string("a \"b\"\\\n"), "\"a \\22b\\22\\5c\\0a\"");
+        assert_eq!(
+            wat_name(Label::new(3, Some("is-even?"))),
+            "$zila_3_is_2d_even_3f_"
+        );
+    }
+
+    #[test]
+    fn module_matches_golden_output() {
+        let (wat, out, code) =
+            generate_wat(": main \"hi\\n\" puts [ 1 2 + ] apply ;", OptLevel::O0, 16);
+        assert_eq!((out.as_str(), code), ("hi\n", 3));
+        assert_eq!(
+            wat,
+            r#"(module
+  (type $proc (func (result i32)))
+  (import "env" "puts" (func $puts (param i32 i32)))
+  (import "env" "exit" (func $exit (param i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "hi\0a")
+  (global $sp (mut i32) (i32.const 8))
+  (table 2 funcref)
+  (elem (i32.const 0) func $zila_0_main $zila_1)
+
+  (func $run (param $next i32)
+    block $done
+      loop $again
+        local.get $next
+        i32.const -1
+        i32.eq
+        br_if $done
+        local.get $next
+        call_indirect (type $proc)
+        local.set $next
+        br $again
+      end
+    end)
+
+  (func $zila_0_main (type $proc)
+    i32.const 136
+    global.get $sp
+    i32.sub
+    i32.const 16
+    i32.lt_u
+    if
+      unreachable
+    end
+    ;; Span { start: 7, end: 13 } -- push-string 0
+    global.get $sp
+    i64.const 0
+    i64.store
+    global.get $sp
+    i32.const 8
+    i32.add
+    i64.const 3
+    i64.store
+    global.get $sp
+    i32.const 16
+    i32.add
+    global.set $sp
+    ;; Span { start: 14, end: 18 } -- puts
+    global.get $sp
+    i32.const -16
+    i32.add
+    i64.load
+    i32.wrap_i64
+    global.get $sp
+    i32.const -8
+    i32.add
+    i64.load
+    i32.wrap_i64
+    global.get $sp
+    i32.const -16
+    i32.add
+    global.set $sp
+    call $puts
+    ;; Span { start: 19, end: 20 } -- push-quote @1
+    global.get $sp
+    i64.const 1
+    i64.store
+    global.get $sp
+    i32.const 8
+    i32.add
+    global.set $sp
+    ;; Span { start: 29, end: 34 } -- apply
+    global.get $sp
+    i32.const -8
+    i32.add
+    i64.load
+    i32.wrap_i64
+    global.get $sp
+    i32.const -8
+    i32.add
+    global.set $sp
+    return
+  )
+
+  (func $zila_1 (type $proc)
+    i32.const 136
+    global.get $sp
+    i32.sub
+    i32.const 16
+    i32.lt_u
+    if
+      unreachable
+    end
+    ;; Span { start: 21, end: 22 } -- push-int 1
+    global.get $sp
+    i64.const 1
+    i64.store
+    global.get $sp
+    i32.const 8
+    i32.add
+    global.set $sp
+    ;; Span { start: 23, end: 24 } -- push-int 2
+    global.get $sp
+    i64.const 2
+    i64.store
+    global.get $sp
+    i32.const 8
+    i32.add
+    global.set $sp
+    ;; Span { start: 25, end: 26 } -- add
+    global.get $sp
+    i32.const -16
+    i32.add
+    global.get $sp
+    i32.const -16
+    i32.add
+    i64.load
+    global.get $sp
+    i32.const -8
+    i32.add
+    i64.load
+    i64.add
+    i64.store
+    global.get $sp
+    i32.const -8
+    i32.add
+    global.set $sp
+    i32.const -1
+  )
+
+  (func (export "main") (result i32)
+    i32.const 0
+    call $run
+    global.get $sp
+    i32.const 8
+    i32.sub
+    i64.load
+    i32.wrap_i64)
+)
+"#
+        );
+    }
+
+    #[test]
+    fn shuffles_and_branches_go_through_memory_and_locals() {
+        let (wat, out, code) = generate_wat(
+            ": main \"ab\" 1 swap puts true [ 4 ] [ 5 ] ? apply ;",
+            OptLevel::O0,
+            DATA_STACK_SLOTS,
+        );
+        assert_eq!((out.as_str(), code), ("ab", 4));
+        assert!(wat.contains(
+            "  (func $zila_0_main (type $proc) (local $t0 i64) (local $t1 i64) (local $t2 i64)\n"
+        ));
+        assert!(wat.contains("    i64.ne\n    select\n    i32.const 8\n    memory.copy\n"));
+    }
+
+    #[test]
+    fn tail_calls_return_the_callee() {
+        let (wat, ..) = generate_wat(
+            ": loop dup 0 = [ drop ] [ 1 - loop ] ? apply ; : main 5 loop ;",
+            OptLevel::O0,
+            DATA_STACK_SLOTS,
+        );
+        assert!(wat.contains("    i32.const 0\n    return\n  )"));
+        assert!(!wat.contains("    i32.const 0\n    call $run\n"));
+    }
+
+    /// Runs the module with node, which needs it assembled by `wat2wasm`.
+    #[test]
+    #[ignore = "needs wat2wasm and node; run with `cargo test -- --ignored`"]
+    fn node_runs_match_the_interpreter() {
+        const HOST: &str = "
+            const fs = require('fs');
+            class Exit { constructor(code) { this.code = code; } }
+            let memory;
+            const env = {
+                puts(ptr, len) { fs.writeSync(1, Buffer.from(memory.buffer, ptr, len)); },
+                exit(code) { throw new Exit(code); },
+            };
+            const instance = new WebAssembly.Instance(
+                new WebAssembly.Module(fs.readFileSync(process.argv[1])), { env });
+            memory = instance.exports.memory;
+            let code;
+            try { code = instance.exports.main(); }
+            catch (e) { if (!(e instanceof Exit)) throw e; code = e.code; }
+            process.exit(code & 255);
+        ";
+        let programs = [
+            ": main \"hello\\n\" puts 3 4 * 5 - 2 / ;",
+            ": main \"a\" 1 swap puts \"b\" over drop puts 2 swap - ;",
+            ": pick [ \"yes\" ] [ \"no\" ] ? apply ;
+             : main true pick puts false pick puts
+             1 2 < [ 10 ] [ 20 ] ? apply 3 3 = [ 4 ] [ 5 ] ? apply + ;",
+            ": count dup 0 = [ drop ] [ 1 - count ] ? apply ;
+             : main 1000000 count \"done\" puts ;",
+            ": s \"xy\" ;
+             : main 1 s 2 swap over drop dup puts puts + s 5 over puts drop puts \"end\" puts 7 exit ;",
+            ": main 0 7 - 2 / 7 0 2 - / * ;",
+        ];
+
+        let dir = env::temp_dir().join(format!("zila-wat-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (i, source) in programs.into_iter().enumerate() {
+            for level in [OptLevel::O0, OptLevel::O2] {
+                let (wat, out, code) = generate_wat(source, level, DATA_STACK_SLOTS);
+                let (src, wasm) = (dir.join(format!("{i}.wat")), dir.join(format!("{i}.wasm")));
+                fs::write(&src, wat).unwrap();
+                let status = Command::new("wat2wasm")
+                    .arg(&src)
+                    .arg("-o")
+                    .arg(&wasm)
+                    .status()
+                    .unwrap();
+                assert!(status.success());
+
+                let output = Command::new("node")
+                    .args(["-e", HOST])
+                    .arg(&wasm)
+                    .output()
+                    .unwrap();
+                assert_eq!(String::from_utf8(output.stdout).unwrap(), out, "{source}");
+                assert_eq!(output.status.code(), Some(code), "{source} at {level:?}");
+            }
+        }
+    }
+}
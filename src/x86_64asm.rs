@@ -0,0 +1,345 @@
+//! The subset of x86_64 assembly that `x86_64gen` emits, as data. Its
+//! `Display` impls print nasm syntax.
+
+use std::fmt;
+
+use crate::compiler::Label;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    Rax,
+    Rcx,
+    Rdx,
+    Rsp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+}
+
+impl Reg {
+    fn low_byte(self) -> &'static str {
+        match self {
+            Reg::Rax => "al",
+            Reg::Rcx => "cl",
+            Reg::Rdx => "dl",
+            Reg::Rsp => "spl",
+            Reg::Rsi => "sil",
+            Reg::Rdi => "dil",
+            Reg::R8 => "r8b",
+            Reg::R9 => "r9b",
+            Reg::R10 => "r10b",
+        }
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Reg::Rax => "rax",
+            Reg::Rcx => "rcx",
+            Reg::Rdx => "rdx",
+            Reg::Rsp => "rsp",
+            Reg::Rsi => "rsi",
+            Reg::Rdi => "rdi",
+            Reg::R8 => "r8",
+            Reg::R9 => "r9",
+            Reg::R10 => "r10",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Symbol<'src> {
+    Start,
+    /// The entry point when linking with libc, which calls it like C's.
+    Main,
+    Proc(Label<'src>),
+    /// A C function that is linked in.
+    Extern(&'src str),
+    Str(usize),
+    DataStack,
+    /// One past the last slot of `DataStack`.
+    DataStackEnd,
+    /// Where the command-line arguments and the environment are saved at
+    /// startup: the argument count, then pointers to the null-terminated
+    /// arrays of argument and `NAME=value` strings.
+    Argc,
+    Argv,
+    Envp,
+    /// Pointers to the start of the buffer standard input is read into, the
+    /// first byte not yet consumed, the end of what has been read and the
+    /// end of the buffer.
+    Input,
+    InputPos,
+    InputEnd,
+    InputLimit,
+    /// A routine that builtins call, or a label inside one.
+    Runtime(&'static str),
+    /// Code that reports a stack check failure and exits, and its message.
+    StackError(StackError),
+    StackErrorMessage(StackError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StackError {
+    Overflow,
+    Underflow,
+}
+
+impl StackError {
+    pub fn message(self) -> &'static str {
+        match self {
+            StackError::Overflow => "data stack overflow\n",
+            StackError::Underflow => "data stack underflow\n",
+        }
+    }
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackError::Overflow => write!(f, "overflow"),
+            StackError::Underflow => write!(f, "underflow"),
+        }
+    }
+}
+
+impl fmt::Display for Symbol<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Symbol::Start => write!(f, "_start"),
+            Symbol::Main => write!(f, "main"),
+            Symbol::Proc(label) => write!(f, "{label}"),
+            Symbol::Extern(name) => write!(f, "{name}"),
+            Symbol::Str(i) => write!(f, "str_{i}"),
+            Symbol::DataStack => write!(f, "data_stack"),
+            Symbol::DataStackEnd => write!(f, "data_stack_end"),
+            Symbol::Argc => write!(f, "argc"),
+            Symbol::Argv => write!(f, "argv"),
+            Symbol::Envp => write!(f, "envp"),
+            Symbol::Input => write!(f, "input"),
+            Symbol::InputPos => write!(f, "input_pos"),
+            Symbol::InputEnd => write!(f, "input_end"),
+            Symbol::InputLimit => write!(f, "input_limit"),
+            Symbol::Runtime(name) => write!(f, "runtime_{name}"),
+            Symbol::StackError(error) => write!(f, "data_stack_{error}"),
+            Symbol::StackErrorMessage(error) => write!(f, "data_stack_{error}_message"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mem<'src> {
+    /// `[base + disp]`
+    Base(Reg, i32),
+    /// `[rel symbol]`
+    Rel(Symbol<'src>),
+}
+
+impl fmt::Display for Mem<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Mem::Base(base, 0) => write!(f, "[{base}]"),
+            Mem::Base(base, disp) if disp < 0 => write!(f, "[{base} - {}]", -(disp as i64)),
+            Mem::Base(base, disp) => write!(f, "[{base} + {disp}]"),
+            Mem::Rel(symbol) => write!(f, "[rel {symbol}]"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    E,
+    L,
+    /// Unsigned above.
+    A,
+    /// Unsigned below.
+    B,
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cond::E => write!(f, "e"),
+            Cond::L => write!(f, "l"),
+            Cond::A => write!(f, "a"),
+            Cond::B => write!(f, "b"),
+        }
+    }
+}
+
+/// A position in a Zila source file. Lines and columns start at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Loc<'src> {
+    pub file: &'src str,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Loc<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+/// One line of assembly. Two-operand instructions take the destination
+/// first, as in Intel syntax.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Asm<'src> {
+    Label(Symbol<'src>),
+    Comment(String),
+    /// The instructions that follow were compiled from `Loc`. nasm has no
+    /// columns, so only the encoder keeps them.
+    Loc(Loc<'src>),
+
+    Mov(Reg, Reg),
+    MovImm(Reg, i64),
+    Load(Reg, Mem<'src>),
+    /// Zero-extends the byte at `Mem` into the register.
+    LoadByte(Reg, Mem<'src>),
+    Store(Mem<'src>, Reg),
+    Lea(Reg, Mem<'src>),
+
+    Add(Reg, Reg),
+    Sub(Reg, Reg),
+    Imul(Reg, Reg),
+    AddImm(Reg, i32),
+    SubImm(Reg, i32),
+    AndImm(Reg, i32),
+    Neg(Reg),
+    Cqo,
+    Idiv(Reg),
+
+    Cmp(Reg, Reg),
+    Test(Reg, Reg),
+    /// Sets the low byte of the register to 1 if the condition holds.
+    Set(Cond, Reg),
+    /// Zero-extends the low byte of the second register into the first.
+    Movzx(Reg, Reg),
+    Cmov(Cond, Reg, Reg),
+
+    Push(Reg),
+    Pop(Reg),
+    Call(Symbol<'src>),
+    CallReg(Reg),
+    Jmp(Symbol<'src>),
+    Jcc(Cond, Symbol<'src>),
+    JmpReg(Reg),
+    Ret,
+    Syscall,
+    /// Copies `rcx` bytes from `[rsi]` to `[rdi]`, advancing both.
+    RepMovsb,
+}
+
+impl fmt::Display for Asm<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Asm::Label(symbol) => write!(f, "{symbol}:"),
+            Asm::Comment(comment) => write!(f, "    ; {comment}"),
+            Asm::Loc(loc) => write!(f, "    %line {}+0 {}", loc.line, loc.file),
+
+            Asm::Mov(dst, src) => write!(f, "    mov {dst}, {src}"),
+            Asm::MovImm(dst, imm) => write!(f, "    mov {dst}, {imm}"),
+            Asm::Load(dst, mem) => write!(f, "    mov {dst}, {mem}"),
+            Asm::LoadByte(dst, mem) => write!(f, "    movzx {dst}, byte {mem}"),
+            Asm::Store(mem, src) => write!(f, "    mov {mem}, {src}"),
+            Asm::Lea(dst, mem) => write!(f, "    lea {dst}, {mem}"),
+
+            Asm::Add(dst, src) => write!(f, "    add {dst}, {src}"),
+            Asm::Sub(dst, src) => write!(f, "    sub {dst}, {src}"),
+            Asm::Imul(dst, src) => write!(f, "    imul {dst}, {src}"),
+            Asm::AddImm(dst, imm) => write!(f, "    add {dst}, {imm}"),
+            Asm::SubImm(dst, imm) => write!(f, "    sub {dst}, {imm}"),
+            Asm::AndImm(dst, imm) => write!(f, "    and {dst}, {imm}"),
+            Asm::Neg(reg) => write!(f, "    neg {reg}"),
+            Asm::Cqo => write!(f, "    cqo"),
+            Asm::Idiv(reg) => write!(f, "    idiv {reg}"),
+
+            Asm::Cmp(a, b) => write!(f, "    cmp {a}, {b}"),
+            Asm::Test(a, b) => write!(f, "    test {a}, {b}"),
+            Asm::Set(cond, reg) => write!(f, "    set{cond} {}", reg.low_byte()),
+            Asm::Movzx(dst, src) => write!(f, "    movzx {dst}, {}", src.low_byte()),
+            Asm::Cmov(cond, dst, src) => write!(f, "    cmov{cond} {dst}, {src}"),
+
+            Asm::Push(reg) => write!(f, "    push {reg}"),
+            Asm::Pop(reg) => write!(f, "    pop {reg}"),
+            // C functions may be in a shared library.
+            Asm::Call(symbol @ Symbol::Extern(_)) => write!(f, "    call {symbol} wrt ..plt"),
+            Asm::Call(symbol) => write!(f, "    call {symbol}"),
+            Asm::CallReg(reg) => write!(f, "    call {reg}"),
+            Asm::Jmp(symbol) => write!(f, "    jmp {symbol}"),
+            Asm::Jcc(cond, symbol) => write!(f, "    j{cond} {symbol}"),
+            Asm::JmpReg(reg) => write!(f, "    jmp {reg}"),
+            Asm::Ret => write!(f, "    ret"),
+            Asm::Syscall => write!(f, "    syscall"),
+            Asm::RepMovsb => write!(f, "    rep movsb"),
+        }
+    }
+}
+
+/// A whole program: code, read-only data and zero-initialized data.
+#[derive(Debug, Default)]
+pub struct Assembly<'src> {
+    pub text: Vec<Asm<'src>>,
+    pub rodata: Vec<(Symbol<'src>, Vec<u8>)>,
+    /// Symbols and their sizes in bytes.
+    pub bss: Vec<(Symbol<'src>, usize)>,
+}
+
+impl fmt::Display for Assembly<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "section .bss")?;
+        for (symbol, size) in &self.bss {
+            writeln!(f, "align 8")?;
+            writeln!(f, "{symbol}: resb {size}")?;
+        }
+
+        writeln!(f, "section .rodata")?;
+        for (symbol, bytes) in &self.rodata {
+            write!(f, "{symbol}:")?;
+            if !bytes.is_empty() {
+                let bytes = bytes.iter().map(|b| b.to_string()).collect::<Vec<_>>();
+                write!(f, " db {}", bytes.join(","))?;
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(f, "section .text")?;
+        let mut externs = Vec::new();
+        for asm in &self.text {
+            if let Asm::Call(symbol @ Symbol::Extern(_)) = asm
+                && !externs.contains(symbol)
+            {
+                externs.push(*symbol);
+                writeln!(f, "extern {symbol}")?;
+            }
+        }
+        // Each proc is sized up to its local `.end` label, which nasm
+        // prefixes with the proc's name.
+        let mut in_proc = false;
+        for asm in &self.text {
+            if let Asm::Label(symbol) = asm {
+                if std::mem::take(&mut in_proc) {
+                    writeln!(f, ".end:")?;
+                }
+                match symbol {
+                    Symbol::Start | Symbol::Main => writeln!(f, "global {symbol}")?,
+                    Symbol::Proc(_) => {
+                        writeln!(f, "global {symbol}:function ({symbol}.end - {symbol})")?;
+                        in_proc = true;
+                    }
+                    _ => (),
+                }
+            }
+            writeln!(f, "{asm}")?;
+        }
+        if in_proc {
+            writeln!(f, ".end:")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,782 @@
+//! Encodes an `x86_64asm::Assembly` as machine code and lays it out as an
+//! `elf::Executable`, or an `elf::Object` to link with libc.
+//!
+//! Every branch and symbol reference uses a 32-bit displacement, so the size
+//! of each instruction is known before any address is, and the code is
+//! encoded in a single pass and patched once the sections are laid out.
+//!
+//! If the assembly has `Asm::Loc`s, the executable gets DWARF line and
+//! call frame information too.
+
+use std::{collections::HashMap, fmt, path::Path};
+
+use crate::{
+    dwarf::{self, Function, Line},
+    elf::{self, Executable, Layout, Object, Relocation, Section},
+    x86_64asm::{Asm, Assembly, Cond, Loc, Mem, Reg, Symbol},
+};
+
+fn number(reg: Reg) -> u8 {
+    match reg {
+        Reg::Rax => 0,
+        Reg::Rcx => 1,
+        Reg::Rdx => 2,
+        Reg::Rsp => 4,
+        Reg::Rsi => 6,
+        Reg::Rdi => 7,
+        Reg::R8 => 8,
+        Reg::R9 => 9,
+        Reg::R10 => 10,
+    }
+}
+
+fn cond_code(cond: Cond) -> u8 {
+    match cond {
+        Cond::B => 0x2,
+        Cond::E => 0x4,
+        Cond::A => 0x7,
+        Cond::L => 0xc,
+    }
+}
+
+/// The `r/m` operand of an instruction.
+#[derive(Clone, Copy)]
+enum Rm<'src> {
+    Reg(Reg),
+    Mem(Mem<'src>),
+}
+
+/// Whether an instruction needs `REX.W` for a 64-bit operand, or a bare REX
+/// prefix so that the byte registers of `rsp`, `rsi` and `rdi` can be named.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Rex {
+    None,
+    Byte,
+    Wide,
+}
+
+#[derive(Default)]
+struct Encoder<'src> {
+    text: Vec<u8>,
+    labels: HashMap<Symbol<'src>, usize>,
+    /// The offsets of the 32-bit displacements to `Symbol`s. Each is the
+    /// last field of its instruction, so it is relative to its own end.
+    fixups: Vec<(usize, Symbol<'src>)>,
+    /// The offsets at which the code from each `Loc` starts.
+    lines: Vec<(usize, Loc<'src>)>,
+    /// The offsets after each `push` and `pop`, and how far they move `rsp`.
+    stack_moves: Vec<(usize, i64)>,
+}
+
+impl<'src> Encoder<'src> {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.text.extend_from_slice(bytes);
+    }
+
+    fn emit_rel32(&mut self, symbol: Symbol<'src>) {
+        self.fixups.push((self.text.len(), symbol));
+        self.emit(&[0; 4]);
+    }
+
+    /// Emits an instruction with a ModRM byte: the optional prefix, the
+    /// opcode, then `reg` and `rm` with any SIB byte and displacement.
+    fn emit_modrm(&mut self, rex: Rex, opcode: &[u8], reg: u8, rm: Rm<'src>) {
+        let base = match rm {
+            Rm::Reg(reg) | Rm::Mem(Mem::Base(reg, _)) => number(reg),
+            Rm::Mem(Mem::Rel(_)) => 0,
+        };
+        let extension = (reg >> 3) << 2 | base >> 3;
+        match rex {
+            Rex::Wide => self.emit(&[0x48 | extension]),
+            Rex::Byte => self.emit(&[0x40 | extension]),
+            Rex::None if extension != 0 => self.emit(&[0x40 | extension]),
+            Rex::None => (),
+        }
+        self.emit(opcode);
+
+        let reg = (reg & 7) << 3;
+        match rm {
+            Rm::Reg(_) => self.emit(&[0xc0 | reg | base & 7]),
+            Rm::Mem(Mem::Rel(symbol)) => {
+                self.emit(&[reg | 0b101]);
+                self.emit_rel32(symbol);
+            }
+            Rm::Mem(Mem::Base(_, disp)) => {
+                // `rbp` and `r13` can't be used without a displacement, and
+                // `rsp` and `r12` need a SIB byte.
+                let mode = match disp {
+                    0 if base & 7 != 5 => 0x00,
+                    -128..=127 => 0x40,
+                    _ => 0x80,
+                };
+                self.emit(&[mode | reg | base & 7]);
+                if base & 7 == 4 {
+                    self.emit(&[0x24]);
+                }
+                match mode {
+                    0x40 => self.emit(&[disp as i8 as u8]),
+                    0x80 => self.emit(&disp.to_le_bytes()),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Emits a one-byte opcode that names a register in its low bits.
+    fn emit_short(&mut self, opcode: u8, reg: Reg) {
+        let reg = number(reg);
+        if reg >= 8 {
+            self.emit(&[0x41]);
+        }
+        self.emit(&[opcode | reg & 7]);
+    }
+
+    fn encode(&mut self, asm: &Asm<'src>) {
+        use Rm::{Mem as M, Reg as R};
+
+        match *asm {
+            Asm::Label(symbol) => {
+                self.labels.insert(symbol, self.text.len());
+            }
+            Asm::Comment(_) => (),
+            Asm::Loc(loc) => self.lines.push((self.text.len(), loc)),
+
+            Asm::Mov(dst, src) => self.emit_modrm(Rex::Wide, &[0x89], number(src), R(dst)),
+            Asm::MovImm(dst, imm) => match i32::try_from(imm) {
+                Ok(imm) => {
+                    self.emit_modrm(Rex::Wide, &[0xc7], 0, R(dst));
+                    self.emit(&imm.to_le_bytes());
+                }
+                Err(_) => {
+                    self.emit(&[0x48 | number(dst) >> 3]);
+                    self.emit(&[0xb8 | number(dst) & 7]);
+                    self.emit(&imm.to_le_bytes());
+                }
+            },
+            Asm::Load(dst, mem) => self.emit_modrm(Rex::Wide, &[0x8b], number(dst), M(mem)),
+            Asm::LoadByte(dst, mem) => {
+                self.emit_modrm(Rex::Wide, &[0x0f, 0xb6], number(dst), M(mem))
+            }
+            Asm::Store(mem, src) => self.emit_modrm(Rex::Wide, &[0x89], number(src), M(mem)),
+            Asm::Lea(dst, mem) => self.emit_modrm(Rex::Wide, &[0x8d], number(dst), M(mem)),
+
+            Asm::Add(dst, src) => self.emit_modrm(Rex::Wide, &[0x01], number(src), R(dst)),
+            Asm::Sub(dst, src) => self.emit_modrm(Rex::Wide, &[0x29], number(src), R(dst)),
+            Asm::Imul(dst, src) => self.emit_modrm(Rex::Wide, &[0x0f, 0xaf], number(dst), R(src)),
+            Asm::AddImm(dst, imm) => self.emit_imm_op(0, dst, imm),
+            Asm::SubImm(dst, imm) => self.emit_imm_op(5, dst, imm),
+            Asm::AndImm(dst, imm) => self.emit_imm_op(4, dst, imm),
+            Asm::Neg(reg) => self.emit_modrm(Rex::Wide, &[0xf7], 3, R(reg)),
+            Asm::Cqo => self.emit(&[0x48, 0x99]),
+            Asm::Idiv(reg) => self.emit_modrm(Rex::Wide, &[0xf7], 7, R(reg)),
+
+            Asm::Cmp(a, b) => self.emit_modrm(Rex::Wide, &[0x39], number(b), R(a)),
+            Asm::Test(a, b) => self.emit_modrm(Rex::Wide, &[0x85], number(b), R(a)),
+            Asm::Set(cond, reg) => {
+                self.emit_modrm(Rex::Byte, &[0x0f, 0x90 | cond_code(cond)], 0, R(reg))
+            }
+            Asm::Movzx(dst, src) => self.emit_modrm(Rex::Wide, &[0x0f, 0xb6], number(dst), R(src)),
+            Asm::Cmov(cond, dst, src) => self.emit_modrm(
+                Rex::Wide,
+                &[0x0f, 0x40 | cond_code(cond)],
+                number(dst),
+                R(src),
+            ),
+
+            Asm::Push(reg) => {
+                self.emit_short(0x50, reg);
+                self.stack_moves.push((self.text.len(), 8));
+            }
+            Asm::Pop(reg) => {
+                self.emit_short(0x58, reg);
+                self.stack_moves.push((self.text.len(), -8));
+            }
+            Asm::Call(symbol) => {
+                self.emit(&[0xe8]);
+                self.emit_rel32(symbol);
+            }
+            Asm::CallReg(reg) => self.emit_modrm(Rex::None, &[0xff], 2, R(reg)),
+            Asm::Jmp(symbol) => {
+                self.emit(&[0xe9]);
+                self.emit_rel32(symbol);
+            }
+            Asm::Jcc(cond, symbol) => {
+                self.emit(&[0x0f, 0x80 | cond_code(cond)]);
+                self.emit_rel32(symbol);
+            }
+            Asm::JmpReg(reg) => self.emit_modrm(Rex::None, &[0xff], 4, R(reg)),
+            Asm::Ret => self.emit(&[0xc3]),
+            Asm::Syscall => self.emit(&[0x0f, 0x05]),
+            Asm::RepMovsb => self.emit(&[0xf3, 0xa4]),
+        }
+    }
+
+    /// `add`, `sub` or `and` with an immediate, picked by `extension`.
+    fn emit_imm_op(&mut self, extension: u8, dst: Reg, imm: i32) {
+        match i8::try_from(imm) {
+            Ok(imm) => {
+                self.emit_modrm(Rex::Wide, &[0x83], extension, Rm::Reg(dst));
+                self.emit(&[imm as u8]);
+            }
+            Err(_) => {
+                self.emit_modrm(Rex::Wide, &[0x81], extension, Rm::Reg(dst));
+                self.emit(&imm.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// The machine code, data and symbols of an `Assembly`, before the
+/// sections are given addresses.
+struct Encoded<'src> {
+    encoder: Encoder<'src>,
+    rodata: Vec<u8>,
+    bss_size: usize,
+    functions: Vec<Function>,
+    /// Every symbol that is defined, sorted by where it is.
+    symbols: Vec<(Symbol<'src>, elf::Symbol)>,
+}
+
+fn encode_sections<'src>(assembly: &Assembly<'src>) -> Encoded<'src> {
+    let mut encoder = Encoder::default();
+    for asm in &assembly.text {
+        encoder.encode(asm);
+    }
+
+    let mut rodata = Vec::new();
+    let mut data = Vec::new();
+    for (symbol, bytes) in &assembly.rodata {
+        data.push((*symbol, Section::Rodata, rodata.len(), bytes.len()));
+        rodata.extend_from_slice(bytes);
+    }
+    let mut bss_size = 0usize;
+    for &(symbol, size) in &assembly.bss {
+        bss_size = bss_size.next_multiple_of(8);
+        data.push((symbol, Section::Bss, bss_size, size));
+        bss_size += size;
+    }
+
+    let functions = functions(&encoder.labels, &encoder.stack_moves, encoder.text.len());
+    let function_sizes = functions
+        .iter()
+        .map(|function| {
+            (
+                function.start as usize,
+                (function.end - function.start) as usize,
+            )
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut symbols = encoder
+        .labels
+        .iter()
+        .map(|(&symbol, &offset)| (symbol, Section::Text, offset, function_sizes[&offset]))
+        .chain(data)
+        .map(|(symbol, section, offset, size)| {
+            (
+                symbol,
+                elf::Symbol {
+                    name: symbol.to_string(),
+                    section,
+                    offset: offset as u64,
+                    size: size as u64,
+                    // Procs are global, like the functions of a C program.
+                    global: matches!(symbol, Symbol::Start | Symbol::Main | Symbol::Proc(_)),
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+    // Labels are hashed, so sort them for a reproducible symbol table.
+    symbols.sort_by_key(|(_, symbol)| (symbol.section, symbol.offset));
+
+    Encoded {
+        encoder,
+        rodata,
+        bss_size,
+        functions,
+        symbols,
+    }
+}
+
+/// A symbol that the assembly refers to but never defines.
+#[derive(Debug)]
+pub struct UndefinedSymbol(String);
+
+impl fmt::Display for UndefinedSymbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` is never defined", self.0)
+    }
+}
+
+/// Encodes `assembly` and resolves every symbol it references. `_start`
+/// becomes the entry point.
+pub fn encode(assembly: &Assembly) -> Result<Executable, UndefinedSymbol> {
+    let Encoded {
+        encoder,
+        rodata,
+        bss_size,
+        functions,
+        symbols,
+    } = encode_sections(assembly);
+
+    let layout = Layout::new(encoder.text.len(), rodata.len());
+    let addresses = symbols
+        .iter()
+        .map(|(symbol, entry)| (*symbol, layout.addr(entry.section) + entry.offset))
+        .collect::<HashMap<_, _>>();
+
+    let mut text = encoder.text;
+    for (offset, symbol) in encoder.fixups {
+        let target = addresses
+            .get(&symbol)
+            .ok_or_else(|| UndefinedSymbol(symbol.to_string()))?;
+        let next = layout.addr(Section::Text) + offset as u64 + 4;
+        let disp = i32::try_from(*target as i64 - next as i64).expect("sections are within 2GB");
+        text[offset..offset + 4].copy_from_slice(&disp.to_le_bytes());
+    }
+
+    let debug_sections = match encoder.lines.first() {
+        Some((_, loc)) => {
+            let lines = encoder
+                .lines
+                .iter()
+                .map(|&(offset, loc)| Line {
+                    offset: offset as u64,
+                    line: loc.line as u64,
+                    col: loc.col as u64,
+                })
+                .collect::<Vec<_>>();
+            dwarf::sections(
+                Path::new(loc.file),
+                layout.addr(Section::Text),
+                text.len() as u64,
+                &lines,
+                &functions,
+            )
+        }
+        None => Vec::new(),
+    };
+
+    let entry = *addresses
+        .get(&Symbol::Start)
+        .ok_or_else(|| UndefinedSymbol(Symbol::Start.to_string()))?;
+    Ok(Executable {
+        layout,
+        entry,
+        text,
+        rodata,
+        bss_size,
+        symbols: symbols.into_iter().map(|(_, symbol)| symbol).collect(),
+        debug_sections,
+    })
+}
+
+/// Encodes `assembly` as an object for the system linker. References
+/// within `.text` are resolved, while those to data and to C functions are
+/// left to the linker. Objects get no debug info.
+pub fn encode_object(assembly: &Assembly) -> Result<Object, UndefinedSymbol> {
+    let Encoded {
+        encoder,
+        rodata,
+        bss_size,
+        symbols,
+        ..
+    } = encode_sections(assembly);
+
+    let places = symbols
+        .iter()
+        .map(|(symbol, entry)| (*symbol, (entry.section, entry.offset)))
+        .collect::<HashMap<_, _>>();
+
+    let mut text = encoder.text;
+    let mut externs = Vec::<String>::new();
+    let mut relocations = Vec::new();
+    for (offset, symbol) in encoder.fixups {
+        let relocation = match places.get(&symbol) {
+            Some(&(Section::Text, target)) => {
+                let disp = target as i64 - (offset as i64 + 4);
+                text[offset..offset + 4].copy_from_slice(&(disp as i32).to_le_bytes());
+                continue;
+            }
+            Some(&(section, target)) => Relocation::Section {
+                offset: offset as u64,
+                section,
+                target,
+            },
+            None => {
+                let Symbol::Extern(name) = symbol else {
+                    return Err(UndefinedSymbol(symbol.to_string()));
+                };
+                let index = match externs.iter().position(|extern_| extern_ == name) {
+                    Some(index) => index,
+                    None => {
+                        externs.push(name.into());
+                        externs.len() - 1
+                    }
+                };
+                Relocation::Extern {
+                    offset: offset as u64,
+                    index,
+                }
+            }
+        };
+        relocations.push(relocation);
+    }
+
+    Ok(Object {
+        text,
+        rodata,
+        bss_size,
+        symbols: symbols.into_iter().map(|(_, symbol)| symbol).collect(),
+        externs,
+        relocations,
+    })
+}
+
+/// Splits `.text` into functions at its labels, as every label in it is
+/// only ever called or jumped to from elsewhere.
+fn functions(
+    labels: &HashMap<Symbol, usize>,
+    stack_moves: &[(usize, i64)],
+    text_size: usize,
+) -> Vec<Function> {
+    let mut starts = labels
+        .iter()
+        .map(|(&symbol, &offset)| (offset, symbol == Symbol::Start))
+        .collect::<Vec<_>>();
+    starts.sort();
+    starts.dedup_by_key(|&mut (offset, _)| offset);
+
+    let ends = starts.iter().skip(1).map(|&(offset, _)| offset);
+    starts
+        .iter()
+        .zip(ends.chain([text_size]))
+        .map(|(&(start, is_entry), end)| {
+            // Functions are entered with only the return address pushed.
+            let mut frame_size = 8;
+            let frame_sizes = stack_moves
+                .iter()
+                .filter(|&&(at, _)| start < at && at < end)
+                .map(|&(at, by)| {
+                    frame_size += by;
+                    (at as u64, frame_size as u64)
+                })
+                .collect();
+            Function {
+                start: start as u64,
+                end: end as u64,
+                is_entry,
+                frame_sizes,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::Label, x86_64asm::StackError};
+
+    fn bytes(asm: Asm) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        encoder.encode(&asm);
+        encoder.text
+    }
+
+    /// Expected encodings are from GNU `as`.
+    #[test]
+    fn instructions_encode_like_an_assembler_would() {
+        let cases = [
+            (Asm::Mov(Reg::Rdi, Reg::R8), &[0x4c, 0x89, 0xc7][..]),
+            (Asm::Mov(Reg::R10, Reg::Rax), &[0x49, 0x89, 0xc2]),
+            (
+                Asm::MovImm(Reg::Rax, 60),
+                &[0x48, 0xc7, 0xc0, 0x3c, 0, 0, 0],
+            ),
+            (
+                Asm::MovImm(Reg::R9, -1),
+                &[0x49, 0xc7, 0xc1, 0xff, 0xff, 0xff, 0xff],
+            ),
+            (
+                Asm::MovImm(Reg::R8, 1 << 40),
+                &[0x49, 0xb8, 0, 0, 0, 0, 0, 1, 0, 0],
+            ),
+            (
+                Asm::Load(Reg::R8, Mem::Base(Reg::Rcx, -8)),
+                &[0x4c, 0x8b, 0x41, 0xf8],
+            ),
+            (
+                Asm::Load(Reg::Rax, Mem::Base(Reg::Rcx, 0)),
+                &[0x48, 0x8b, 0x01],
+            ),
+            (
+                Asm::Load(Reg::Rdx, Mem::Base(Reg::Rcx, -4096)),
+                &[0x48, 0x8b, 0x91, 0x00, 0xf0, 0xff, 0xff],
+            ),
+            (
+                Asm::LoadByte(Reg::Rax, Mem::Base(Reg::R8, 0)),
+                &[0x49, 0x0f, 0xb6, 0x00],
+            ),
+            (
+                Asm::LoadByte(Reg::R10, Mem::Base(Reg::Rdx, 0)),
+                &[0x4c, 0x0f, 0xb6, 0x12],
+            ),
+            (
+                Asm::Store(Mem::Base(Reg::Rsp, -16), Reg::Rax),
+                &[0x48, 0x89, 0x44, 0x24, 0xf0],
+            ),
+            (
+                Asm::Store(Mem::Base(Reg::Rcx, 8), Reg::R10),
+                &[0x4c, 0x89, 0x51, 0x08],
+            ),
+            (
+                Asm::Lea(Reg::Rax, Mem::Base(Reg::Rcx, 16)),
+                &[0x48, 0x8d, 0x41, 0x10],
+            ),
+            (Asm::Add(Reg::R8, Reg::R9), &[0x4d, 0x01, 0xc8]),
+            (Asm::Sub(Reg::R9, Reg::R8), &[0x4d, 0x29, 0xc1]),
+            (Asm::Imul(Reg::R8, Reg::R9), &[0x4d, 0x0f, 0xaf, 0xc1]),
+            (Asm::AddImm(Reg::Rcx, 8), &[0x48, 0x83, 0xc1, 0x08]),
+            (
+                Asm::SubImm(Reg::Rcx, 1024),
+                &[0x48, 0x81, 0xe9, 0x00, 0x04, 0, 0],
+            ),
+            (Asm::AndImm(Reg::Rsp, -16), &[0x48, 0x83, 0xe4, 0xf0]),
+            (Asm::Neg(Reg::R8), &[0x49, 0xf7, 0xd8]),
+            (Asm::Cqo, &[0x48, 0x99]),
+            (Asm::Idiv(Reg::R9), &[0x49, 0xf7, 0xf9]),
+            (Asm::Cmp(Reg::Rax, Reg::Rdx), &[0x48, 0x39, 0xd0]),
+            (Asm::Test(Reg::R8, Reg::R8), &[0x4d, 0x85, 0xc0]),
+            (Asm::Set(Cond::E, Reg::Rsi), &[0x40, 0x0f, 0x94, 0xc6]),
+            (Asm::Set(Cond::L, Reg::R10), &[0x41, 0x0f, 0x9c, 0xc2]),
+            (Asm::Movzx(Reg::R10, Reg::R10), &[0x4d, 0x0f, 0xb6, 0xd2]),
+            (
+                Asm::Cmov(Cond::E, Reg::Rdx, Reg::Rsi),
+                &[0x48, 0x0f, 0x44, 0xd6],
+            ),
+            (Asm::Push(Reg::Rcx), &[0x51]),
+            (Asm::Pop(Reg::R8), &[0x41, 0x58]),
+            (Asm::Pop(Reg::Rsp), &[0x5c]),
+            (Asm::CallReg(Reg::R9), &[0x41, 0xff, 0xd1]),
+            (Asm::JmpReg(Reg::Rax), &[0xff, 0xe0]),
+            (Asm::Ret, &[0xc3]),
+            (Asm::Syscall, &[0x0f, 0x05]),
+            (Asm::RepMovsb, &[0xf3, 0xa4]),
+        ];
+
+        for (asm, expected) in cases {
+            assert_eq!(bytes(asm.clone()), expected, "{asm}");
+        }
+    }
+
+    #[test]
+    fn references_are_relative_to_the_next_instruction() {
+        let assembly = Assembly {
+            text: vec![
+                Asm::Label(Symbol::Start),
+                Asm::Lea(Reg::Rcx, Mem::Rel(Symbol::DataStack)),
+                Asm::Jcc(Cond::A, Symbol::Start),
+                Asm::Call(Symbol::Start),
+            ],
+            rodata: vec![(Symbol::Str(0), b"hi".to_vec())],
+            bss: vec![(Symbol::DataStack, 16)],
+        };
+        let executable = encode(&assembly).unwrap();
+
+        let text = executable.layout.addr(Section::Text);
+        let bss = executable.layout.addr(Section::Bss);
+        assert_eq!(executable.entry, text);
+        assert_eq!(&executable.text[..3], [0x48, 0x8d, 0x0d]);
+        let disp = i32::from_le_bytes(executable.text[3..7].try_into().unwrap());
+        assert_eq!(text as i64 + 7 + disp as i64, bss as i64);
+        assert_eq!(
+            &executable.text[7..13],
+            [0x0f, 0x87, 0xf3, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(&executable.text[13..], [0xe8, 0xee, 0xff, 0xff, 0xff]);
+        assert_eq!(executable.rodata, b"hi");
+        assert_eq!(executable.bss_size, 16);
+    }
+
+    #[test]
+    fn undefined_symbols_are_errors() {
+        let assembly = Assembly {
+            text: vec![Asm::Label(Symbol::Start), Asm::Call(Symbol::Argc)],
+            rodata: Vec::new(),
+            bss: Vec::new(),
+        };
+        let err = encode(&assembly).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("`{}` is never defined", Symbol::Argc)
+        );
+        assert!(encode_object(&assembly).is_err());
+
+        let assembly = Assembly {
+            text: vec![Asm::Ret],
+            rodata: Vec::new(),
+            bss: Vec::new(),
+        };
+        assert!(encode(&assembly).is_err());
+    }
+
+    #[test]
+    fn locs_and_pushes_become_debug_info() {
+        let loc = |line| {
+            Asm::Loc(Loc {
+                file: "/src/f.zila",
+                line,
+                col: 1,
+            })
+        };
+        let f = Symbol::Str(0);
+        let assembly = Assembly {
+            text: vec![
+                Asm::Label(Symbol::Start),
+                Asm::Call(f),
+                Asm::Label(f),
+                loc(1),
+                Asm::Push(Reg::Rcx),
+                loc(2),
+                Asm::Syscall,
+                Asm::Pop(Reg::Rcx),
+                Asm::Ret,
+            ],
+            ..Default::default()
+        };
+        let executable = encode(&assembly).unwrap();
+
+        let names = executable
+            .debug_sections
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                ".debug_abbrev",
+                ".debug_info",
+                ".debug_line",
+                ".debug_frame"
+            ]
+        );
+
+        let mut encoder = Encoder::default();
+        for asm in &assembly.text {
+            encoder.encode(asm);
+        }
+        let lines = encoder
+            .lines
+            .iter()
+            .map(|&(offset, loc)| (offset, loc.line))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [(5, 1), (6, 2)]);
+        assert_eq!(
+            functions(&encoder.labels, &encoder.stack_moves, encoder.text.len()),
+            [
+                Function {
+                    start: 0,
+                    end: 5,
+                    is_entry: true,
+                    frame_sizes: vec![],
+                },
+                Function {
+                    start: 5,
+                    end: 10,
+                    is_entry: false,
+                    frame_sizes: vec![(6, 16), (9, 8)],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn assembly_without_locs_has_no_debug_info() {
+        let assembly = Assembly {
+            text: vec![Asm::Label(Symbol::Start), Asm::Ret],
+            ..Default::default()
+        };
+        assert!(encode(&assembly).unwrap().debug_sections.is_empty());
+    }
+
+    #[test]
+    fn procs_are_global_and_sized() {
+        let f = Symbol::Proc(Label::new(0, Some("2dup")));
+        let assembly = Assembly {
+            text: vec![
+                Asm::Label(Symbol::Start),
+                Asm::Call(f),
+                Asm::Label(Symbol::StackError(StackError::Overflow)),
+                Asm::Syscall,
+                Asm::Label(f),
+                Asm::Ret,
+            ],
+            ..Default::default()
+        };
+        let symbols = encode(&assembly)
+            .unwrap()
+            .symbols
+            .into_iter()
+            .map(|symbol| (symbol.name, symbol.size, symbol.global))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            symbols,
+            [
+                ("_start".into(), 5, true),
+                ("data_stack_overflow".into(), 2, false),
+                ("zila_0_2dup".into(), 1, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn objects_leave_data_and_c_functions_to_the_linker() {
+        let f = Symbol::Proc(Label::new(0, Some("f")));
+        let assembly = Assembly {
+            text: vec![
+                Asm::Label(Symbol::Main),
+                Asm::Lea(Reg::Rcx, Mem::Rel(Symbol::DataStack)),
+                Asm::Call(f),
+                Asm::Ret,
+                Asm::Label(f),
+                Asm::Call(Symbol::Extern("malloc")),
+                Asm::Lea(Reg::Rax, Mem::Rel(Symbol::Str(1))),
+                Asm::Call(Symbol::Extern("malloc")),
+                Asm::Ret,
+            ],
+            rodata: vec![
+                (Symbol::Str(0), b"a".to_vec()),
+                (Symbol::Str(1), b"b".to_vec()),
+            ],
+            bss: vec![(Symbol::DataStack, 16)],
+        };
+        let object = encode_object(&assembly).unwrap();
+
+        assert_eq!(object.externs, ["malloc"]);
+        assert_eq!(
+            object.relocations,
+            [
+                Relocation::Section {
+                    offset: 3,
+                    section: Section::Bss,
+                    target: 0,
+                },
+                Relocation::Extern {
+                    offset: 14,
+                    index: 0,
+                },
+                Relocation::Section {
+                    offset: 21,
+                    section: Section::Rodata,
+                    target: 1,
+                },
+                Relocation::Extern {
+                    offset: 26,
+                    index: 0,
+                },
+            ]
+        );
+        // Calls within `.text` are resolved already.
+        assert_eq!(&object.text[7..12], [0xe8, 0x01, 0, 0, 0]);
+    }
+}
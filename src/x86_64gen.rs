@@ -1,273 +1,1446 @@
-use std::{
-    fmt,
-    io::{self, Write},
-};
-
 use crate::{
-    compiler::{Instruction, Label, Proc},
+    analyzer::{MAX_FFI_ARGS, SYSCALLS},
+    backend::Backend,
+    compiler::{Entry, Instruction, Proc},
+    diagnostic,
     lexer::Span,
+    x86_64asm::{Asm, Assembly, Cond, Loc, Mem, Reg, StackError, Symbol},
 };
 
-impl fmt::Display for Label<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.name() {
-            Some(name) => write!(f, "proc_{}_{}", self.id(), sanitize(name)),
-            None => write!(f, "proc_{}", self.id()),
+/// How many of the top data stack slots are kept in registers when
+/// optimizing.
+pub const CACHED_SLOTS: usize = 2;
+
+/// Registers that hold cached stack slots. `syscall` preserves all of them
+/// and no instruction uses them as scratch, so only calls and the arguments
+/// of system calls force a spill.
+const CACHE_REGS: [Reg; 3] = [Reg::R8, Reg::R9, Reg::R10];
+
+/// The smallest buffer standard input is read into. A full buffer is
+/// replaced by one with room for this much more than it had left to
+/// consume.
+const INPUT_CHUNK: i32 = 64 << 10;
+
+/// Reported when there is no memory left to read input into.
+const OUT_OF_MEMORY_MESSAGE: &str = "out of memory\n";
+
+/// Points one past the topmost slot that is in memory.
+const SP: Reg = Reg::Rcx;
+
+/// The registers the System V ABI passes the first integer arguments in.
+const ARG_REGS: [Reg; MAX_FFI_ARGS] = [Reg::Rdi, Reg::Rsi, Reg::Rdx, Reg::Rcx, Reg::R8, Reg::R9];
+
+/// The registers Linux takes a system call's number and arguments in.
+const SYSCALL_REGS: [Reg; SYSCALLS.len()] = [
+    Reg::Rax,
+    Reg::Rdi,
+    Reg::Rsi,
+    Reg::Rdx,
+    Reg::R10,
+    Reg::R8,
+    Reg::R9,
+];
+
+/// The top slots of the data stack that live in registers, bottom first.
+/// Every slot below them is in memory under `rcx`. The cache is empty at
+/// the start and end of every proc and around calls.
+struct Cache {
+    regs: Vec<Reg>,
+    limit: usize,
+}
+
+impl Cache {
+    fn new(limit: usize) -> Self {
+        Self {
+            regs: Vec::new(),
+            limit,
+        }
+    }
+
+    fn free_reg(&self) -> Option<Reg> {
+        CACHE_REGS.into_iter().find(|reg| !self.regs.contains(reg))
+    }
+
+    /// Pushes a new slot in a free register, spilling the bottom of the
+    /// cache if none is left.
+    fn push_new(&mut self, code: &mut Vec<Asm>) -> Reg {
+        let reg = match self.free_reg() {
+            Some(reg) => reg,
+            None => {
+                self.spill(1, code);
+                self.free_reg().unwrap()
+            }
+        };
+        self.regs.push(reg);
+        reg
+    }
+
+    fn pop(&mut self) -> Reg {
+        self.regs.pop().expect("cache was filled first")
+    }
+
+    fn top(&self) -> Reg {
+        *self.regs.last().expect("cache was filled first")
+    }
+
+    /// Loads slots from memory until the top `n` are cached.
+    fn fill(&mut self, n: usize, code: &mut Vec<Asm>) {
+        let missing = n.saturating_sub(self.regs.len());
+        if missing == 0 {
+            return;
+        }
+
+        for i in 0..missing {
+            let reg = self.free_reg().expect("at most three slots are cached");
+            code.push(Asm::Load(reg, Mem::Base(SP, -8 * (i as i32 + 1))));
+            self.regs.insert(0, reg);
         }
+        code.push(Asm::SubImm(SP, 8 * missing as i32));
+    }
+
+    /// Stores the bottom `n` cached slots to memory.
+    fn spill(&mut self, n: usize, code: &mut Vec<Asm>) {
+        if n == 0 {
+            return;
+        }
+
+        for (i, reg) in self.regs.drain(..n).enumerate() {
+            code.push(Asm::Store(Mem::Base(SP, 8 * i as i32), reg));
+        }
+        code.push(Asm::AddImm(SP, 8 * n as i32));
+    }
+
+    fn flush(&mut self, code: &mut Vec<Asm>) {
+        self.spill(self.regs.len(), code);
+    }
+
+    /// Spills down to the limit between instructions.
+    fn trim(&mut self, code: &mut Vec<Asm>) {
+        self.spill(self.regs.len().saturating_sub(self.limit), code);
     }
 }
 
-fn sanitize(s: &str) -> String {
-    s.chars().filter(|c| c.is_alphanumeric() || *c == '_').collect()
+/// The source file the program was compiled from, with the offsets at
+/// which its lines start.
+struct Source<'src> {
+    file: &'src str,
+    text: &'src str,
+    line_starts: Vec<usize>,
 }
 
-pub struct Generator<'src> {
-    procs: &'src [Proc<'src>],
+impl<'src> Source<'src> {
+    fn new(file: &'src str, text: &'src str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            file,
+            text,
+            line_starts,
+        }
+    }
+
+    fn loc(&self, span: Span) -> Loc<'src> {
+        let start = span.parts().0;
+        let line = self
+            .line_starts
+            .partition_point(|&line_start| line_start <= start);
+        let line_start = self.line_starts[line - 1];
+        let (_, col) = diagnostic::line_col(&self.text[line_start..], start - line_start);
+        Loc {
+            file: self.file,
+            line,
+            col,
+        }
+    }
+}
+
+/// Generates x86_64 Linux code, keeping up to `cached_slots` of the top
+/// stack slots in registers and `stack_slots` in memory.
+pub struct X86_64<'src> {
     string_literals: &'src [Box<str>],
+    stack_slots: usize,
+    source: Option<Source<'src>>,
+    link_libc: bool,
+    /// Whether any proc uses `read-line` or `read-all`.
+    reads_input: bool,
+    cache: Cache,
+    code: Vec<Asm<'src>>,
 }
 
-impl<'src> Generator<'src> {
-    pub fn new(procs: &'src [Proc<'src>], string_literals: &'src [Box<str>]) -> Self {
+impl<'src> X86_64<'src> {
+    pub fn new(string_literals: &'src [Box<str>], cached_slots: usize, stack_slots: usize) -> Self {
         Self {
-            procs,
             string_literals,
+            stack_slots,
+            source: None,
+            link_libc: false,
+            reads_input: false,
+            cache: Cache::new(cached_slots),
+            code: Vec::new(),
         }
     }
 
-    pub fn generate(
-        main_proc: Label,
-        procs: &'src [Proc<'src>],
-        string_literals: &'src [Box<str>],
-        out: &mut impl Write,
-    ) -> io::Result<()> {
-        let generator = Self::new(procs, string_literals);
-
-        generator.gen_header(main_proc, out)?;
-        for proc in procs {
-            generator.gen_proc(proc.label(), out)?;
-        }
+    /// Marks the code of each instruction with where it is in `source`,
+    /// which was read from `file`, for debuggers.
+    pub fn with_source(mut self, file: &'src str, source: &'src str) -> Self {
+        self.source = Some(Source::new(file, source));
+        self
+    }
 
-        Ok(())
+    /// Generates code to be linked with libc: the entry point is C's `main`,
+    /// C functions can be called, and `exit` goes through C's `exit` so that
+    /// its buffers are flushed.
+    pub fn with_libc(mut self) -> Self {
+        self.link_libc = true;
+        self
     }
 
-    fn get_proc(&self, label: Label) -> &Proc<'src> {
-        &self.procs[label.id()]
+    /// Writes the message for `error` to stderr and exits with 1.
+    fn gen_stack_error(&mut self, error: StackError) {
+        let code = &mut self.code;
+        code.push(Asm::Label(Symbol::StackError(error)));
+        code.push(Asm::MovImm(Reg::Rax, 1));
+        code.push(Asm::MovImm(Reg::Rdi, 2));
+        code.push(Asm::Lea(
+            Reg::Rsi,
+            Mem::Rel(Symbol::StackErrorMessage(error)),
+        ));
+        code.push(Asm::MovImm(Reg::Rdx, error.message().len() as i64));
+        code.push(Asm::Syscall);
+        code.push(Asm::MovImm(Reg::Rax, 60));
+        code.push(Asm::MovImm(Reg::Rdi, 1));
+        code.push(Asm::Syscall);
     }
 
-    fn gen_header(&self, main_proc: Label, out: &mut impl Write) -> io::Result<()> {
-        writeln!(out, "section .bss")?;
-        writeln!(out, "align 8")?;
-        writeln!(out, "data_stack: resq 1024")?;
+    /// The routines behind `argv-nth` and `getenv`. Runtime routines are
+    /// only called with an empty cache, so they may use its registers, but
+    /// keep `rcx`.
+    fn gen_runtime(&mut self) {
+        use Asm::*;
+        use Reg::*;
+        use Symbol::Runtime;
 
-        writeln!(out, "section .rodata")?;
+        let at = |reg| Mem::Base(reg, 0);
+        self.code.extend([
+            // Takes a null-terminated string in `rsi` and leaves its length
+            // in `rdx`.
+            Label(Runtime("strlen")),
+            Mov(Rdx, Rsi),
+            Label(Runtime("strlen_byte")),
+            LoadByte(Rax, at(Rdx)),
+            Test(Rax, Rax),
+            Jcc(Cond::E, Runtime("strlen_end")),
+            AddImm(Rdx, 1),
+            Jmp(Runtime("strlen_byte")),
+            Label(Runtime("strlen_end")),
+            Sub(Rdx, Rsi),
+            Ret,
+            // Takes an index in `rax` and leaves that argument's pointer in
+            // `rax` and length in `rdx`, or the empty string past the end.
+            Label(Runtime("argv_nth")),
+            Load(Rdx, Mem::Rel(Symbol::Argc)),
+            Cmp(Rax, Rdx),
+            Jcc(Cond::B, Runtime("argv_nth_found")),
+            MovImm(Rax, 0),
+            MovImm(Rdx, 0),
+            Ret,
+            Label(Runtime("argv_nth_found")),
+            MovImm(Rdx, 8),
+            Imul(Rax, Rdx),
+            Load(Rsi, Mem::Rel(Symbol::Argv)),
+            Add(Rax, Rsi),
+            Load(Rsi, at(Rax)),
+            Call(Runtime("strlen")),
+            Mov(Rax, Rsi),
+            Ret,
+            // Takes a name's pointer in `rsi` and length in `rdx`, and
+            // leaves its value's pointer in `rax`, length in `rdx` and
+            // whether it is set in `rdi`. Names stop at the first `=` of an
+            // entry, so one with `=` or a null byte in it is never set.
+            Label(Runtime("getenv")),
+            Add(Rdx, Rsi),
+            Load(Rdi, Mem::Rel(Symbol::Envp)),
+            Label(Runtime("getenv_entry")),
+            Load(R8, at(Rdi)),
+            AddImm(Rdi, 8),
+            Test(R8, R8),
+            Jcc(Cond::E, Runtime("getenv_unset")),
+            Mov(R9, Rsi),
+            Label(Runtime("getenv_byte")),
+            LoadByte(Rax, at(R8)),
+            Cmp(R9, Rdx),
+            Jcc(Cond::E, Runtime("getenv_name_end")),
+            LoadByte(R10, at(R9)),
+            Cmp(Rax, R10),
+            Jcc(Cond::E, Runtime("getenv_same")),
+            Jmp(Runtime("getenv_entry")),
+            Label(Runtime("getenv_same")),
+            Test(Rax, Rax),
+            Jcc(Cond::E, Runtime("getenv_entry")),
+            MovImm(R10, b'=' as i64),
+            Cmp(Rax, R10),
+            Jcc(Cond::E, Runtime("getenv_entry")),
+            AddImm(R8, 1),
+            AddImm(R9, 1),
+            Jmp(Runtime("getenv_byte")),
+            Label(Runtime("getenv_name_end")),
+            MovImm(R10, b'=' as i64),
+            Cmp(Rax, R10),
+            Jcc(Cond::E, Runtime("getenv_set")),
+            Jmp(Runtime("getenv_entry")),
+            Label(Runtime("getenv_set")),
+            Lea(Rsi, Mem::Base(R8, 1)),
+            Call(Runtime("strlen")),
+            Mov(Rax, Rsi),
+            MovImm(Rdi, -1),
+            Ret,
+            Label(Runtime("getenv_unset")),
+            MovImm(Rax, 0),
+            MovImm(Rdx, 0),
+            MovImm(Rdi, 0),
+            Ret,
+        ]);
+    }
 
-        for (i, string_literal) in self.string_literals.iter().enumerate() {
-            write!(out, "str_{i}: db ")?;
-            let bytes = string_literal.as_bytes();
-            for (l, byte) in bytes.iter().enumerate() {
-                write!(out, "{byte}")?;
-                if l < bytes.len() - 1 {
-                    write!(out, ",")?;
-                }
-            }
-            writeln!(out)?;
-        }
+    /// The routines behind `read-line` and `read-all`, which are only
+    /// generated for programs that read input. Strings read from the
+    /// buffer point into it, so when it is full the input that has not
+    /// been consumed is moved to a new, bigger one, and the old one is kept
+    /// unless nothing was consumed from it.
+    fn gen_input_runtime(&mut self) {
+        use Asm::*;
+        use Reg::*;
+        use Symbol::{Input, InputEnd, InputLimit, InputPos, Runtime};
+
+        let at = |reg| Mem::Base(reg, 0);
+        self.code.extend([
+            // Appends what one `read` of stdin returns to the buffer and
+            // leaves how many bytes that was in `rax`, which is 0 at its
+            // end. Keeps `r8`.
+            Label(Runtime("read_input")),
+            Load(Rsi, Mem::Rel(InputEnd)),
+            Load(Rdx, Mem::Rel(InputLimit)),
+            Cmp(Rsi, Rdx),
+            Jcc(Cond::B, Runtime("read_input_read")),
+            Call(Runtime("grow_input")),
+            Load(Rsi, Mem::Rel(InputEnd)),
+            Load(Rdx, Mem::Rel(InputLimit)),
+            Label(Runtime("read_input_read")),
+            Sub(Rdx, Rsi),
+            MovImm(Rax, 0),
+            MovImm(Rdi, 0),
+            Push(SP),
+            Syscall,
+            Pop(SP),
+            Test(Rax, Rax),
+            Jcc(Cond::L, Runtime("read_input_failed")),
+            Load(Rdx, Mem::Rel(InputEnd)),
+            Add(Rdx, Rax),
+            Store(Mem::Rel(InputEnd), Rdx),
+            Ret,
+            Label(Runtime("read_input_failed")),
+            MovImm(Rax, 0),
+            Ret,
+            // Maps a buffer with room for twice the unconsumed input and
+            // another chunk, and moves the unconsumed input there. Keeps
+            // `r8`.
+            Label(Runtime("grow_input")),
+            Push(R8),
+            Load(Rsi, Mem::Rel(InputEnd)),
+            Load(Rdx, Mem::Rel(InputPos)),
+            Sub(Rsi, Rdx),
+            Add(Rsi, Rsi),
+            AddImm(Rsi, INPUT_CHUNK),
+            Push(Rsi),
+            // mmap(NULL, rsi, PROT_READ | PROT_WRITE,
+            //      MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+            MovImm(Rax, 9),
+            MovImm(Rdi, 0),
+            MovImm(Rdx, 3),
+            MovImm(R10, 0x22),
+            MovImm(R8, -1),
+            MovImm(R9, 0),
+            Push(SP),
+            Syscall,
+            Pop(SP),
+            Pop(Rdx),
+            Test(Rax, Rax),
+            Jcc(Cond::L, Runtime("out_of_memory")),
+            Mov(Rdi, Rax),
+            Load(Rsi, Mem::Rel(InputPos)),
+            Load(R9, Mem::Rel(InputEnd)),
+            Sub(R9, Rsi),
+            Push(SP),
+            Mov(SP, R9),
+            RepMovsb,
+            Pop(SP),
+            // `rdi` is now the end of the moved input.
+            Load(R9, Mem::Rel(InputPos)),
+            Load(R10, Mem::Rel(Input)),
+            Load(R8, Mem::Rel(InputLimit)),
+            Store(Mem::Rel(Input), Rax),
+            Store(Mem::Rel(InputPos), Rax),
+            Store(Mem::Rel(InputEnd), Rdi),
+            Add(Rdx, Rax),
+            Store(Mem::Rel(InputLimit), Rdx),
+            // No string points into the old buffer if nothing was consumed
+            // from it, so it can be unmapped.
+            Test(R10, R10),
+            Jcc(Cond::E, Runtime("grow_input_done")),
+            Cmp(R9, R10),
+            Jcc(Cond::E, Runtime("grow_input_unmap")),
+            Jmp(Runtime("grow_input_done")),
+            Label(Runtime("grow_input_unmap")),
+            MovImm(Rax, 11),
+            Mov(Rdi, R10),
+            Mov(Rsi, R8),
+            Sub(Rsi, R10),
+            Push(SP),
+            Syscall,
+            Pop(SP),
+            Label(Runtime("grow_input_done")),
+            Pop(R8),
+            Ret,
+            Label(Runtime("out_of_memory")),
+            MovImm(Rax, 1),
+            MovImm(Rdi, 2),
+            Lea(Rsi, Mem::Rel(Runtime("out_of_memory_message"))),
+            MovImm(Rdx, OUT_OF_MEMORY_MESSAGE.len() as i64),
+            Syscall,
+            MovImm(Rax, 60),
+            MovImm(Rdi, 1),
+            Syscall,
+            // Leaves the next line's pointer in `rax`, length in `rdx` and
+            // whether there was one in `rdi`. `r8` counts the bytes scanned
+            // for the newline, which stays right if the input moves.
+            Label(Runtime("read_line")),
+            MovImm(R8, 0),
+            Label(Runtime("read_line_byte")),
+            Load(Rsi, Mem::Rel(InputPos)),
+            Add(Rsi, R8),
+            Load(Rdx, Mem::Rel(InputEnd)),
+            Cmp(Rsi, Rdx),
+            Jcc(Cond::E, Runtime("read_line_more")),
+            LoadByte(Rax, at(Rsi)),
+            AddImm(R8, 1),
+            MovImm(R9, b'\n' as i64),
+            Cmp(Rax, R9),
+            Jcc(Cond::E, Runtime("read_line_newline")),
+            Jmp(Runtime("read_line_byte")),
+            Label(Runtime("read_line_more")),
+            Call(Runtime("read_input")),
+            Test(Rax, Rax),
+            Jcc(Cond::E, Runtime("read_line_end")),
+            Jmp(Runtime("read_line_byte")),
+            // The line is `rdx` bytes long and the next one starts `r8`
+            // bytes in.
+            Label(Runtime("read_line_newline")),
+            Lea(Rdx, Mem::Base(R8, -1)),
+            Jmp(Runtime("read_line_found")),
+            Label(Runtime("read_line_end")),
+            Mov(Rdx, R8),
+            Test(R8, R8),
+            Jcc(Cond::E, Runtime("read_line_none")),
+            Label(Runtime("read_line_found")),
+            Load(Rax, Mem::Rel(InputPos)),
+            Mov(Rsi, Rax),
+            Add(Rsi, R8),
+            Store(Mem::Rel(InputPos), Rsi),
+            MovImm(Rdi, -1),
+            Ret,
+            Label(Runtime("read_line_none")),
+            MovImm(Rax, 0),
+            MovImm(Rdi, 0),
+            Ret,
+            // Leaves the rest of the input's pointer in `rax` and length in
+            // `rdx`.
+            Label(Runtime("read_all")),
+            Call(Runtime("read_input")),
+            Test(Rax, Rax),
+            Jcc(Cond::E, Runtime("read_all_end")),
+            Jmp(Runtime("read_all")),
+            Label(Runtime("read_all_end")),
+            Load(Rax, Mem::Rel(InputPos)),
+            Load(Rdx, Mem::Rel(InputEnd)),
+            Store(Mem::Rel(InputPos), Rdx),
+            Sub(Rdx, Rax),
+            Ret,
+        ]);
+    }
+}
 
-        writeln!(out, "section .text")?;
-        writeln!(out, "global _start")?;
+impl<'src> Backend<'src> for X86_64<'src> {
+    type Output = Assembly<'src>;
 
-        writeln!(out, "_start:")?;
-        writeln!(out, "    lea rcx, [rel data_stack]")?;
-        writeln!(out, "    call {main_proc}")?;
-        writeln!(out, "    mov rax, 60")?;
-        writeln!(out, "    xor rdi, rdi")?;
-        writeln!(out, "    syscall")?;
+    fn gen_start(&mut self, entry: Entry<'src>) {
+        let code = &mut self.code;
+        // C's `main` returns the exit code, where `_start` has to exit.
+        let (start, exit_code) = if self.link_libc {
+            (Symbol::Main, Reg::Rax)
+        } else {
+            (Symbol::Start, Reg::Rdi)
+        };
+        code.push(Asm::Label(start));
+        if !self.link_libc {
+            // Linux starts a program with `argc` on top of the stack, then
+            // `argv` and `envp`, each ended by a null pointer. C's `main` is
+            // passed the same in `rdi`, `rsi` and `rdx`.
+            code.push(Asm::Load(Reg::Rdi, Mem::Base(Reg::Rsp, 0)));
+            code.push(Asm::Lea(Reg::Rsi, Mem::Base(Reg::Rsp, 8)));
+            code.push(Asm::MovImm(Reg::Rdx, 8));
+            code.push(Asm::Imul(Reg::Rdx, Reg::Rdi));
+            code.push(Asm::Add(Reg::Rdx, Reg::Rsi));
+            code.push(Asm::AddImm(Reg::Rdx, 8));
+        }
+        code.push(Asm::Store(Mem::Rel(Symbol::Argc), Reg::Rdi));
+        code.push(Asm::Store(Mem::Rel(Symbol::Argv), Reg::Rsi));
+        code.push(Asm::Store(Mem::Rel(Symbol::Envp), Reg::Rdx));
+        code.push(Asm::Lea(SP, Mem::Rel(Symbol::DataStack)));
+        code.push(Asm::Call(Symbol::Proc(entry.label())));
+        if entry.returns_exit_code() {
+            code.push(Asm::Load(exit_code, Mem::Base(SP, -8)));
+        } else {
+            code.push(Asm::MovImm(exit_code, 0));
+        }
+        if self.link_libc {
+            code.push(Asm::Ret);
+        } else {
+            code.push(Asm::MovImm(Reg::Rax, 60));
+            code.push(Asm::Syscall);
+        }
 
-        Ok(())
+        for error in [StackError::Overflow, StackError::Underflow] {
+            self.gen_stack_error(error);
+        }
+        self.gen_runtime();
     }
 
-    fn gen_proc(&self, label: Label, out: &mut impl Write) -> io::Result<()> {
-        writeln!(out, "{label}:")?;
+    fn gen_proc(&mut self, proc: &Proc<'src>) {
+        self.code.push(Asm::Label(Symbol::Proc(proc.label())));
 
-        let proc = self.get_proc(label);
+        // The entry check belongs to the first instruction.
+        if let (Some(source), Some(&(span, _))) = (&self.source, proc.code().first()) {
+            self.code.push(Asm::Loc(source.loc(span)));
+        }
+    }
 
-        for &(span, instruction) in proc.code() {
-            self.gen_instruction(span, instruction, out)?;
+    /// Only called with an empty cache, so `rcx` is the top.
+    fn gen_stack_check(&mut self, below: usize, above: usize) {
+        let checks = [
+            (
+                above,
+                8,
+                Symbol::DataStackEnd,
+                Cond::A,
+                StackError::Overflow,
+            ),
+            (below, -8, Symbol::DataStack, Cond::B, StackError::Underflow),
+        ];
+        for (slots, direction, bound, cond, error) in checks {
+            if slots > 0 {
+                let code = &mut self.code;
+                code.push(Asm::Lea(Reg::Rax, Mem::Base(SP, direction * slots as i32)));
+                code.push(Asm::Lea(Reg::Rdx, Mem::Rel(bound)));
+                code.push(Asm::Cmp(Reg::Rax, Reg::Rdx));
+                code.push(Asm::Jcc(cond, Symbol::StackError(error)));
+            }
         }
+    }
 
-        writeln!(out, "    ; RETURN")?;
-        writeln!(out, "    ret")?;
+    fn gen_instruction(&mut self, span: Span, instruction: Instruction<'src>, tail: bool) {
+        match &self.source {
+            Some(source) => {
+                let loc = source.loc(span);
+                self.code.push(Asm::Loc(loc));
+                self.code
+                    .push(Asm::Comment(format!("{loc} -- {instruction}")));
+            }
+            None => self.code.push(Asm::Comment(instruction.to_string())),
+        }
+        self.reads_input |= matches!(instruction, Instruction::ReadLine | Instruction::ReadAll);
+        gen_instruction(
+            instruction,
+            tail,
+            self.string_literals,
+            self.link_libc,
+            &mut self.cache,
+            &mut self.code,
+        );
+        self.cache.trim(&mut self.code);
+    }
 
-        Ok(())
+    /// A tail call jumps straight to the callee, whose own `ret` returns to
+    /// our caller, so this is only needed after other instructions.
+    fn gen_return(&mut self) {
+        self.cache.flush(&mut self.code);
+        self.code.push(Asm::Comment("RETURN".into()));
+        self.code.push(Asm::Ret);
     }
 
-    fn emit_copy_up(&self, out: &mut impl Write, offset: isize, size: usize) -> io::Result<()> {
-        for i in 0..size {
-            let byte_offset = offset - (8 * (size - i) as isize);
-            writeln!(out, "    mov rax, [rcx + {byte_offset}]")?;
-            writeln!(out, "    mov [rcx + {}], rax", i * 8)?;
+    fn finish(mut self) -> Assembly<'src> {
+        if self.reads_input {
+            self.gen_input_runtime();
+        }
+
+        let mut assembly = Assembly {
+            text: self.code,
+            ..Default::default()
+        };
+
+        assembly.bss.push((Symbol::DataStack, self.stack_slots * 8));
+        assembly.bss.push((Symbol::DataStackEnd, 0));
+        for symbol in [Symbol::Argc, Symbol::Argv, Symbol::Envp] {
+            assembly.bss.push((symbol, 8));
+        }
+        if self.reads_input {
+            for symbol in [
+                Symbol::Input,
+                Symbol::InputPos,
+                Symbol::InputEnd,
+                Symbol::InputLimit,
+            ] {
+                assembly.bss.push((symbol, 8));
+            }
+            assembly.rodata.push((
+                Symbol::Runtime("out_of_memory_message"),
+                OUT_OF_MEMORY_MESSAGE.as_bytes().to_vec(),
+            ));
+        }
+        for (i, string_literal) in self.string_literals.iter().enumerate() {
+            assembly
+                .rodata
+                .push((Symbol::Str(i), string_literal.as_bytes().to_vec()));
+        }
+        for error in [StackError::Overflow, StackError::Underflow] {
+            assembly.rodata.push((
+                Symbol::StackErrorMessage(error),
+                error.message().as_bytes().to_vec(),
+            ));
         }
-        writeln!(out, "    add rcx, {}", size * 8)?;
-        Ok(())
-    }
 
-    fn emit_drop(&self, out: &mut impl Write, size: usize) -> io::Result<()> {
-        writeln!(out, "    sub rcx, {}", size * 8)?;
-        Ok(())
+        assembly
     }
+}
 
-    fn gen_instruction(
-        &self,
-        span: Span,
-        instruction: Instruction,
-        out: &mut impl Write,
-    ) -> io::Result<()> {
-        match instruction {
-            Instruction::PushInt(i) => {
-                writeln!(out, "    ; {:?} -- PUSHINT", span)?;
-                writeln!(out, "    mov qword [rcx], {i}")?;
-                writeln!(out, "    add rcx, 8")?;
+fn gen_instruction<'src>(
+    instruction: Instruction<'src>,
+    tail: bool,
+    string_literals: &[Box<str>],
+    link_libc: bool,
+    cache: &mut Cache,
+    code: &mut Vec<Asm<'src>>,
+) {
+    match instruction {
+        Instruction::PushInt(i) => {
+            let reg = cache.push_new(code);
+            code.push(Asm::MovImm(reg, i as i64));
+        }
+        Instruction::PushBool(b) => {
+            let reg = cache.push_new(code);
+            code.push(Asm::MovImm(reg, if b { -1 } else { 0 }));
+        }
+        Instruction::PushString(i) => {
+            let ptr = cache.push_new(code);
+            code.push(Asm::Lea(ptr, Mem::Rel(Symbol::Str(i))));
+            let len = cache.push_new(code);
+            code.push(Asm::MovImm(len, string_literals[i].len() as i64));
+        }
+        Instruction::PushQuote(q) => {
+            let reg = cache.push_new(code);
+            code.push(Asm::Lea(reg, Mem::Rel(Symbol::Proc(q))));
+        }
+
+        Instruction::Apply => {
+            cache.fill(1, code);
+            let quotation = cache.pop();
+            cache.flush(code);
+            if tail {
+                code.push(Asm::JmpReg(quotation));
+            } else {
+                code.push(Asm::CallReg(quotation));
             }
-            Instruction::PushBool(b) => {
-                writeln!(out, "    ; {:?} -- PUSHBOOL", span)?;
-                writeln!(
-                    out,
-                    "    mov qword [rcx], {}",
-                    if b { -1isize } else { 0isize }
-                )?;
-                writeln!(out, "    add rcx, 8")?;
+        }
+        Instruction::Call(proc) => {
+            cache.flush(code);
+            if tail {
+                code.push(Asm::Jmp(Symbol::Proc(proc)));
+            } else {
+                code.push(Asm::Call(Symbol::Proc(proc)));
             }
-            Instruction::PushString(i) => {
-                writeln!(out, "    ; {:?} -- PUSHSTRING", span)?;
-                writeln!(out, "    lea rax, [rel str_{i}]")?;
-                writeln!(out, "    mov [rcx], rax")?;
-                writeln!(
-                    out,
-                    "    mov qword [rcx + 8], {}",
-                    self.string_literals[i].len()
-                )?;
-                writeln!(out, "    add rcx, 16")?;
+        }
+        Instruction::Branch { size: 1 } => {
+            cache.fill(3, code);
+            let if_false = cache.pop();
+            let if_true = cache.pop();
+            let cond = cache.pop();
+            code.push(Asm::Test(cond, cond));
+            code.push(Asm::Cmov(Cond::E, if_true, if_false));
+            cache.regs.push(if_true);
+        }
+        Instruction::Branch { size } => {
+            cache.flush(code);
+            emit_branch(size, code);
+        }
+
+        Instruction::Exit => {
+            cache.fill(1, code);
+            let code_reg = cache.pop();
+            code.push(Asm::Mov(Reg::Rdi, code_reg));
+            if link_libc {
+                // C's `exit` never returns, so `rsp` needn't be restored.
+                code.push(Asm::AndImm(Reg::Rsp, -16));
+                code.push(Asm::Call(Symbol::Extern("exit")));
+            } else {
+                code.push(Asm::MovImm(Reg::Rax, 60));
+                code.push(Asm::Syscall);
             }
-            Instruction::PushQuote(q) => {
-                writeln!(out, "    ; {:?} -- PUSHQUOTE", span)?;
-                writeln!(out, "    mov qword [rcx], {q}")?;
-                writeln!(out, "    add rcx, 8")?;
+        }
+
+        Instruction::Puts => {
+            cache.fill(2, code);
+            let len = cache.pop();
+            let ptr = cache.pop();
+            code.push(Asm::Mov(Reg::Rsi, ptr));
+            code.push(Asm::Mov(Reg::Rdx, len));
+            code.push(Asm::MovImm(Reg::Rdi, 1));
+            code.push(Asm::MovImm(Reg::Rax, 1));
+            code.push(Asm::Push(SP));
+            code.push(Asm::Syscall);
+            code.push(Asm::Pop(SP));
+        }
+
+        Instruction::Argc => {
+            let argc = cache.push_new(code);
+            code.push(Asm::Load(argc, Mem::Rel(Symbol::Argc)));
+        }
+        Instruction::ArgvNth => {
+            cache.fill(1, code);
+            let n = cache.pop();
+            code.push(Asm::Mov(Reg::Rax, n));
+            emit_runtime_call("argv_nth", &[Reg::Rax, Reg::Rdx], cache, code);
+        }
+        Instruction::Getenv => {
+            cache.fill(2, code);
+            let len = cache.pop();
+            let ptr = cache.pop();
+            code.push(Asm::Mov(Reg::Rsi, ptr));
+            code.push(Asm::Mov(Reg::Rdx, len));
+            emit_runtime_call("getenv", &[Reg::Rax, Reg::Rdx, Reg::Rdi], cache, code);
+        }
+
+        Instruction::ReadLine => {
+            emit_runtime_call("read_line", &[Reg::Rax, Reg::Rdx, Reg::Rdi], cache, code);
+        }
+        Instruction::ReadAll => emit_runtime_call("read_all", &[Reg::Rax, Reg::Rdx], cache, code),
+
+        Instruction::Syscall { args } => {
+            cache.flush(code);
+            code.push(Asm::SubImm(SP, 8 * (args as i32 + 1)));
+            for (i, reg) in SYSCALL_REGS[..=args].iter().enumerate() {
+                code.push(Asm::Load(*reg, Mem::Base(SP, 8 * i as i32)));
             }
+            // `syscall` clobbers `rcx`.
+            code.push(Asm::Push(SP));
+            code.push(Asm::Syscall);
+            code.push(Asm::Pop(SP));
+            let result = cache.push_new(code);
+            code.push(Asm::Mov(result, Reg::Rax));
+        }
 
-            Instruction::Apply => {
-                writeln!(out, "    ; {:?} -- APPLY", span)?;
-                writeln!(out, "    sub rcx, 8")?;
-                writeln!(out, "    call [rcx]")?;
+        Instruction::Ffi {
+            name,
+            inputs,
+            outputs,
+        } => {
+            cache.flush(code);
+            if inputs > 0 {
+                code.push(Asm::SubImm(SP, 8 * inputs as i32));
             }
-            Instruction::Call(proc) => {
-                writeln!(out, "    ; {:?} -- CALL", span)?;
-                writeln!(out, "    call {proc}")?;
+            emit_c_call(Symbol::Extern(name), inputs, code);
+            if outputs > 0 {
+                let result = cache.push_new(code);
+                code.push(Asm::Mov(result, Reg::Rax));
             }
-            Instruction::Branch { size } => {
-                writeln!(out, "    ; {:?} -- BRANCH", span)?;
+        }
 
-                let cond_off = -8 * (2 * size as isize + 1);
-                let true_off_start = -8 * (size as isize + 1);
-                let false_off_start = -8 * 1;
-                let result_off_start = cond_off;
+        Instruction::Add => emit_binary(cache, code, Asm::Add),
+        Instruction::Sub => emit_binary(cache, code, Asm::Sub),
+        Instruction::Mul => emit_binary(cache, code, Asm::Imul),
+        Instruction::Div => {
+            cache.fill(2, code);
+            let b = cache.pop();
+            let a = cache.top();
+            code.push(Asm::Mov(Reg::Rax, a));
+            code.push(Asm::Cqo);
+            code.push(Asm::Idiv(b));
+            code.push(Asm::Mov(a, Reg::Rax));
+        }
 
-                writeln!(out, "    mov rax, [rcx - {}]", -cond_off)?;
-                writeln!(out, "    mov rbx, rax")?;
-                writeln!(out, "    not rbx")?;
+        Instruction::Eq => emit_compare(cache, code, Cond::E),
+        Instruction::Lt => emit_compare(cache, code, Cond::L),
 
-                for i in 0..size {
-                    let true_i = true_off_start - 8 * i as isize;
-                    let false_i = false_off_start - 8 * i as isize;
-                    let res_i = result_off_start - 8 * i as isize;
+        Instruction::Dup { size: 1 } => {
+            cache.fill(1, code);
+            let top = cache.top();
+            let copy = cache.push_new(code);
+            code.push(Asm::Mov(copy, top));
+        }
+        Instruction::Dup { size } => {
+            cache.flush(code);
+            emit_copy_up(-8 * size as i32, size, code);
+        }
 
-                    writeln!(out, "    mov rdx, [rcx - {}]", -true_i)?;
-                    writeln!(out, "    and rdx, rax")?;
+        Instruction::Over {
+            size_a: 1,
+            size_b: 1,
+        } => {
+            cache.fill(2, code);
+            let under = cache.regs[cache.regs.len() - 2];
+            let copy = cache.push_new(code);
+            code.push(Asm::Mov(copy, under));
+        }
+        Instruction::Over { size_a, size_b } => {
+            cache.flush(code);
+            emit_copy_up(-8 * (size_a + size_b) as i32, size_b, code);
+        }
 
-                    writeln!(out, "    mov rsi, [rcx - {}]", -false_i)?;
-                    writeln!(out, "    and rsi, rbx")?;
+        Instruction::Drop { size } => {
+            let cached = size.min(cache.regs.len());
+            cache.regs.truncate(cache.regs.len() - cached);
+            if size > cached {
+                code.push(Asm::SubImm(SP, 8 * (size - cached) as i32));
+            }
+        }
 
-                    writeln!(out, "    or rdx, rsi")?;
-                    writeln!(out, "    mov [rcx - {}], rdx", -res_i)?;
-                }
+        Instruction::Swap {
+            size_a: 1,
+            size_b: 1,
+        } => {
+            cache.fill(2, code);
+            let len = cache.regs.len();
+            cache.regs.swap(len - 1, len - 2);
+        }
+        Instruction::Swap { size_a, size_b } => {
+            cache.flush(code);
+            emit_swap(size_a, size_b, code);
+        }
+    }
+}
 
-                writeln!(out, "    sub rcx, {}", 16 * size)?;
-            }
+fn emit_binary<'src>(cache: &mut Cache, code: &mut Vec<Asm<'src>>, op: fn(Reg, Reg) -> Asm<'src>) {
+    cache.fill(2, code);
+    let b = cache.pop();
+    let a = cache.top();
+    code.push(op(a, b));
+}
 
-            Instruction::Exit => {
-                writeln!(out, "    ; {:?} -- EXIT", span)?;
-                writeln!(out, "    mov rax, 60")?;
-                writeln!(out, "    mov rdi, [rcx - 8]")?;
-                writeln!(out, "    syscall")?;
-            }
+/// Calls one of the runtime routines, whose arguments are already in
+/// registers, and pushes the `results` it leaves in registers.
+fn emit_runtime_call<'src>(
+    routine: &'static str,
+    results: &[Reg],
+    cache: &mut Cache,
+    code: &mut Vec<Asm<'src>>,
+) {
+    cache.flush(code);
+    code.push(Asm::Call(Symbol::Runtime(routine)));
+    for &result in results {
+        let slot = cache.push_new(code);
+        code.push(Asm::Mov(slot, result));
+    }
+}
 
-            Instruction::Puts => {
-                writeln!(out, "    ; {:?} -- PUTS", span)?;
-                writeln!(out, "    push rcx")?;
-                writeln!(out, "    mov rdi, 1")?;
-                writeln!(out, "    mov rsi, [rcx - 16]")?;
-                writeln!(out, "    mov rdx, [rcx - 8]")?;
-                writeln!(out, "    mov rax, 1")?;
-                writeln!(out, "    syscall")?;
-                writeln!(out, "    pop rcx")?;
-                writeln!(out, "    sub rcx, 16")?;
-            }
+/// Calls a C function with the `args` slots from `rcx` up as its
+/// arguments. `rcx` is saved across the call, and `rsp` aligned to 16
+/// bytes as the System V ABI requires. Only used with an empty cache.
+fn emit_c_call<'src>(function: Symbol<'src>, args: usize, code: &mut Vec<Asm<'src>>) {
+    code.push(Asm::Push(SP));
+    code.push(Asm::Mov(Reg::Rax, Reg::Rsp));
+    code.push(Asm::AndImm(Reg::Rsp, -16));
+    code.push(Asm::SubImm(Reg::Rsp, 8));
+    code.push(Asm::Push(Reg::Rax));
 
-            Instruction::Add => {
-                writeln!(out, "    ; {:?} -- ADD", span)?;
-                writeln!(out, "    mov rax, [rcx - 8]")?;
-                writeln!(out, "    add [rcx - 16], rax")?;
-                writeln!(out, "    sub rcx, 8")?;
-            }
-            Instruction::Sub => {
-                writeln!(out, "    ; {:?} -- SUB", span)?;
-                writeln!(out, "    mov rax, [rcx - 8]")?;
-                writeln!(out, "    sub [rcx - 16], rax")?;
-                writeln!(out, "    sub rcx, 8")?;
-            }
-            Instruction::Mul => {
-                writeln!(out, "    ; {:?} -- MUL", span)?;
-                writeln!(out, "    mov rax, [rcx - 8]")?;
-                writeln!(out, "    imul [rcx - 16], rax")?;
-                writeln!(out, "    sub rcx, 8")?;
-            }
-            Instruction::Div => todo!(),
+    // `rcx` is an argument register too, so it is loaded last.
+    let mut order = (0..args).collect::<Vec<_>>();
+    order.sort_by_key(|&i| ARG_REGS[i] == SP);
+    for i in order {
+        code.push(Asm::Load(ARG_REGS[i], Mem::Base(SP, 8 * i as i32)));
+    }
+    // Variadic functions like `printf` read how many vector registers hold
+    // arguments from `al`.
+    code.push(Asm::MovImm(Reg::Rax, 0));
+    code.push(Asm::Call(function));
 
-            Instruction::Dup { size } => {
-                writeln!(out, "    ; {:?} -- DUP", span)?;
-                self.emit_copy_up(out, -(size as isize * 8), size)?;
-            }
+    code.push(Asm::Pop(Reg::Rsp));
+    code.push(Asm::Pop(SP));
+}
+
+/// Replaces the two ints on top with the all-ones or all-zeros bool
+/// that `cond` computes from comparing them.
+fn emit_compare<'src>(cache: &mut Cache, code: &mut Vec<Asm<'src>>, cond: Cond) {
+    cache.fill(2, code);
+    let b = cache.pop();
+    let a = cache.top();
+    code.push(Asm::Cmp(a, b));
+    code.push(Asm::Set(cond, a));
+    code.push(Asm::Movzx(a, a));
+    code.push(Asm::Neg(a));
+}
+
+/// Pushes a copy of the `size` slots that start `offset` bytes from
+/// `rcx`. Only used with an empty cache.
+fn emit_copy_up<'src>(offset: i32, size: usize, code: &mut Vec<Asm<'src>>) {
+    for i in 0..size as i32 {
+        code.push(Asm::Load(Reg::Rax, Mem::Base(SP, offset + 8 * i)));
+        code.push(Asm::Store(Mem::Base(SP, 8 * i), Reg::Rax));
+    }
+    code.push(Asm::AddImm(SP, 8 * size as i32));
+}
+
+/// Swaps two multi-slot values in memory, using the red zone below `rsp`
+/// to hold the top one. Only used with an empty cache.
+fn emit_swap<'src>(size_a: usize, size_b: usize, code: &mut Vec<Asm<'src>>) {
+    let slot = |i: usize| -8 * (i as i32 + 1);
+    let (sa, sb) = (size_a as i32 * 8, size_b as i32 * 8);
+
+    for i in 0..size_a {
+        code.push(Asm::Load(Reg::Rax, Mem::Base(SP, slot(i))));
+        code.push(Asm::Store(Mem::Base(Reg::Rsp, slot(i)), Reg::Rax));
+    }
+    for i in 0..size_b {
+        code.push(Asm::Load(Reg::Rax, Mem::Base(SP, slot(i) - sa)));
+        code.push(Asm::Store(Mem::Base(SP, slot(i)), Reg::Rax));
+    }
+    for i in 0..size_a {
+        code.push(Asm::Load(Reg::Rax, Mem::Base(Reg::Rsp, slot(i))));
+        code.push(Asm::Store(Mem::Base(SP, slot(i) - sb), Reg::Rax));
+    }
+}
+
+/// Keeps the true or the false block of `size` slots below the
+/// condition. Only used with an empty cache.
+fn emit_branch<'src>(size: usize, code: &mut Vec<Asm<'src>>) {
+    let cond = -8 * (2 * size as i32 + 1);
+    code.push(Asm::Load(Reg::Rax, Mem::Base(SP, cond)));
+    code.push(Asm::Test(Reg::Rax, Reg::Rax));
+
+    for i in 0..size as i32 {
+        let result = cond + 8 * i;
+        let if_true = result + 8;
+        let if_false = if_true + 8 * size as i32;
+        code.push(Asm::Load(Reg::Rdx, Mem::Base(SP, if_true)));
+        code.push(Asm::Load(Reg::Rsi, Mem::Base(SP, if_false)));
+        code.push(Asm::Cmov(Cond::E, Reg::Rdx, Reg::Rsi));
+        code.push(Asm::Store(Mem::Base(SP, result), Reg::Rdx));
+    }
+
+    code.push(Asm::SubImm(SP, 8 * (size as i32 + 1)));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        io::Write,
+        os::unix::fs::PermissionsExt,
+        path::PathBuf,
+        process::{Command, Stdio},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{
+        analyzer::Analyzer,
+        backend,
+        compiler::{Compiler, DATA_STACK_SLOTS},
+        interp::Interpreter,
+        lexer::Lexer,
+        optimizer::{self, OptLevel},
+        x86_64enc,
+    };
+
+    const COUNTDOWN: &str = ": loop dup 0 = [ drop ] [ 1 - loop ] ? apply ;
+                             : main 1000000 loop ;";
+
+    fn generate(source: &str, cached_slots: usize) -> String {
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        let (entry, procs, strings) = Compiler::compile(defs).unwrap();
+        let backend = X86_64::new(&strings, cached_slots, DATA_STACK_SLOTS);
+        backend::generate(backend, entry.unwrap(), &procs).to_string()
+    }
+
+    /// The lines of the proc whose label ends in `_{name}`.
+    fn proc_body<'a>(asm: &'a str, name: &str) -> Vec<&'a str> {
+        asm.lines()
+            .skip_while(|line| !line.ends_with(&format!("_{name}:")))
+            .skip(1)
+            .take_while(|line| line.starts_with(' '))
+            .filter(|line| !line.trim_start().starts_with(';'))
+            .collect()
+    }
+
+    /// Builds `source` into an executable in a fresh temporary directory.
+    fn build_native(source: &str, level: OptLevel, cached_slots: usize, name: &str) -> PathBuf {
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        let (entry, mut procs, strings) = Compiler::compile(defs).unwrap();
+        optimizer::optimize(&mut procs, level);
+        let backend = X86_64::new(&strings, cached_slots, DATA_STACK_SLOTS);
+        let assembly = backend::generate(backend, entry.unwrap(), &procs);
+
+        let dir = env::temp_dir().join(format!("zila-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let exe = dir.join(format!("{level:?}-{cached_slots}"));
+        let mut file = fs::File::create(&exe).unwrap();
+        x86_64enc::encode(&assembly)
+            .unwrap()
+            .write(&mut file)
+            .unwrap();
+        file.set_permissions(fs::Permissions::from_mode(0o755))
+            .unwrap();
+
+        exe
+    }
+
+    /// Builds `source` with libc and the C functions in `c_source` in a
+    /// fresh temporary directory.
+    fn build_linked(source: &str, c_source: &str, cached_slots: usize, name: &str) -> PathBuf {
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        let (entry, procs, strings) = Compiler::compile(defs).unwrap();
+        let backend = X86_64::new(&strings, cached_slots, DATA_STACK_SLOTS).with_libc();
+        let assembly = backend::generate(backend, entry.unwrap(), &procs);
+
+        let dir = env::temp_dir().join(format!("zila-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let object = dir.join(format!("{cached_slots}.o"));
+        let mut file = fs::File::create(&object).unwrap();
+        x86_64enc::encode_object(&assembly)
+            .unwrap()
+            .write(&mut file)
+            .unwrap();
+        let c_file = dir.join("lib.c");
+        fs::write(&c_file, c_source).unwrap();
 
-            Instruction::Over { size_a, size_b } => {
-                writeln!(out, "    ; {:?} -- OVER", span)?;
-                let offset = -((size_a + size_b) as isize * 8);
-                self.emit_copy_up(out, offset, size_a)?;
+        let exe = dir.join(cached_slots.to_string());
+        let status = Command::new("cc")
+            .arg("-o")
+            .arg(&exe)
+            .arg(&object)
+            .arg(&c_file)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        exe
+    }
+
+    /// Runs `source` with the interpreter reading `input`, returning its
+    /// output and exit code.
+    fn interpret(source: &str, input: &[u8]) -> (String, i32) {
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        let (entry, procs, strings) = Compiler::compile(defs).unwrap();
+        let mut out = Vec::new();
+        let code = Interpreter::run(
+            entry.unwrap(),
+            &procs,
+            &strings,
+            &[],
+            DATA_STACK_SLOTS,
+            input,
+            &mut out,
+        )
+        .unwrap();
+        (String::from_utf8(out).unwrap(), code as u8 as i32)
+    }
+
+    #[test]
+    fn arithmetic_stays_in_registers() {
+        let asm = generate(": f 1 2 + 3 * 4 swap - ; : main ;", CACHED_SLOTS);
+        assert_eq!(
+            proc_body(&asm, "f"),
+            [
+                "    lea rax, [rcx + 16]",
+                "    lea rdx, [rel data_stack_end]",
+                "    cmp rax, rdx",
+                "    ja data_stack_overflow",
+                "    mov r8, 1",
+                "    mov r9, 2",
+                "    add r8, r9",
+                "    mov r9, 3",
+                "    imul r8, r9",
+                "    mov r9, 4",
+                "    sub r9, r8",
+                "    mov [rcx], r9",
+                "    add rcx, 8",
+                "    ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn copies_read_the_right_slots() {
+        let asm = generate(": d \"s\" dup ; : o 1 \"s\" over ; : main ;", 0);
+
+        let dup = proc_body(&asm, "d");
+        assert_eq!(
+            dup[dup.len() - 6..],
+            [
+                "    mov rax, [rcx - 16]",
+                "    mov [rcx], rax",
+                "    mov rax, [rcx - 8]",
+                "    mov [rcx + 8], rax",
+                "    add rcx, 16",
+                "    ret",
+            ]
+        );
+
+        let over = proc_body(&asm, "o");
+        assert_eq!(
+            over[over.len() - 4..],
+            [
+                "    mov rax, [rcx - 24]",
+                "    mov [rcx], rax",
+                "    add rcx, 8",
+                "    ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn tail_calls_jump() {
+        for cached_slots in [0, CACHED_SLOTS] {
+            let asm = generate(COUNTDOWN, cached_slots);
+
+            let looped = proc_body(&asm, "loop");
+            assert_eq!(looped.last(), Some(&"    jmp r8"));
+
+            let main = proc_body(&asm, "main");
+            assert_eq!(main.last(), Some(&"    jmp zila_0_loop"));
+            assert!(!main.contains(&"    ret"));
+        }
+    }
+
+    #[test]
+    fn instructions_are_marked_with_where_they_are() {
+        let source = ": f 1\n\t2 + ;\n: main \"x\" puts ;";
+        let defs = Analyzer::analyze(Lexer::new(source)).unwrap();
+        let (entry, procs, strings) = Compiler::compile(defs).unwrap();
+        let backend =
+            X86_64::new(&strings, CACHED_SLOTS, DATA_STACK_SLOTS).with_source("f.zila", source);
+        let assembly = backend::generate(backend, entry.unwrap(), &procs);
+
+        let locs = assembly
+            .text
+            .iter()
+            .filter_map(|asm| match asm {
+                Asm::Loc(loc) => Some((loc.file, loc.line, loc.col)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // Each proc starts with the location of its first instruction.
+        assert_eq!(
+            locs,
+            [
+                ("f.zila", 1, 5),
+                ("f.zila", 1, 5),
+                ("f.zila", 2, 5),
+                ("f.zila", 2, 7),
+                ("f.zila", 3, 8),
+                ("f.zila", 3, 8),
+                ("f.zila", 3, 12),
+            ]
+        );
+        assert!(
+            assembly
+                .to_string()
+                .contains("    %line 2+0 f.zila\n    ; f.zila:2:7 -- add\n")
+        );
+    }
+
+    #[test]
+    fn c_calls_keep_rcx_and_align_rsp() {
+        let asm = generate(
+            "FFI: f ( int int int int -- int ) : g 1 2 3 4 f ; : main ;",
+            0,
+        );
+        let g = proc_body(&asm, "g");
+        let call = g
+            .iter()
+            .position(|line| line.starts_with("    sub rcx"))
+            .unwrap();
+        assert_eq!(
+            g[call..],
+            [
+                "    sub rcx, 32",
+                "    push rcx",
+                "    mov rax, rsp",
+                "    and rsp, -16",
+                "    sub rsp, 8",
+                "    push rax",
+                "    mov rdi, [rcx]",
+                "    mov rsi, [rcx + 8]",
+                "    mov rdx, [rcx + 16]",
+                "    mov rcx, [rcx + 24]",
+                "    mov rax, 0",
+                "    call f wrt ..plt",
+                "    pop rsp",
+                "    pop rcx",
+                "    mov r8, rax",
+                "    mov [rcx], r8",
+                "    add rcx, 8",
+                "    ret",
+            ]
+        );
+        assert!(asm.contains("extern f\n"));
+    }
+
+    #[test]
+    fn c_functions_are_called_when_linked_with_libc() {
+        let c_source = "#include <stdio.h>
+            long digits(long a, long b, long c, long d, long e, long f) {
+                return a * 100000 + b * 10000 + c * 1000 + d * 100 + e * 10 + f;
             }
+            void say(long n) { printf(\"%ld\\n\", n); }";
+        let source = "FFI: digits ( int int int int int int -- int )
+                      FFI: say ( int -- )
+                      FFI: labs ( int -- int )
+                      : deep dup 0 = [ drop ] [ 1 - deep ] ? apply ;
+                      : main 1 2 3 4 5 6 digits say \"zila\\n\" puts 0 9 - labs 3 deep exit ;";
+
+        for cached_slots in [0, CACHED_SLOTS] {
+            let exe = build_linked(source, c_source, cached_slots, "ffi");
+            let output = Command::new(&exe).output().unwrap();
+            // `exit` flushes what C buffered, after Zila's unbuffered write.
+            assert_eq!(output.stdout, b"zila\n123456\n");
+            assert_eq!(output.status.code(), Some(9));
+        }
+    }
+
+    #[test]
+    fn syscalls_load_their_arguments_and_keep_rcx() {
+        let asm = generate(": f 1 2 3 4 syscall3 ; : main ;", 0);
+        let f = proc_body(&asm, "f");
+        let call = f
+            .iter()
+            .position(|line| line.starts_with("    sub rcx"))
+            .unwrap();
+        assert_eq!(
+            f[call..],
+            [
+                "    sub rcx, 32",
+                "    mov rax, [rcx]",
+                "    mov rdi, [rcx + 8]",
+                "    mov rsi, [rcx + 16]",
+                "    mov rdx, [rcx + 24]",
+                "    push rcx",
+                "    syscall",
+                "    pop rcx",
+                "    mov r8, rax",
+                "    mov [rcx], r8",
+                "    add rcx, 8",
+                "    ret",
+            ]
+        );
+    }
 
-            Instruction::Drop { size } => {
-                writeln!(out, "    ; {:?} -- DROP", span)?;
-                self.emit_drop(out, size)?;
+    #[test]
+    fn syscalls_reach_the_kernel() {
+        // `getpid` leaves the cached 3 and 4 alone, and `exit` ends the
+        // program with their sum.
+        let source = ": main 3 4 39 syscall0 0 < 1 0 ? + + 60 swap syscall1 ;";
+        for level in [OptLevel::O0, OptLevel::O1] {
+            for cached_slots in [0, CACHED_SLOTS] {
+                let exe = build_native(source, level, cached_slots, "syscall");
+                let status = Command::new(&exe).status().unwrap();
+                assert_eq!(status.code(), Some(7));
             }
-            Instruction::Swap { size_a, size_b } => {
-                writeln!(out, "    ; {:?} -- SWAP", span)?;
+        }
+    }
 
-                let sa = size_a * 8;
-                let sb = size_b * 8;
+    #[test]
+    fn arguments_and_environment_are_read() {
+        let source = ": show argv-nth puts \"\\n\" puts ;
+                      : each dup argc < [ dup show 1 + each ] [ drop ] ? apply ;
+                      : env getenv [ puts ] [ drop \"unset\" puts ] ? apply \"\\n\" puts ;
+                      : main 1 each 9 show \"ZILA_VAR\" env \"ZILA_VAR=\" env \"ZILA\" env argc ;";
+        let run = |exe: PathBuf| {
+            let output = Command::new(exe)
+                .args(["a", "b c"])
+                .env("ZILA_VAR", "x=y")
+                .output()
+                .unwrap();
+            assert_eq!(output.stdout, b"a\nb c\n\nx=y\nunset\nunset\n");
+            assert_eq!(output.status.code(), Some(3));
+        };
 
-                for i in 0..size_a {
-                    writeln!(out, "    mov rax, [rcx - {}]", 8 * (i + 1))?;
-                    writeln!(out, "    mov [rsp - {}], rax", 8 * (i + 1))?;
-                }
-                for i in 0..size_b {
-                    writeln!(out, "    mov rax, [rcx - {}]", sa + 8 * (i + 1))?;
-                    writeln!(out, "    mov [rcx - {}], rax", 8 * (i + 1))?;
+        for cached_slots in [0, CACHED_SLOTS] {
+            run(build_native(source, OptLevel::O1, cached_slots, "args"));
+            run(build_linked(source, "", cached_slots, "args"));
+        }
+    }
+
+    #[test]
+    fn input_is_read_by_the_line_or_whole() {
+        // Many buffers' worth, ending in a line longer than one.
+        let mut long = (0..20000)
+            .map(|i| format!("line {i}\n"))
+            .collect::<String>()
+            .into_bytes();
+        long.extend([b'x'; 3 * INPUT_CHUNK as usize]);
+        let inputs: [&[u8]; 2] = [b"head\none\n\ntwo\nthree", &long];
+        let programs = [
+            ": loop read-line [ \"> \" puts puts \"\\n\" puts 1 + loop ] [ drop ] ? apply ;
+             : main read-line drop 0 loop swap puts \"|\" puts read-all puts
+             read-line swap drop [ 3 ] [ 4 ] ? apply + ;",
+            ": main read-line drop read-all puts \"|\" puts puts read-line swap drop [ 1 ] [ 2 ] ? apply ;",
+        ];
+        let run = |exe: PathBuf, input: &[u8]| {
+            let mut child = Command::new(exe)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            // The output is only read once all the input is written, so
+            // the pipes would fill up and block if this were not a thread.
+            let mut stdin = child.stdin.take().unwrap();
+            let input = input.to_vec();
+            let writer = std::thread::spawn(move || stdin.write_all(&input).unwrap());
+            let output = child.wait_with_output().unwrap();
+            writer.join().unwrap();
+            (
+                String::from_utf8(output.stdout).unwrap(),
+                output.status.code().unwrap(),
+            )
+        };
+
+        for (i, source) in programs.into_iter().enumerate() {
+            for (j, input) in inputs.into_iter().enumerate() {
+                let expected = interpret(source, input);
+                for cached_slots in [0, CACHED_SLOTS] {
+                    let name = format!("input-{i}-{j}");
+                    let exe = build_native(source, OptLevel::O1, cached_slots, &name);
+                    assert_eq!(run(exe, input), expected, "{source}");
+                    let exe = build_linked(source, "", cached_slots, &name);
+                    assert_eq!(run(exe, input), expected, "{source} linked with libc");
                 }
-                for i in 0..size_a {
-                    writeln!(out, "    mov rax, [rsp - {}]", 8 * (i + 1))?;
-                    writeln!(out, "    mov [rcx - {}], rax", sb + 8 * (i + 1))?;
+            }
+        }
+
+        // Programs that don't read input get no buffer for it.
+        assert!(!generate(": main \"hi\" puts ;", CACHED_SLOTS).contains("input"));
+    }
+
+    #[test]
+    fn stack_is_checked_on_entry_and_after_calls() {
+        let asm = generate(": f 1 + 2 3 ; : main 5 f f + + + + ;", CACHED_SLOTS);
+
+        // `+` reads one slot below `rcx` and `3` leaves two above it.
+        assert_eq!(
+            proc_body(&asm, "f")[..8],
+            [
+                "    lea rax, [rcx + 16]",
+                "    lea rdx, [rel data_stack_end]",
+                "    cmp rax, rdx",
+                "    ja data_stack_overflow",
+                "    lea rax, [rcx - 8]",
+                "    lea rdx, [rel data_stack]",
+                "    cmp rax, rdx",
+                "    jb data_stack_underflow",
+            ]
+        );
+
+        // After the second call, the four `+`s only read.
+        let main = proc_body(&asm, "main");
+        let after_call = main
+            .iter()
+            .rposition(|line| line.starts_with("    call"))
+            .unwrap();
+        assert_eq!(
+            main[after_call + 1..after_call + 5],
+            [
+                "    lea rax, [rcx - 40]",
+                "    lea rdx, [rel data_stack]",
+                "    cmp rax, rdx",
+                "    jb data_stack_underflow",
+            ]
+        );
+    }
+
+    /// Without tail calls the countdown needs two return addresses per
+    /// iteration, 16MB in total. It runs under a 1MB stack limit of its own,
+    /// so that it overflows whatever limit the tests were started with.
+    #[test]
+    fn tail_recursion_runs_in_constant_stack_space() {
+        let exe = build_native(COUNTDOWN, OptLevel::O0, CACHED_SLOTS, "tco");
+        let status = Command::new("sh")
+            .args(["-c", "ulimit -s 1024 && exec \"$0\""])
+            .arg(&exe)
+            .status()
+            .unwrap();
+        assert_eq!(status.code(), Some(0));
+    }
+
+    #[test]
+    fn native_code_matches_the_interpreter() {
+        let programs = [
+            ": main \"hello\\n\" puts 3 4 * 5 - 2 / ;",
+            ": main \"a\" 1 swap puts \"b\" over drop puts 2 swap - ;",
+            ": pick [ \"yes\" ] [ \"no\" ] ? apply ;
+             : main true pick puts false pick puts
+             1 2 < [ 10 ] [ 20 ] ? apply 3 3 = [ 4 ] [ 5 ] ? apply + ;",
+            ": sum dup 0 = [ ] [ dup 1 - sum + ] ? apply ;
+             : main 100 sum 256 / ;",
+            ": s \"xy\" ;
+             : main 1 s 2 swap over drop dup puts puts + s 5 over puts drop puts \"end\" puts 7 exit ;",
+            ": main 0 7 - 2 / 7 0 2 - / * ;",
+        ];
+
+        for (i, source) in programs.into_iter().enumerate() {
+            let expected = interpret(source, b"");
+            for level in [OptLevel::O0, OptLevel::O2] {
+                for cached_slots in [0, CACHED_SLOTS] {
+                    let exe = build_native(source, level, cached_slots, &format!("diff{i}"));
+                    let output = Command::new(&exe).output().unwrap();
+                    let actual = (
+                        String::from_utf8(output.stdout).unwrap(),
+                        output.status.code().unwrap(),
+                    );
+                    assert_eq!(
+                        actual, expected,
+                        "{source} at {level:?}, {cached_slots} cached"
+                    );
                 }
             }
         }
+    }
+
+    #[test]
+    fn native_stack_overflow_is_reported() {
+        let source = ": grow dup 0 = [ ] [ dup 1 - grow + ] ? apply ; : main 5000 grow ;";
+        let exe = build_native(source, OptLevel::O1, CACHED_SLOTS, "overflow");
+        let output = Command::new(&exe).output().unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(output.stderr, b"data stack overflow\n");
+    }
+
+    /// Times an arithmetic loop with every slot in memory against the
+    /// register cache. Run with `cargo test -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_arithmetic_loop() {
+        let source = ": step dup dup * over + 7 - swap 3 * + ;
+                      : loop dup 0 = [ drop ] [ dup step drop 1 - loop ] ? apply ;
+                      : main 100000000 loop ;";
+
+        let time = |cached_slots| {
+            let exe = build_native(source, OptLevel::O0, cached_slots, "bench");
+            let start = Instant::now();
+            assert!(Command::new(&exe).status().unwrap().success());
+            start.elapsed()
+        };
 
-        Ok(())
+        let memory: Duration = time(0);
+        let cached: Duration = time(CACHED_SLOTS);
+        println!("memory only: {memory:?}, cached: {cached:?}");
+        assert!(cached < memory);
     }
 }